@@ -0,0 +1,156 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::{mpsc, oneshot};
+use tracing::{info, warn};
+
+use crate::ratelimit::RateLimits;
+use crate::state::TorrentState;
+use crate::tracker::{Announce, AnnounceEvent, Peer, TrackerTiers};
+
+// How often to announce if the tracker doesn't tell us
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(30 * 60);
+
+// How long to wait before trying again when no tracker answers
+const RETRY_INTERVAL: Duration = Duration::from_secs(60);
+
+// How long to wait for the stopped announce on shutdown, since nothing depends on its answer
+const STOPPED_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Keeps a torrent's trackers up to date with our progress for as long as the torrent is running,
+/// passing along any new peers they tell us about
+pub struct Announcer {
+    state: Arc<TorrentState>,
+    trackers: TrackerTiers,
+    /// Limits on tracker and DHT traffic
+    limits: Arc<RateLimits>,
+    started: bool,
+    interval: Duration,
+    /// The byte counters when we started, since trackers want the amounts for this session only
+    uploaded_base: u64,
+    downloaded_base: u64,
+}
+
+impl Announcer {
+    pub fn new(state: Arc<TorrentState>, trackers: TrackerTiers, limits: Arc<RateLimits>) -> Self {
+        Self {
+            uploaded_base: state.uploaded(),
+            downloaded_base: state.downloaded(),
+            state,
+            trackers,
+            limits,
+            started: false,
+            interval: DEFAULT_INTERVAL,
+        }
+    }
+
+    /// Announces an event to the trackers, returning the peers they sent back and scheduling the
+    /// next regular announce
+    async fn announce(&mut self, event: AnnounceEvent) -> anyhow::Result<Vec<Peer>> {
+        let announce = Announce {
+            info_hash: self.state.torrent.info_hash,
+            peer_id: self.state.peer_id,
+            port: self.state.port,
+            uploaded: self.state.uploaded().saturating_sub(self.uploaded_base),
+            downloaded: self.state.downloaded().saturating_sub(self.downloaded_base),
+            left: self.state.left(),
+            event,
+        };
+
+        let response = match self.trackers.announce(&announce, &self.limits).await {
+            Ok(response) => response,
+            Err(error) => {
+                self.interval = RETRY_INTERVAL;
+                return Result::Err(error);
+            }
+        };
+
+        if event == AnnounceEvent::Started {
+            self.started = true;
+        }
+
+        // Never announce more often than the tracker allows
+        let interval = response.interval.unwrap_or(DEFAULT_INTERVAL);
+        self.interval = match response.min_interval {
+            Some(min_interval) => interval.max(min_interval),
+            None => interval,
+        };
+
+        if let (Some(seeders), Some(leechers)) = (response.seeders, response.leechers) {
+            info!(
+                "Tracker reports {} seeders and {} leechers for {}",
+                seeders, leechers, self.state.torrent.name
+            );
+        }
+
+        Result::Ok(response.peers)
+    }
+
+    /// Announces that we started straight away, then re-announces at the interval the tracker asks
+    /// for until told to stop, sending `completed` when the download finishes and `stopped` on the
+    /// way out
+    pub async fn run(
+        mut self,
+        peer_sender: mpsc::UnboundedSender<Vec<Peer>>,
+        mut stop: oneshot::Receiver<()>,
+    ) {
+        let mut haves = self.state.subscribe_haves();
+        // Only tell the tracker we completed the download if it happened while we were running
+        let mut completed = self.state.is_complete();
+
+        // Only announcing resets the deadline, so completing pieces doesn't push it back
+        let mut next_announce = tokio::time::Instant::now();
+
+        loop {
+            let event = tokio::select! {
+                _ = tokio::time::sleep_until(next_announce) => {
+                    if !self.started {
+                        AnnounceEvent::Started
+                    } else if !completed && self.state.is_complete() {
+                        // The completed announce failed last time, so try it again
+                        AnnounceEvent::Completed
+                    } else {
+                        AnnounceEvent::None
+                    }
+                }
+                // Missing a have still means a piece completed, so check either way
+                _ = haves.recv() => {
+                    if completed || !self.state.is_complete() {
+                        continue;
+                    }
+                    AnnounceEvent::Completed
+                }
+                _ = &mut stop => break,
+            };
+
+            // Trackers can take a long time to answer, don't hold up shutdown waiting for them
+            let result = tokio::select! {
+                result = self.announce(event) => result,
+                _ = &mut stop => break,
+            };
+            next_announce = tokio::time::Instant::now() + self.interval;
+
+            match result {
+                Ok(peers) => {
+                    // Only stop sending completed once a tracker has heard it
+                    if event == AnnounceEvent::Completed {
+                        completed = true;
+                    }
+                    if peer_sender.send(peers).is_err() {
+                        break;
+                    }
+                }
+                Err(error) => warn!("{}", error),
+            }
+        }
+
+        if self.started {
+            match tokio::time::timeout(STOPPED_TIMEOUT, self.announce(AnnounceEvent::Stopped)).await
+            {
+                Ok(Err(error)) => warn!("{}", error),
+                Err(_) => warn!("Timed out sending stopped announce"),
+                Ok(Ok(_)) => {}
+            }
+        }
+    }
+}
@@ -0,0 +1,147 @@
+use std::ops::Range;
+
+// The deepest nesting of lists and dictionaries we accept. Bencoded data can come from peers, and
+// nothing we parse comes close to this.
+const MAX_DEPTH: usize = 32;
+
+/// Returns the offset just past the end of the bencoded value starting at `start`.
+pub fn value_end(buf: &[u8], start: usize) -> anyhow::Result<usize> {
+    // Lists and dictionaries are walked without recursing, counting how deep we are instead
+    let mut offset = start;
+    let mut depth = 0;
+    loop {
+        match buf.get(offset) {
+            Some(b'i') => offset = find(buf, offset + 1, b'e')? + 1,
+            Some(b'l') | Some(b'd') => {
+                depth += 1;
+                if depth > MAX_DEPTH {
+                    return Result::Err(anyhow::anyhow!(
+                        "Bencoded value at {} is nested deeper than {}",
+                        start,
+                        MAX_DEPTH
+                    ));
+                }
+                offset += 1;
+            }
+            Some(b'e') if depth > 0 => {
+                depth -= 1;
+                offset += 1;
+            }
+            Some(b'0'..=b'9') => offset = string_end(buf, offset)?,
+            Some(b) => {
+                return Result::Err(anyhow::anyhow!(
+                    "Invalid bencode value prefix {:?} at {}",
+                    *b as char,
+                    offset
+                ))
+            }
+            None => return Result::Err(anyhow::anyhow!("Unexpected end of bencoded data")),
+        }
+
+        if depth == 0 {
+            return Result::Ok(offset);
+        }
+    }
+}
+
+fn string_end(buf: &[u8], start: usize) -> anyhow::Result<usize> {
+    let colon = find(buf, start, b':')?;
+    let len: usize = std::str::from_utf8(&buf[start..colon])?.parse()?;
+    match (colon + 1).checked_add(len) {
+        Some(end) if end <= buf.len() => Result::Ok(end),
+        _ => Result::Err(anyhow::anyhow!(
+            "Bencoded string of length {} at {} overruns buffer of size {}",
+            len,
+            start,
+            buf.len()
+        )),
+    }
+}
+
+/// Finds the byte span of the value stored under `key` in the top level bencoded dictionary in
+/// `buf`, if it exists.
+pub fn dict_value_span(buf: &[u8], key: &[u8]) -> anyhow::Result<Option<Range<usize>>> {
+    if buf.first() != Some(&b'd') {
+        return Result::Err(anyhow::anyhow!("Expected a bencoded dictionary"));
+    }
+
+    let mut offset = 1;
+    while buf.get(offset) != Some(&b'e') {
+        let key_end = value_end(buf, offset)?;
+        if !buf[offset].is_ascii_digit() {
+            return Result::Err(anyhow::anyhow!(
+                "Expected a string dictionary key at {}",
+                offset
+            ));
+        }
+        let colon = find(buf, offset, b':')?;
+        let value_start = key_end;
+        let value_end = value_end(buf, value_start)?;
+
+        if &buf[colon + 1..key_end] == key {
+            return Result::Ok(Some(value_start..value_end));
+        }
+
+        offset = value_end;
+    }
+
+    Result::Ok(None)
+}
+
+fn find(buf: &[u8], start: usize, byte: u8) -> anyhow::Result<usize> {
+    buf[start..]
+        .iter()
+        .position(|b| *b == byte)
+        .map(|i| start + i)
+        .ok_or_else(|| anyhow::anyhow!("Unexpected end of bencoded data"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_value_ends() {
+        assert_eq!(value_end(b"i42e", 0).unwrap(), 4);
+        assert_eq!(value_end(b"4:spamxx", 0).unwrap(), 6);
+        assert_eq!(value_end(b"l4:spami-3ee", 0).unwrap(), 12);
+        assert_eq!(value_end(b"d3:cowl3:mooee4:spam", 0).unwrap(), 14);
+        assert_eq!(value_end(b"xxi0e", 2).unwrap(), 5);
+    }
+
+    #[test]
+    fn rejects_truncated_values() {
+        assert!(value_end(b"i42", 0).is_err());
+        assert!(value_end(b"5:spam", 0).is_err());
+        assert!(value_end(b"l4:spam", 0).is_err());
+        assert!(value_end(b"x", 0).is_err());
+        assert!(value_end(b"", 0).is_err());
+        assert!(value_end(b"e", 0).is_err());
+        assert!(value_end(b"18446744073709551615:x", 0).is_err());
+    }
+
+    #[test]
+    fn rejects_deep_nesting() {
+        let mut nested = vec![b'l'; MAX_DEPTH];
+        nested.extend(vec![b'e'; MAX_DEPTH]);
+        assert_eq!(value_end(&nested, 0).unwrap(), nested.len());
+
+        let mut too_deep = vec![b'l'; MAX_DEPTH + 1];
+        too_deep.extend(vec![b'e'; MAX_DEPTH + 1]);
+        assert!(value_end(&too_deep, 0).is_err());
+
+        // What a peer could send as a ut_metadata payload
+        assert!(value_end(&vec![b'l'; 1024 * 1024], 0).is_err());
+    }
+
+    #[test]
+    fn finds_dict_value_spans() {
+        let buf = b"d8:announce3:url4:infod4:name1:xee";
+        let span = dict_value_span(buf, b"info").unwrap().unwrap();
+        assert_eq!(&buf[span], b"d4:name1:xe");
+        assert_eq!(dict_value_span(buf, b"missing").unwrap(), None);
+        // Values that happen to look like the key don't match
+        assert_eq!(dict_value_span(b"d1:a4:infoe", b"info").unwrap(), None);
+        assert!(dict_value_span(b"l4:infoe", b"info").is_err());
+    }
+}
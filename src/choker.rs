@@ -0,0 +1,330 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use rand::seq::SliceRandom;
+use tokio::sync::{watch, Notify};
+
+use crate::state::TorrentState;
+
+// The number of peers unchoked for their rates if not configured, besides the optimistic unchoke
+pub const DEFAULT_UNCHOKE_SLOTS: usize = 4;
+
+// How often the peers to unchoke are chosen again
+const CHOKE_INTERVAL: Duration = Duration::from_secs(10);
+
+// How often the optimistic unchoke moves on to another peer
+const OPTIMISTIC_INTERVAL: Duration = Duration::from_secs(30);
+
+// How long a peer can leave our requests unanswered before we consider it to be snubbing us
+const SNUB_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// What a worker tells the choker about its peer
+#[derive(Default)]
+pub struct PeerStats {
+    downloaded: AtomicU64,
+    uploaded: AtomicU64,
+    /// Whether the peer wants to download from us
+    interested: AtomicBool,
+    /// Since when we have been waiting for a block from the peer, if we have requests outstanding
+    waiting_since: Mutex<Option<Instant>>,
+}
+
+impl PeerStats {
+    pub fn add_downloaded(&self, bytes: u64) {
+        self.downloaded.fetch_add(bytes, Ordering::Relaxed);
+        // The peer is still answering our requests
+        if let Some(waiting_since) = self.waiting_since.lock().unwrap().as_mut() {
+            *waiting_since = Instant::now();
+        }
+    }
+
+    pub fn add_uploaded(&self, bytes: u64) {
+        self.uploaded.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn set_interested(&self, interested: bool) {
+        self.interested.store(interested, Ordering::Relaxed);
+    }
+
+    fn interested(&self) -> bool {
+        self.interested.load(Ordering::Relaxed)
+    }
+
+    /// Records whether we have requests outstanding with the peer
+    pub fn set_waiting(&self, waiting: bool) {
+        let mut waiting_since = self.waiting_since.lock().unwrap();
+        if waiting {
+            waiting_since.get_or_insert_with(Instant::now);
+        } else {
+            *waiting_since = None;
+        }
+    }
+
+    /// Whether the peer has stopped sending us the blocks we asked for
+    fn snubbed(&self) -> bool {
+        self.waiting_since
+            .lock()
+            .unwrap()
+            .is_some_and(|since| since.elapsed() >= SNUB_TIMEOUT)
+    }
+}
+
+/// A peer the choker decides for
+struct ChokerPeer {
+    stats: Arc<PeerStats>,
+    unchoke: watch::Sender<bool>,
+    /// The byte counters at the last round, to measure the rates over the round
+    downloaded: u64,
+    uploaded: u64,
+}
+
+impl ChokerPeer {
+    fn unchoked(&self) -> bool {
+        *self.unchoke.borrow()
+    }
+
+    /// Tells the worker whether to unchoke its peer, if that changed
+    fn set_unchoked(&self, unchoked: bool) {
+        self.unchoke.send_if_modified(|current| {
+            let changed = *current != unchoked;
+            *current = unchoked;
+            changed
+        });
+    }
+}
+
+/// A peer registered with the choker, through which its worker reports on it and hears back
+pub struct ChokerHandle {
+    id: u64,
+    pub stats: Arc<PeerStats>,
+    /// Whether the choker wants the peer unchoked
+    pub unchoked: watch::Receiver<bool>,
+}
+
+/// Decides which peers we upload to (BEP 3). Every round the peers that give us the most, or
+/// take the most once we are seeding, are unchoked, along with one optimistic unchoke that rotates
+/// so new peers get a chance to prove themselves.
+#[derive(Default)]
+pub struct Choker {
+    peers: Mutex<HashMap<u64, ChokerPeer>>,
+    next_id: AtomicU64,
+    optimistic: Mutex<Option<(u64, Instant)>>,
+    /// Wakes the choker to fill a free slot when a peer becomes interested
+    wake: Notify,
+}
+
+impl Choker {
+    /// Registers a peer, which starts out choked
+    pub fn register(&self) -> ChokerHandle {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let stats = Arc::new(PeerStats::default());
+        let (unchoke, unchoked) = watch::channel(false);
+        self.peers.lock().unwrap().insert(
+            id,
+            ChokerPeer {
+                stats: stats.clone(),
+                unchoke,
+                downloaded: 0,
+                uploaded: 0,
+            },
+        );
+        ChokerHandle {
+            id,
+            stats,
+            unchoked,
+        }
+    }
+
+    pub fn unregister(&self, handle: &ChokerHandle) {
+        self.peers.lock().unwrap().remove(&handle.id);
+        // The slot the peer had can go to someone else straight away
+        self.wake.notify_one();
+    }
+
+    /// Lets the choker know a peer became interested, so it doesn't wait for the next round if a
+    /// slot is free
+    pub fn peer_interested(&self) {
+        self.wake.notify_one();
+    }
+
+    /// Chooses the peers to unchoke for the next round, by the rate at which they sent to us while
+    /// downloading or at which we sent to them while seeding
+    fn rechoke(&self, slots: usize, seeding: bool, elapsed: Duration) {
+        let mut peers = self.peers.lock().unwrap();
+        let elapsed = elapsed.as_secs_f64().max(1.0);
+
+        let mut rates = Vec::new();
+        for (id, peer) in peers.iter_mut() {
+            let downloaded = peer.stats.downloaded.load(Ordering::Relaxed);
+            let uploaded = peer.stats.uploaded.load(Ordering::Relaxed);
+            let sent = if seeding {
+                uploaded - peer.uploaded
+            } else {
+                downloaded - peer.downloaded
+            };
+            let rate = sent as f64 / elapsed;
+            peer.downloaded = downloaded;
+            peer.uploaded = uploaded;
+
+            // Peers snubbing us only get a chance through the optimistic unchoke
+            if peer.stats.interested() && (seeding || !peer.stats.snubbed()) {
+                rates.push((*id, rate));
+            }
+        }
+        rates.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+        let mut unchoke: Vec<u64> = rates.iter().take(slots).map(|(id, _)| *id).collect();
+
+        // Keep the optimistic unchoke for its whole turn unless it stopped wanting anything or
+        // earned a regular slot, then give it to a random interested peer
+        let mut optimistic = self.optimistic.lock().unwrap();
+        let keep = optimistic.is_some_and(|(id, since)| {
+            since.elapsed() < OPTIMISTIC_INTERVAL
+                && peers.get(&id).is_some_and(|peer| peer.stats.interested())
+                && !unchoke.contains(&id)
+        });
+        if !keep {
+            let candidates: Vec<u64> = peers
+                .iter()
+                .filter(|(id, peer)| peer.stats.interested() && !unchoke.contains(id))
+                .map(|(id, _)| *id)
+                .collect();
+            *optimistic = candidates
+                .choose(&mut rand::thread_rng())
+                .map(|id| (*id, Instant::now()));
+        }
+        if let Some((id, _)) = *optimistic {
+            unchoke.push(id);
+        }
+
+        for (id, peer) in peers.iter() {
+            peer.set_unchoked(unchoke.contains(id));
+        }
+    }
+
+    /// Unchokes interested peers while there are slots nobody is using
+    fn fill_slots(&self, slots: usize, seeding: bool) {
+        let peers = self.peers.lock().unwrap();
+        // One slot more for the optimistic unchoke
+        let mut free = (slots + 1).saturating_sub(peers.values().filter(|p| p.unchoked()).count());
+        for peer in peers.values() {
+            if free == 0 {
+                break;
+            }
+            if !peer.unchoked() && peer.stats.interested() && (seeding || !peer.stats.snubbed()) {
+                peer.set_unchoked(true);
+                free -= 1;
+            }
+        }
+    }
+}
+
+/// Runs the choker for a torrent, choosing the peers to unchoke every CHOKE_INTERVAL and filling
+/// free slots in between
+pub async fn run(state: Arc<TorrentState>, slots: usize) {
+    let mut interval = tokio::time::interval(CHOKE_INTERVAL);
+    let mut last_round = Instant::now();
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {
+                state
+                    .choker
+                    .rechoke(slots, state.is_complete(), last_round.elapsed());
+                last_round = Instant::now();
+            }
+            _ = state.choker.wake.notified() => {
+                state.choker.fill_slots(slots, state.is_complete());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ROUND: Duration = Duration::from_secs(10);
+
+    /// Registers interested peers that sent us, and took from us, the given number of bytes
+    fn peers(choker: &Choker, bytes: &[u64]) -> Vec<ChokerHandle> {
+        bytes
+            .iter()
+            .map(|bytes| {
+                let handle = choker.register();
+                handle.stats.set_interested(true);
+                handle.stats.add_downloaded(*bytes);
+                handle.stats.add_uploaded(*bytes);
+                handle
+            })
+            .collect()
+    }
+
+    fn unchoked(handles: &[ChokerHandle]) -> Vec<bool> {
+        handles.iter().map(|h| *h.unchoked.borrow()).collect()
+    }
+
+    #[test]
+    fn unchokes_the_fastest_peers_and_one_optimistic() {
+        let choker = Choker::default();
+        let handles = peers(&choker, &[500, 400, 300, 200, 100]);
+        choker.rechoke(2, false, ROUND);
+
+        let unchoked = unchoked(&handles);
+        assert!(unchoked[0] && unchoked[1]);
+        assert_eq!(unchoked[2..].iter().filter(|u| **u).count(), 1);
+    }
+
+    #[test]
+    fn ranks_by_upload_rate_when_seeding() {
+        let choker = Choker::default();
+        let handles = peers(&choker, &[0, 0, 0]);
+        handles[2].stats.add_uploaded(1000);
+        choker.rechoke(1, true, ROUND);
+        assert!(unchoked(&handles)[2]);
+
+        // Rates only count what was sent since the last round
+        handles[1].stats.add_uploaded(10);
+        choker.rechoke(1, true, ROUND);
+        assert!(unchoked(&handles)[1]);
+    }
+
+    #[test]
+    fn leaves_uninterested_peers_choked() {
+        let choker = Choker::default();
+        let handles = peers(&choker, &[500, 400]);
+        handles[0].stats.set_interested(false);
+        choker.rechoke(4, false, ROUND);
+        assert_eq!(unchoked(&handles), [false, true]);
+    }
+
+    #[test]
+    fn excludes_snubbing_peers_from_regular_slots() {
+        let choker = Choker::default();
+        let handles = peers(&choker, &[1000, 300, 200]);
+        *handles[0].stats.waiting_since.lock().unwrap() = Some(Instant::now() - SNUB_TIMEOUT);
+        assert!(handles[0].stats.snubbed());
+
+        // With the optimistic unchoke taken, the snubbing peer loses out to slower peers
+        *choker.optimistic.lock().unwrap() = Some((handles[2].id, Instant::now()));
+        choker.rechoke(1, false, ROUND);
+        assert_eq!(unchoked(&handles), [false, true, true]);
+
+        // Once seeding it doesn't matter what the peer sends us
+        handles[0].stats.add_uploaded(1000);
+        choker.rechoke(1, true, ROUND);
+        assert!(unchoked(&handles)[0]);
+    }
+
+    #[test]
+    fn fills_free_slots() {
+        let choker = Choker::default();
+        let handles = peers(&choker, &[0, 0, 0, 0]);
+        choker.fill_slots(2, false);
+        assert_eq!(unchoked(&handles).iter().filter(|u| **u).count(), 3);
+
+        choker.unregister(&handles[0]);
+        choker.fill_slots(2, false);
+        assert_eq!(unchoked(&handles[1..]), [true, true, true]);
+    }
+}
@@ -1,92 +1,539 @@
+use std::collections::HashSet;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
 use rand::Rng;
-use tokio::sync::mpsc;
-use tokio::task::JoinHandle;
-use tracing::{error, info, warn};
+use tokio::net::TcpListener;
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinSet;
+use tracing::{info, warn};
 
+use crate::announcer::Announcer;
+use crate::choker;
+use crate::dht::{Dht, DhtConfig};
+use crate::lsd::LocalDiscovery;
+use crate::magnet::MagnetLink;
+use crate::ratelimit::{self, RateConfig, RateLimits};
+use crate::resume::ResumeData;
+use crate::state::TorrentState;
 use crate::torrent::Torrent;
 use crate::torrent_file::TorrentMetaInfo;
-use crate::tracker::get_peers;
+use crate::tracker::{Announce, AnnounceEvent, Peer, TrackerTiers};
 use crate::types::{PeerID, PEER_ID_LEN};
-use crate::worker::{PieceInfo, PieceResult, TorrentDownloadWorker};
+use crate::utp::{UtpSocket, UtpStream};
+use crate::worker::{
+    fetch_metadata, Bitfield, EncryptionPolicy, PieceResult, TorrentDownloadWorker, Transport,
+    TransportConfig,
+};
 use crate::writer::TorrentWriter;
 
+// How often to save the resume file for a torrent while downloading
+const RESUME_SAVE_INTERVAL: Duration = Duration::from_secs(30);
+
+// How often to look for peers on the DHT and announce ourselves to it
+const DHT_ANNOUNCE_INTERVAL: Duration = Duration::from_secs(15 * 60);
+
+// The file in the resume directory the DHT routing table is saved in
+const DHT_STATE_FILE: &str = "dht.dat";
+
+// How long to wait before accepting connections again when accepting one fails, e.g. because we
+// ran out of file descriptors
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+// The bytes left we announce before the metadata tells us the size. Trackers take peers with
+// nothing left for seeds, and may not hand seeds out to them.
+const UNKNOWN_LEFT: u64 = 16384;
+
+pub struct ClientConfig {
+    /// The port we listen on for incoming peer connections
+    pub port: u16,
+    /// Whether to keep seeding once a download completes
+    pub seed: bool,
+    /// The directory fast resume state for each torrent is saved in
+    pub resume_dir: PathBuf,
+    /// Settings for our DHT node, or None to find peers through trackers only
+    pub dht: Option<DhtConfig>,
+    /// Whether to look for peers on the local network with local service discovery
+    pub lsd: bool,
+    /// Whether connections to peers are encrypted
+    pub encryption: EncryptionPolicy,
+    /// Whether to connect to peers over uTP, which yields to other traffic, before trying TCP
+    pub utp: bool,
+    /// How many peers to upload to at once, besides the optimistic unchoke
+    pub unchoke_slots: usize,
+    /// Limits on the blocks exchanged with peers across every torrent
+    pub limits: RateConfig,
+    /// Limits on the blocks exchanged with each torrent's peers
+    pub torrent_limits: RateConfig,
+    /// Limits on tracker and DHT traffic, which don't count towards the limits on blocks
+    pub overhead_limits: RateConfig,
+}
+
 pub struct TorrentClient {
     peer_id: PeerID,
-    port: u16,
+    config: ClientConfig,
+    dht: Option<Arc<Dht>>,
+    lsd: Option<Arc<LocalDiscovery>>,
+    transport: TransportConfig,
+    overhead: Arc<RateLimits>,
 }
 
 impl TorrentClient {
-    pub fn new(port: u16) -> Self {
+    pub async fn new(mut config: ClientConfig) -> anyhow::Result<Self> {
         let mut peer_id = [0u8; PEER_ID_LEN];
         rand::thread_rng().fill(&mut peer_id);
-        Self { peer_id, port }
+
+        // uTP is an improvement over TCP, so carry on without it if its socket can't be bound
+        let utp = if config.utp {
+            match UtpSocket::bind(config.port).await {
+                Ok(utp) => Some(utp),
+                Err(error) => {
+                    warn!("Failed to start uTP: {}", error);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+
+        let overhead = Arc::new(RateLimits::new(config.overhead_limits));
+        let dht = match config.dht.take() {
+            Some(dht_config) => {
+                let state_path = config.resume_dir.join(DHT_STATE_FILE);
+                // The DHT can't bind the port uTP is already using, so it shares the socket
+                let shared = utp
+                    .as_ref()
+                    .filter(|_| dht_config.port == config.port)
+                    .map(|utp| utp.share());
+                Some(Dht::start(dht_config, &state_path, shared, overhead.clone()).await?)
+            }
+            None => None,
+        };
+
+        // Peers on the local network are a bonus, so carry on without them if multicast fails
+        let lsd = if config.lsd {
+            match LocalDiscovery::start().await {
+                Ok(lsd) => Some(lsd),
+                Err(error) => {
+                    warn!("Failed to start local service discovery: {}", error);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+
+        let transport = TransportConfig {
+            encryption: config.encryption,
+            utp,
+            limits: Arc::new(RateLimits::new(config.limits)),
+        };
+
+        Result::Ok(Self {
+            peer_id,
+            config,
+            dht,
+            lsd,
+            transport,
+            overhead,
+        })
+    }
+
+    pub async fn download_magnet(&self, magnet: MagnetLink) -> anyhow::Result<()> {
+        let mut trackers = TrackerTiers::new(std::slice::from_ref(&magnet.trackers));
+        let announce = Announce {
+            info_hash: magnet.info_hash,
+            peer_id: self.peer_id,
+            port: self.config.port,
+            uploaded: 0,
+            downloaded: 0,
+            left: UNKNOWN_LEFT,
+            event: AnnounceEvent::None,
+        };
+        let mut peers = match trackers.announce(&announce, &self.overhead).await {
+            Ok(response) => response.peers,
+            Err(error) if self.dht.is_some() => {
+                warn!("{}, looking for peers on the DHT", error);
+                Vec::new()
+            }
+            Err(error) => return Result::Err(error),
+        };
+        if let Some(dht) = &self.dht {
+            for peer in dht.get_peers(magnet.info_hash).await {
+                if !peers.contains(&peer) {
+                    peers.push(peer);
+                }
+            }
+        }
+
+        info!(
+            "Fetching metadata for {}",
+            magnet.name.as_deref().unwrap_or("magnet link")
+        );
+
+        // Try each peer in turn until one of them gives us the info dictionary
+        for peer in peers {
+            let metadata = fetch_metadata(
+                &magnet.info_hash,
+                &self.peer_id,
+                self.config.port,
+                &peer,
+                &self.transport,
+            );
+            match metadata.await {
+                Ok(info_bytes) => {
+                    let torrent_file =
+                        TorrentMetaInfo::from_info_bytes(info_bytes, magnet.trackers)?;
+                    return self.download_file(torrent_file).await;
+                }
+                Err(error) => {
+                    warn!("Failed to fetch metadata from {}: {}", peer, error);
+                }
+            }
+        }
+
+        Result::Err(anyhow::anyhow!("Failed to fetch metadata from any peer"))
     }
 
     pub async fn download_file(&self, torrent_file: TorrentMetaInfo) -> anyhow::Result<()> {
         let torrent = Torrent::try_from(torrent_file)?;
-        let peers = get_peers(&self.peer_id, self.port, &torrent).await?;
+        let resume_path = ResumeData::path(&self.config.resume_dir, &torrent.info_hash);
+
+        // Trust the resume file if the files on disk haven't changed since it was saved, otherwise
+        // check any data already on disk so an interrupted download picks up where it left off
+        let mut writer = TorrentWriter::from_torrent(&torrent).await?;
+        let file_stats = writer.file_stats().await?;
+        let resume = match ResumeData::load(&resume_path) {
+            Ok(resume) => resume
+                .filter(|r| r.matches(&torrent.info_hash, torrent.piece_hashes.len(), &file_stats)),
+            Err(error) => {
+                warn!("Failed to load resume file {:?}: {}", resume_path, error);
+                None
+            }
+        };
+
+        let have = match resume {
+            Some(_) => Bitfield::new(torrent.piece_hashes.len()),
+            None => {
+                info!("Verifying existing data for {}", &torrent.name);
+                writer.verify(&torrent).await?
+            }
+        };
+
+        let state = Arc::new(TorrentState::new(
+            torrent,
+            self.peer_id,
+            self.config.port,
+            self.dht.clone(),
+            self.transport.clone(),
+            writer,
+            have,
+        ));
+        let torrent = &state.torrent;
+        state
+            .limits
+            .upload
+            .set_rate(self.config.torrent_limits.upload);
+        state
+            .limits
+            .download
+            .set_rate(self.config.torrent_limits.download);
+
+        if let Some(resume) = &resume {
+            info!("Resuming {} from {:?}", &torrent.name, resume_path);
+            state.restore(resume).await?;
+        }
+
+        if state.is_complete() && !self.config.seed {
+            info!("Download already complete for {}", &torrent.name);
+            return Result::Ok(());
+        }
+
+        let announcer = Announcer::new(
+            state.clone(),
+            TrackerTiers::new(&torrent.announce_list),
+            self.overhead.clone(),
+        );
+        // Start with the peers saved for resume while the trackers are asked for more, since a
+        // tracker that doesn't answer shouldn't hold up the download
+        let peers = state.peers();
+
+        // Announce in the background, picking up new peers as the swarm changes
+        let (peer_sender, mut peer_receiver) = mpsc::unbounded_channel::<Vec<Peer>>();
+        let (stop_sender, stop_receiver) = oneshot::channel();
+        let dht_task = self
+            .dht
+            .clone()
+            .map(|dht| tokio::spawn(announce_dht(dht, state.clone(), peer_sender.clone())));
+        let announcer_task = tokio::spawn(announcer.run(peer_sender.clone(), stop_receiver));
+        if let Some(lsd) = &self.lsd {
+            lsd.add_torrent(torrent.info_hash, self.config.port, peer_sender.clone())
+                .await;
+        }
+
+        let choker_task = tokio::spawn(choker::run(state.clone(), self.config.unchoke_slots));
 
-        let (download_sender, download_receiver) = async_channel::unbounded::<PieceInfo>();
         let (result_sender, mut result_receiver) = mpsc::unbounded_channel::<PieceResult>();
 
-        // Spawn a worker to connect to each peer
-        let tasks: Vec<JoinHandle<anyhow::Result<()>>> = peers
-            .into_iter()
-            .map(|peer| {
-                let peer_id = self.peer_id.clone();
-                let info_hash = torrent.info_hash.clone();
-                let channel = (download_sender.clone(), download_receiver.clone());
-                let results = result_sender.clone();
-                tokio::spawn(async move {
-                    let mut worker =
-                        TorrentDownloadWorker::connect(&info_hash, &peer_id, &peer).await?;
-                    worker.start(channel, results).await?;
-                    Result::Ok(())
-                })
-            })
-            .collect();
-
-        // Send out each piece of the file to workers
-        // TODO: Better piece picking algorithm: https://luminarys.com/posts/writing-a-bittorrent-client.html
-        for i in 0..torrent.piece_hashes.len() {
-            let begin = i as u64 * torrent.piece_length;
-            let end = u64::min((i + 1) as u64 * torrent.piece_length, torrent.length);
-            let length = end - begin;
-            let piece_info = PieceInfo::new(i as u32, torrent.piece_hashes[i], length as u32);
-            download_sender.send(piece_info).await?;
+        // Accept connections from peers that find us through the tracker
+        // Listen on both IPv4 and IPv6 where the OS supports dual stack sockets
+        let listener = match TcpListener::bind((Ipv6Addr::UNSPECIFIED, self.config.port)).await {
+            Ok(listener) => listener,
+            Err(_) => TcpListener::bind((Ipv4Addr::UNSPECIFIED, self.config.port)).await?,
+        };
+        let listener_task = tokio::spawn(listen(
+            listener,
+            state.clone(),
+            result_sender.clone(),
+            peer_sender.clone(),
+        ));
+
+        // Spawn a worker to connect to each peer, each of which asks the state for the next piece
+        // to download from its peer. Dropping the set on the way out aborts every worker.
+        let mut workers = JoinSet::new();
+        let mut connected = HashSet::new();
+        connect_peers(
+            &mut workers,
+            &mut connected,
+            &state,
+            &result_sender,
+            &peer_sender,
+            peers,
+        );
+
+        if state.is_complete() {
+            info!("Seeding {}, press Ctrl-C to stop", &torrent.name);
         }
 
-        let mut writer = TorrentWriter::from_torrent(&torrent).await?;
+        let mut commands = read_commands();
+        let mut save_interval = tokio::time::interval(RESUME_SAVE_INTERVAL);
+        loop {
+            tokio::select! {
+                Some(piece_result) = result_receiver.recv() => {
+                    // Pieces downloaded from several peers at once in endgame mode can be reported
+                    // again after the download finished
+                    let was_complete = state.is_complete();
+                    state.complete_piece(piece_result.index).await?;
+                    if was_complete || !state.is_complete() {
+                        continue;
+                    }
 
-        let mut bytes_written = 0u64;
-        while let Some(piece_result) = result_receiver.recv().await {
-            let offset = piece_result.index as u64 * torrent.piece_length;
-            writer.write(offset, &piece_result.buf).await?;
+                    info!("Download finished for {}", &torrent.name);
+                    save_resume(&state, &resume_path).await;
+                    if !self.config.seed {
+                        break;
+                    }
+                    info!("Seeding {}, press Ctrl-C to stop", &torrent.name);
+                }
+                Some(peers) = peer_receiver.recv() => {
+                    connect_peers(
+                        &mut workers,
+                        &mut connected,
+                        &state,
+                        &result_sender,
+                        &peer_sender,
+                        peers,
+                    );
+                }
+                Some(joined) = workers.join_next() => {
+                    if let Ok(peer) = joined {
+                        connected.remove(&peer);
+                    }
+                }
+                _ = save_interval.tick() => {
+                    save_resume(&state, &resume_path).await;
+                }
+                Some(command) = commands.recv() => {
+                    if let Err(error) = self.run_command(&state, &command) {
+                        warn!("{}", error);
+                    }
+                }
+                _ = tokio::signal::ctrl_c() => break,
+            }
+        }
 
-            bytes_written += piece_result.buf.len() as u64;
+        // Let the trackers know we are going away before disconnecting from everyone
+        let _ = stop_sender.send(());
+        let _ = announcer_task.await;
+        if let Some(dht_task) = dht_task {
+            dht_task.abort();
+        }
+        if let Some(lsd) = &self.lsd {
+            lsd.remove_torrent(&torrent.info_hash);
+        }
+        listener_task.abort();
+        choker_task.abort();
+        drop(workers);
+
+        save_resume(&state, &resume_path).await;
+        if let Some(dht) = &self.dht {
+            let dht_path = self.config.resume_dir.join(DHT_STATE_FILE);
+            if let Err(error) = dht.save(&dht_path).await {
+                warn!("Failed to save DHT state {:?}: {}", dht_path, error);
+            }
+        }
+
+        Result::Ok(())
+    }
+
+    /// Runs a command typed while a torrent is running. The only command changes a rate limit:
+    /// `limit <global|torrent|overhead> <upload|download> <KiB/s>`, with 0 removing the limit.
+    fn run_command(&self, state: &TorrentState, command: &str) -> anyhow::Result<()> {
+        let words: Vec<&str> = command.split_whitespace().collect();
+        let ["limit", scope, direction, rate] = words.as_slice() else {
+            return Result::Err(anyhow::anyhow!(
+                "Unknown command {:?}, expected limit <global|torrent|overhead> <upload|download> <KiB/s>",
+                command
+            ));
+        };
+
+        let limits = match *scope {
+            "global" => self.transport.limits.as_ref(),
+            "torrent" => &state.limits,
+            "overhead" => self.overhead.as_ref(),
+            _ => return Result::Err(anyhow::anyhow!("Unknown rate limit {:?}", scope)),
+        };
+        let limiter = match *direction {
+            "upload" => &limits.upload,
+            "download" => &limits.download,
+            _ => return Result::Err(anyhow::anyhow!("Unknown direction {:?}", direction)),
+        };
+        let rate: u64 = rate
+            .parse()
+            .map_err(|_| anyhow::anyhow!("Invalid rate {:?}, expected KiB/s", rate))?;
+
+        limiter.set_rate(ratelimit::from_kib(rate));
+        if rate > 0 {
+            info!("Limited {} {} to {} KiB/s", scope, direction, rate);
+        } else {
+            info!("Removed the {} {} limit", scope, direction);
+        }
+
+        Result::Ok(())
+    }
+}
 
-            if bytes_written == torrent.length {
+/// Reads commands from stdin on a thread of its own, since a blocking read can't be cancelled and
+/// would hold up shutting down the runtime
+fn read_commands() -> mpsc::UnboundedReceiver<String> {
+    let (sender, commands) = mpsc::unbounded_channel();
+    std::thread::spawn(move || {
+        for line in std::io::stdin().lines() {
+            let Ok(line) = line else {
+                break;
+            };
+            if !line.trim().is_empty() && sender.send(line).is_err() {
                 break;
             }
         }
+    });
+    commands
+}
 
-        info!("Download finished for {}", &torrent.name);
-        download_receiver.close();
+/// Spawns a worker for each peer we aren't already connected to
+fn connect_peers(
+    workers: &mut JoinSet<Peer>,
+    connected: &mut HashSet<Peer>,
+    state: &Arc<TorrentState>,
+    result_sender: &mpsc::UnboundedSender<PieceResult>,
+    peer_sender: &mpsc::UnboundedSender<Vec<Peer>>,
+    peers: Vec<Peer>,
+) {
+    state.add_peers(peers.iter().copied());
 
-        for task in tasks {
-            match task.await {
-                Ok(task_result) => match task_result {
-                    Ok(_) => {}
-                    Err(error) => {
-                        warn!("Error in worker: {}", error);
-                    }
-                },
+    for peer in peers {
+        if !connected.insert(peer) {
+            continue;
+        }
+
+        let state = state.clone();
+        let results = result_sender.clone();
+        let peer_sender = peer_sender.clone();
+        workers.spawn(async move {
+            let result = async {
+                let mut worker = TorrentDownloadWorker::connect(state, &peer, peer_sender).await?;
+                worker.start(results).await
+            };
+            if let Err(error) = result.await {
+                warn!("Error in worker for {}: {}", peer, error);
+            }
+            peer
+        });
+    }
+}
+
+/// Looks for peers on the DHT and announces ourselves to it every DHT_ANNOUNCE_INTERVAL, passing
+/// the peers found to the client the same way as peers from trackers
+async fn announce_dht(
+    dht: Arc<Dht>,
+    state: Arc<TorrentState>,
+    peer_sender: mpsc::UnboundedSender<Vec<Peer>>,
+) {
+    loop {
+        let peers = dht.announce(state.torrent.info_hash, state.port).await;
+        if peer_sender.send(peers).is_err() {
+            return;
+        }
+        tokio::time::sleep(DHT_ANNOUNCE_INTERVAL).await;
+    }
+}
+
+async fn save_resume(state: &TorrentState, path: &Path) {
+    if let Err(error) = state.save(path).await {
+        warn!("Failed to save resume file {:?}: {}", path, error);
+    }
+}
+
+/// Accepts incoming peer connections, spawning a worker for each of them
+async fn listen(
+    listener: TcpListener,
+    state: Arc<TorrentState>,
+    result_sender: mpsc::UnboundedSender<PieceResult>,
+    peer_sender: mpsc::UnboundedSender<Vec<Peer>>,
+) -> anyhow::Result<()> {
+    // Dropping the set when the listener is aborted aborts every inbound connection
+    let mut connections = JoinSet::new();
+
+    loop {
+        let (stream, addr) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, addr)) => (Transport::Tcp(stream), addr),
+                // Failing to accept one connection doesn't stop us accepting the next
                 Err(error) => {
-                    error!("Failed to join task: {}", error);
+                    warn!("Failed to accept connection: {}", error);
+                    tokio::time::sleep(ACCEPT_BACKOFF).await;
+                    continue;
                 }
+            },
+            Some(stream) = accept_utp(state.transport.utp.as_deref()) => {
+                let addr = stream.peer_addr();
+                (Transport::Utp(stream), addr)
             }
-        }
+            Some(_) = connections.join_next() => continue,
+        };
 
-        Result::Ok(())
+        let peer = Peer::new(addr);
+        let state = state.clone();
+        let results = result_sender.clone();
+        let peer_sender = peer_sender.clone();
+        connections.spawn(async move {
+            let result = async {
+                let mut worker = TorrentDownloadWorker::accept(state, stream, peer_sender).await?;
+                worker.start(results).await
+            };
+            if let Err(error) = result.await {
+                warn!("Error in worker for {}: {}", peer, error);
+            }
+        });
+    }
+}
+
+/// Waits for a peer to connect to us over uTP, or forever if uTP is disabled
+async fn accept_utp(utp: Option<&UtpSocket>) -> Option<UtpStream> {
+    match utp {
+        Some(utp) => utp.accept().await,
+        None => std::future::pending().await,
     }
 }
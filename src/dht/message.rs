@@ -0,0 +1,111 @@
+use serde_bytes::ByteBuf;
+use serde_derive::{Deserialize, Serialize};
+
+use super::routing::Node;
+use super::NODE_ID_LEN;
+use crate::tracker::{Peer, COMPACT_LEN_V4};
+
+// The length of a node in compact form, its id followed by its compact IPv4 address
+pub const COMPACT_NODE_LEN: usize = NODE_ID_LEN + COMPACT_LEN_V4;
+
+// Error codes sent in KRPC error messages
+pub const ERROR_PROTOCOL: i64 = 203;
+pub const ERROR_METHOD_UNKNOWN: i64 = 204;
+
+/// A KRPC message, the bencoded dictionaries DHT nodes exchange over UDP (BEP 5)
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct KrpcMessage {
+    /// The transaction id, echoed back in the response to a query
+    pub t: ByteBuf,
+    /// The message type, "q" for a query, "r" for a response or "e" for an error
+    pub y: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub q: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub a: Option<QueryArgs>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub r: Option<ResponseValues>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub e: Option<(i64, String)>,
+}
+
+/// The arguments of a query, covering every query type
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct QueryArgs {
+    pub id: ByteBuf,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target: Option<ByteBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub info_hash: Option<ByteBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token: Option<ByteBuf>,
+    /// Set to 1 to have the peer's port taken from the source port of the query instead
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub implied_port: Option<u8>,
+}
+
+/// The values of a response, covering every query type
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct ResponseValues {
+    pub id: ByteBuf,
+    /// Nodes closer to the target, in compact form
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nodes: Option<ByteBuf>,
+    /// Peers for the info hash, each in compact form
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub values: Option<Vec<ByteBuf>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token: Option<ByteBuf>,
+}
+
+impl KrpcMessage {
+    pub fn query(transaction: &[u8], method: &str, args: QueryArgs) -> Self {
+        Self {
+            t: ByteBuf::from(transaction),
+            y: "q".to_owned(),
+            q: Some(method.to_owned()),
+            a: Some(args),
+            ..Default::default()
+        }
+    }
+
+    pub fn response(transaction: ByteBuf, values: ResponseValues) -> Self {
+        Self {
+            t: transaction,
+            y: "r".to_owned(),
+            r: Some(values),
+            ..Default::default()
+        }
+    }
+
+    pub fn error(transaction: ByteBuf, code: i64, message: &str) -> Self {
+        Self {
+            t: transaction,
+            y: "e".to_owned(),
+            e: Some((code, message.to_owned())),
+            ..Default::default()
+        }
+    }
+}
+
+/// Encodes nodes in compact form, leaving out any we can't reach over IPv4
+pub fn encode_nodes(nodes: &[Node]) -> ByteBuf {
+    let mut bytes = Vec::with_capacity(nodes.len() * COMPACT_NODE_LEN);
+    for node in nodes.iter().filter(|node| node.addr.is_ipv4()) {
+        bytes.extend_from_slice(&node.id);
+        bytes.extend_from_slice(&Peer::new(node.addr).to_compact());
+    }
+    ByteBuf::from(bytes)
+}
+
+pub fn decode_nodes(bytes: &[u8]) -> Vec<Node> {
+    bytes
+        .chunks_exact(COMPACT_NODE_LEN)
+        .map(|chunk| Node {
+            id: chunk[..NODE_ID_LEN].try_into().unwrap(),
+            addr: Peer::from_compact(&chunk[NODE_ID_LEN..]).addr,
+        })
+        .collect()
+}
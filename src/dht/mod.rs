@@ -0,0 +1,750 @@
+mod message;
+mod routing;
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::Path;
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use rand::Rng;
+use serde_bytes::ByteBuf;
+use serde_derive::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+use tokio::net::UdpSocket;
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinSet;
+use tokio::time::Instant;
+use tracing::{info, warn};
+
+use self::message::{
+    decode_nodes, encode_nodes, KrpcMessage, QueryArgs, ResponseValues, ERROR_METHOD_UNKNOWN,
+    ERROR_PROTOCOL,
+};
+use self::routing::{Node, RoutingTable, BUCKET_SIZE};
+use crate::ratelimit::RateLimits;
+use crate::tracker::{Peer, COMPACT_LEN_V4, COMPACT_LEN_V6};
+use crate::types::InfoHash;
+use crate::utp::Datagram;
+
+pub const NODE_ID_LEN: usize = 20;
+pub type NodeId = [u8; NODE_ID_LEN];
+
+// Well known nodes to join the DHT through when we don't know any nodes yet
+pub const DEFAULT_BOOTSTRAP_NODES: [&str; 3] = [
+    "router.bittorrent.com:6881",
+    "dht.transmissionbt.com:6881",
+    "router.utorrent.com:6881",
+];
+
+const BUFFER_SIZE: usize = 4096;
+
+// How long to wait for a node to answer a query
+const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+
+// The number of queries a lookup keeps in flight at once
+const LOOKUP_PARALLELISM: usize = 3;
+
+// How often we check on nodes we haven't heard from and look for new nodes close to us
+const REFRESH_INTERVAL: Duration = Duration::from_secs(15 * 60);
+
+// How often the secret tokens are derived from changes. Tokens made with the previous secret are
+// still accepted, so a token stays valid for between one and two rotations.
+const TOKEN_ROTATION: Duration = Duration::from_secs(5 * 60);
+
+// How long we hand out a peer after it announced itself to us
+const PEER_LIFETIME: Duration = Duration::from_secs(30 * 60);
+
+// The most peers we store for a single torrent, and the most torrents we store peers for, so that
+// nodes announcing to us can't make us use up all our memory
+const MAX_PEERS_PER_TORRENT: usize = 1000;
+const MAX_TORRENTS: usize = 10000;
+
+// The most peers sent in a single get_peers response, so that it fits in one datagram
+const MAX_VALUES: usize = 50;
+
+/// Returns the XOR distance between two ids, which compares like a big endian number
+pub fn distance(a: &NodeId, b: &NodeId) -> NodeId {
+    let mut distance = [0u8; NODE_ID_LEN];
+    for (d, (a, b)) in distance.iter_mut().zip(a.iter().zip(b)) {
+        *d = a ^ b;
+    }
+    distance
+}
+
+pub struct DhtConfig {
+    /// The UDP port the node listens on
+    pub port: u16,
+    /// The host:port addresses of the nodes to bootstrap from
+    pub bootstrap: Vec<String>,
+}
+
+/// Our node id and the routing table, saved between runs so we don't have to bootstrap from
+/// scratch every time
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct DhtState {
+    id: ByteBuf,
+    /// The nodes in the routing table, in compact form
+    nodes: ByteBuf,
+}
+
+impl DhtState {
+    fn load(path: &Path) -> anyhow::Result<Option<Self>> {
+        match std::fs::read(path) {
+            Ok(bytes) => Result::Ok(Some(serde_bencode::from_bytes::<Self>(&bytes)?)),
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => Result::Ok(None),
+            Err(error) => Result::Err(error.into()),
+        }
+    }
+
+    async fn save(&self, path: &Path) -> anyhow::Result<()> {
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+
+        let tmp_path = path.with_extension("tmp");
+        tokio::fs::write(&tmp_path, serde_bencode::to_bytes(self)?).await?;
+        tokio::fs::rename(&tmp_path, path).await?;
+
+        Result::Ok(())
+    }
+}
+
+/// The secrets announce tokens are derived from
+struct TokenSecrets {
+    current: [u8; 16],
+    previous: [u8; 16],
+    rotated: Instant,
+}
+
+/// A query waiting for its response
+struct PendingQuery {
+    /// The node the query was sent to, the only one we accept the response from
+    addr: SocketAddr,
+    sender: oneshot::Sender<anyhow::Result<ResponseValues>>,
+}
+
+/// The result of an iterative lookup
+struct Lookup {
+    peers: HashSet<Peer>,
+    /// The closest nodes that answered a get_peers query, with the token each gave us
+    tokens: Vec<(Node, ByteBuf)>,
+}
+
+/// A node in the mainline DHT (BEP 5), which finds peers for torrents without a tracker. It
+/// answers queries from other nodes on its own task, and can be shared between torrents.
+pub struct Dht {
+    id: NodeId,
+    port: u16,
+    socket: Arc<UdpSocket>,
+    /// Limits on tracker and DHT traffic
+    limits: Arc<RateLimits>,
+    bootstrap: Vec<String>,
+    table: Mutex<RoutingTable>,
+    /// Queries waiting for a response, by transaction id
+    pending: Mutex<HashMap<u16, PendingQuery>>,
+    next_transaction: AtomicU16,
+    secrets: Mutex<TokenSecrets>,
+    /// The peers that announced themselves to us for each torrent, with when they announced
+    peers: Mutex<HashMap<InfoHash, HashMap<SocketAddr, Instant>>>,
+}
+
+impl Dht {
+    /// Starts a node, restoring the routing table saved at `state_path` if there is one, and
+    /// joins the DHT in the background. The node binds its own socket unless it is given one
+    /// shared with uTP, along with the datagrams received on it.
+    pub async fn start(
+        config: DhtConfig,
+        state_path: &Path,
+        shared: Option<(Arc<UdpSocket>, mpsc::UnboundedReceiver<Datagram>)>,
+        limits: Arc<RateLimits>,
+    ) -> anyhow::Result<Arc<Self>> {
+        let saved = DhtState::load(state_path).unwrap_or_else(|error| {
+            warn!("Failed to load DHT state {:?}: {}", state_path, error);
+            None
+        });
+
+        let id = saved
+            .as_ref()
+            .and_then(|saved| saved.id.as_ref().try_into().ok())
+            .unwrap_or_else(|| rand::thread_rng().gen());
+        let mut table = RoutingTable::new(id);
+        if let Some(saved) = &saved {
+            for node in decode_nodes(&saved.nodes) {
+                table.insert(node);
+            }
+        }
+
+        let (socket, datagrams) = match shared {
+            Some(shared) => shared,
+            None => {
+                let socket = Arc::new(UdpSocket::bind((Ipv4Addr::UNSPECIFIED, config.port)).await?);
+                let (sender, datagrams) = mpsc::unbounded_channel();
+                tokio::spawn(receive(socket.clone(), sender));
+                (socket, datagrams)
+            }
+        };
+        info!(
+            "DHT node listening on port {} with {} known nodes",
+            config.port,
+            table.len()
+        );
+
+        let mut rng = rand::thread_rng();
+        let dht = Arc::new(Self {
+            id,
+            port: config.port,
+            socket,
+            limits,
+            bootstrap: config.bootstrap,
+            table: Mutex::new(table),
+            pending: Mutex::new(HashMap::new()),
+            next_transaction: AtomicU16::new(rng.gen()),
+            secrets: Mutex::new(TokenSecrets {
+                current: rng.gen(),
+                previous: rng.gen(),
+                rotated: Instant::now(),
+            }),
+            peers: Mutex::new(HashMap::new()),
+        });
+
+        tokio::spawn(dht.clone().run(datagrams));
+        tokio::spawn(dht.clone().refresh());
+
+        Result::Ok(dht)
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Saves our node id and routing table
+    pub async fn save(&self, path: &Path) -> anyhow::Result<()> {
+        let nodes = self.table.lock().unwrap().nodes();
+        let state = DhtState {
+            id: ByteBuf::from(self.id.to_vec()),
+            nodes: encode_nodes(&nodes),
+        };
+        state.save(path).await
+    }
+
+    /// Finds peers for a torrent without telling the DHT about ourselves
+    pub async fn get_peers(self: &Arc<Self>, info_hash: InfoHash) -> Vec<Peer> {
+        let lookup = self.lookup(info_hash, true).await;
+        info!("Found {} peers on the DHT", lookup.peers.len());
+        lookup.peers.into_iter().collect()
+    }
+
+    /// Finds peers for a torrent, and announces to the nodes closest to it that we accept
+    /// connections for the torrent on `port`
+    pub async fn announce(self: &Arc<Self>, info_hash: InfoHash, port: u16) -> Vec<Peer> {
+        let lookup = self.lookup(info_hash, true).await;
+
+        let mut announces = JoinSet::new();
+        for (node, token) in lookup.tokens {
+            let dht = self.clone();
+            let args = QueryArgs {
+                info_hash: Some(ByteBuf::from(info_hash.to_vec())),
+                port: Some(port),
+                token: Some(token),
+                ..self.args()
+            };
+            announces.spawn(async move { dht.query(node.addr, "announce_peer", args).await });
+        }
+        let mut announced = 0;
+        while let Some(result) = announces.join_next().await {
+            if let Ok(Ok(_)) = result {
+                announced += 1;
+            }
+        }
+
+        info!(
+            "Found {} peers on the DHT, announced to {} nodes",
+            lookup.peers.len(),
+            announced
+        );
+        lookup.peers.into_iter().collect()
+    }
+
+    /// Pings a node a peer told us about, adding it to the routing table if it answers
+    pub fn add_node(self: &Arc<Self>, addr: SocketAddr) {
+        if !addr.is_ipv4() {
+            return;
+        }
+
+        let dht = self.clone();
+        tokio::spawn(async move {
+            let _ = dht.query(addr, "ping", dht.args()).await;
+        });
+    }
+
+    /// Joins the DHT by looking up our own id, which fills the buckets closest to us, then keeps
+    /// the routing table fresh by checking on nodes we haven't heard from in a while
+    async fn refresh(self: Arc<Self>) {
+        loop {
+            self.lookup(self.id, false).await;
+            info!(
+                "DHT routing table has {} nodes",
+                self.table.lock().unwrap().len()
+            );
+
+            tokio::time::sleep(REFRESH_INTERVAL).await;
+
+            let questionable = self.table.lock().unwrap().questionable();
+            let mut pings = JoinSet::new();
+            for node in questionable {
+                let dht = self.clone();
+                pings.spawn(async move { dht.query(node.addr, "ping", dht.args()).await });
+            }
+            while pings.join_next().await.is_some() {}
+        }
+    }
+
+    /// Iteratively queries the nodes closest to `target`, each of which tells us about nodes
+    /// closer still, until the closest nodes we know of have all answered. Asks for peers with
+    /// get_peers queries if `get_peers` is set, otherwise only for nodes with find_node.
+    async fn lookup(self: &Arc<Self>, target: NodeId, get_peers: bool) -> Lookup {
+        let mut seeds: Vec<SocketAddr> = self
+            .table
+            .lock()
+            .unwrap()
+            .closest(&target, BUCKET_SIZE)
+            .iter()
+            .map(|node| node.addr)
+            .collect();
+        if seeds.len() < BUCKET_SIZE {
+            seeds.extend(self.bootstrap_addrs().await);
+        }
+
+        let mut queried = HashSet::new();
+        // Nodes we have heard about but not queried yet, and the nodes that answered, keyed by
+        // their distance to the target
+        let mut candidates: BTreeMap<NodeId, Node> = BTreeMap::new();
+        let mut responded: BTreeMap<NodeId, (Node, Option<ByteBuf>)> = BTreeMap::new();
+        let mut peers = HashSet::new();
+        let mut queries = JoinSet::new();
+
+        for addr in seeds {
+            if queried.insert(addr) {
+                self.spawn_lookup_query(&mut queries, addr, target, get_peers);
+            }
+        }
+
+        loop {
+            while queries.len() < LOOKUP_PARALLELISM {
+                let Some((&key, _)) = candidates.first_key_value() else {
+                    break;
+                };
+                // Stop once no candidate is closer than the closest nodes that already answered
+                let kth_closest = responded.keys().nth(BUCKET_SIZE - 1);
+                if kth_closest.is_some_and(|kth_closest| key > *kth_closest) {
+                    break;
+                }
+
+                let (_, node) = candidates.pop_first().unwrap();
+                if queried.insert(node.addr) {
+                    self.spawn_lookup_query(&mut queries, node.addr, target, get_peers);
+                }
+            }
+
+            let Some(joined) = queries.join_next().await else {
+                break;
+            };
+            let Ok((addr, Ok(values))) = joined else {
+                continue;
+            };
+            let Ok(id) = NodeId::try_from(values.id.as_ref()) else {
+                continue;
+            };
+
+            responded.insert(distance(&id, &target), (Node { id, addr }, values.token));
+            for node in decode_nodes(values.nodes.as_deref().map_or(&[], Vec::as_slice)) {
+                if node.id != self.id && !queried.contains(&node.addr) {
+                    candidates.insert(distance(&node.id, &target), node);
+                }
+            }
+            for value in values.values.iter().flatten() {
+                if value.len() == COMPACT_LEN_V4 || value.len() == COMPACT_LEN_V6 {
+                    peers.insert(Peer::from_compact(value));
+                }
+            }
+        }
+
+        let tokens = responded
+            .into_values()
+            .filter_map(|(node, token)| Some((node, token?)))
+            .take(BUCKET_SIZE)
+            .collect();
+        Lookup { peers, tokens }
+    }
+
+    fn spawn_lookup_query(
+        self: &Arc<Self>,
+        queries: &mut JoinSet<(SocketAddr, anyhow::Result<ResponseValues>)>,
+        addr: SocketAddr,
+        target: NodeId,
+        get_peers: bool,
+    ) {
+        let dht = self.clone();
+        let target = Some(ByteBuf::from(target.to_vec()));
+        let (method, args) = if get_peers {
+            let args = QueryArgs {
+                info_hash: target,
+                ..self.args()
+            };
+            ("get_peers", args)
+        } else {
+            (
+                "find_node",
+                QueryArgs {
+                    target,
+                    ..self.args()
+                },
+            )
+        };
+        queries.spawn(async move { (addr, dht.query(addr, method, args).await) });
+    }
+
+    /// Resolves the IPv4 addresses of the bootstrap nodes
+    async fn bootstrap_addrs(&self) -> Vec<SocketAddr> {
+        let mut addrs = Vec::new();
+        for node in &self.bootstrap {
+            match tokio::net::lookup_host(node.as_str()).await {
+                Ok(resolved) => addrs.extend(resolved.filter(SocketAddr::is_ipv4)),
+                Err(error) => warn!("Could not resolve DHT bootstrap node {}: {}", node, error),
+            }
+        }
+        addrs
+    }
+
+    /// Query arguments identifying us, for the query specific arguments to be added to
+    fn args(&self) -> QueryArgs {
+        QueryArgs {
+            id: ByteBuf::from(self.id.to_vec()),
+            ..Default::default()
+        }
+    }
+
+    /// Sends a query to a node and waits for its response
+    async fn query(
+        &self,
+        addr: SocketAddr,
+        method: &str,
+        args: QueryArgs,
+    ) -> anyhow::Result<ResponseValues> {
+        let transaction = self.next_transaction.fetch_add(1, Ordering::Relaxed);
+        let (sender, receiver) = oneshot::channel();
+        self.pending
+            .lock()
+            .unwrap()
+            .insert(transaction, PendingQuery { addr, sender });
+
+        let message = KrpcMessage::query(&transaction.to_be_bytes(), method, args);
+        let response = match self.send(addr, &message).await {
+            Ok(()) => tokio::time::timeout(QUERY_TIMEOUT, receiver).await,
+            Err(error) => {
+                self.pending.lock().unwrap().remove(&transaction);
+                return Result::Err(error);
+            }
+        };
+        self.pending.lock().unwrap().remove(&transaction);
+
+        match response {
+            Ok(Ok(result)) => result,
+            Ok(Err(_)) => Result::Err(anyhow::anyhow!("Query to DHT node {} was dropped", addr)),
+            Err(_) => {
+                self.table.lock().unwrap().failed(addr);
+                Result::Err(anyhow::anyhow!("DHT node {} did not respond", addr))
+            }
+        }
+    }
+
+    async fn send(&self, addr: SocketAddr, message: &KrpcMessage) -> anyhow::Result<()> {
+        let bytes = serde_bencode::to_bytes(message)?;
+        self.limits.upload.acquire(bytes.len()).await;
+        self.socket.send_to(&bytes, addr).await?;
+        Result::Ok(())
+    }
+
+    /// Receives messages from other nodes, answering their queries and passing responses on to
+    /// the queries waiting for them
+    async fn run(self: Arc<Self>, mut datagrams: mpsc::UnboundedReceiver<Datagram>) {
+        while let Some((bytes, addr)) = datagrams.recv().await {
+            self.limits.download.acquire(bytes.len()).await;
+            // Anything that isn't a KRPC message is ignored
+            let Ok(message) = serde_bencode::from_bytes::<KrpcMessage>(&bytes) else {
+                continue;
+            };
+
+            match message.y.as_str() {
+                "q" => {
+                    let response = match self.answer_query(addr, &message) {
+                        Ok(values) => KrpcMessage::response(message.t, values),
+                        Err((code, error)) => KrpcMessage::error(message.t, code, error),
+                    };
+                    if let Err(error) = self.send(addr, &response).await {
+                        warn!("Failed to respond to DHT node {}: {}", addr, error);
+                    }
+                }
+                "r" | "e" => self.handle_response(addr, message),
+                _ => {}
+            }
+        }
+    }
+
+    /// Answers a query from another node, or returns the KRPC error code and message to send back
+    fn answer_query(
+        &self,
+        addr: SocketAddr,
+        message: &KrpcMessage,
+    ) -> Result<ResponseValues, (i64, &'static str)> {
+        let (Some(method), Some(args)) = (&message.q, &message.a) else {
+            return Result::Err((ERROR_PROTOCOL, "Missing query method or arguments"));
+        };
+        let id = parse_id(Some(&args.id))?;
+        self.table.lock().unwrap().insert(Node { id, addr });
+
+        let mut values = ResponseValues {
+            id: ByteBuf::from(self.id.to_vec()),
+            ..Default::default()
+        };
+        match method.as_str() {
+            "ping" => {}
+            "find_node" => {
+                let target = parse_id(args.target.as_ref())?;
+                let nodes = self.table.lock().unwrap().closest(&target, BUCKET_SIZE);
+                values.nodes = Some(encode_nodes(&nodes));
+            }
+            "get_peers" => {
+                let info_hash = parse_id(args.info_hash.as_ref())?;
+                let nodes = self.table.lock().unwrap().closest(&info_hash, BUCKET_SIZE);
+                values.nodes = Some(encode_nodes(&nodes));
+                values.token = Some(self.token(addr.ip(), false));
+
+                let peers = self.stored_peers(&info_hash);
+                if !peers.is_empty() {
+                    values.values = Some(peers);
+                }
+            }
+            "announce_peer" => {
+                let info_hash = parse_id(args.info_hash.as_ref())?;
+                let valid_token = args.token.as_ref().is_some_and(|token| {
+                    *token == self.token(addr.ip(), false) || *token == self.token(addr.ip(), true)
+                });
+                if !valid_token {
+                    return Result::Err((ERROR_PROTOCOL, "Bad token"));
+                }
+
+                let port = match (args.implied_port, args.port) {
+                    (Some(1), _) => addr.port(),
+                    (_, Some(port)) => port,
+                    _ => return Result::Err((ERROR_PROTOCOL, "Missing port")),
+                };
+                self.store_peer(info_hash, SocketAddr::new(addr.ip(), port));
+            }
+            _ => return Result::Err((ERROR_METHOD_UNKNOWN, "Method Unknown")),
+        }
+
+        Result::Ok(values)
+    }
+
+    fn handle_response(&self, addr: SocketAddr, message: KrpcMessage) {
+        let Ok(transaction) = <[u8; 2]>::try_from(message.t.as_ref()) else {
+            return;
+        };
+        let transaction = u16::from_be_bytes(transaction);
+
+        let query = {
+            let mut pending = self.pending.lock().unwrap();
+            match pending.get(&transaction) {
+                Some(query) if query.addr == addr => pending.remove(&transaction),
+                _ => None,
+            }
+        };
+        let Some(query) = query else {
+            return;
+        };
+
+        let result = match (message.r, message.e) {
+            (Some(values), _) => {
+                if let Ok(id) = NodeId::try_from(values.id.as_ref()) {
+                    self.table.lock().unwrap().insert(Node { id, addr });
+                }
+                Result::Ok(values)
+            }
+            (None, Some((code, error))) => Result::Err(anyhow::anyhow!(
+                "DHT node {} returned error {}: {}",
+                addr,
+                code,
+                error
+            )),
+            (None, None) => Result::Err(anyhow::anyhow!("Invalid response from DHT node {}", addr)),
+        };
+        let _ = query.sender.send(result);
+    }
+
+    /// Returns the token a node at `ip` has to send back to announce itself to us, made with
+    /// either the current or the previous secret
+    fn token(&self, ip: IpAddr, previous: bool) -> ByteBuf {
+        let mut secrets = self.secrets.lock().unwrap();
+        if secrets.rotated.elapsed() >= TOKEN_ROTATION {
+            secrets.previous = secrets.current;
+            secrets.current = rand::thread_rng().gen();
+            secrets.rotated = Instant::now();
+        }
+
+        let mut sha1 = Sha1::new();
+        match ip {
+            IpAddr::V4(ip) => sha1.update(ip.octets()),
+            IpAddr::V6(ip) => sha1.update(ip.octets()),
+        }
+        if previous {
+            sha1.update(secrets.previous);
+        } else {
+            sha1.update(secrets.current);
+        }
+        ByteBuf::from(sha1.finalize().to_vec())
+    }
+
+    /// Remembers a peer that announced itself to us. Peers that announced too long ago are
+    /// forgotten to make room once a limit is reached, after which the peer that announced longest
+    /// ago makes way for a new one, while new torrents are turned away.
+    fn store_peer(&self, info_hash: InfoHash, addr: SocketAddr) {
+        let mut peers = self.peers.lock().unwrap();
+        if !peers.contains_key(&info_hash) && peers.len() >= MAX_TORRENTS {
+            peers.retain(|_, torrent_peers| {
+                torrent_peers.retain(|_, announced| announced.elapsed() < PEER_LIFETIME);
+                !torrent_peers.is_empty()
+            });
+            if peers.len() >= MAX_TORRENTS {
+                return;
+            }
+        }
+
+        let torrent_peers = peers.entry(info_hash).or_default();
+        if !torrent_peers.contains_key(&addr) && torrent_peers.len() >= MAX_PEERS_PER_TORRENT {
+            torrent_peers.retain(|_, announced| announced.elapsed() < PEER_LIFETIME);
+            let oldest = torrent_peers
+                .iter()
+                .min_by_key(|(_, announced)| **announced)
+                .map(|(addr, _)| *addr);
+            if let Some(oldest) = oldest.filter(|_| torrent_peers.len() >= MAX_PEERS_PER_TORRENT) {
+                torrent_peers.remove(&oldest);
+            }
+        }
+        torrent_peers.insert(addr, Instant::now());
+    }
+
+    /// Returns the peers announced to us for a torrent in compact form, forgetting any that
+    /// announced too long ago
+    fn stored_peers(&self, info_hash: &InfoHash) -> Vec<ByteBuf> {
+        let mut peers = self.peers.lock().unwrap();
+        let Some(torrent_peers) = peers.get_mut(info_hash) else {
+            return Vec::new();
+        };
+
+        torrent_peers.retain(|_, announced| announced.elapsed() < PEER_LIFETIME);
+        torrent_peers
+            .keys()
+            .take(MAX_VALUES)
+            .map(|addr| ByteBuf::from(Peer::new(*addr).to_compact()))
+            .collect()
+    }
+}
+
+fn parse_id(id: Option<&ByteBuf>) -> Result<NodeId, (i64, &'static str)> {
+    id.and_then(|id| id.as_ref().try_into().ok())
+        .ok_or((ERROR_PROTOCOL, "Missing or invalid id"))
+}
+
+/// Receives datagrams on a socket the node has to itself, passing them on to the node
+async fn receive(socket: Arc<UdpSocket>, sender: mpsc::UnboundedSender<Datagram>) {
+    let mut buf = vec![0u8; BUFFER_SIZE];
+    loop {
+        match socket.recv_from(&mut buf).await {
+            Ok((len, addr)) => {
+                if sender.send((buf[..len].to_vec(), addr)).is_err() {
+                    return;
+                }
+            }
+            Err(error) => warn!("Failed to receive from DHT socket: {}", error),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Starts a node on its own loopback socket, bootstrapping from the given nodes
+    async fn start_node(bootstrap: &[Arc<Dht>]) -> Arc<Dht> {
+        let socket = Arc::new(UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap());
+        let (sender, datagrams) = mpsc::unbounded_channel();
+        tokio::spawn(receive(socket.clone(), sender));
+
+        let config = DhtConfig {
+            port: socket.local_addr().unwrap().port(),
+            bootstrap: bootstrap
+                .iter()
+                .map(|node| format!("127.0.0.1:{}", node.port()))
+                .collect(),
+        };
+        // Nothing is saved, so the node starts with a new id and an empty routing table
+        let state_path = std::env::temp_dir().join("rustor-dht-test-missing/dht.dat");
+        Dht::start(
+            config,
+            &state_path,
+            Some((socket, datagrams)),
+            Arc::new(RateLimits::default()),
+        )
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn finds_peers_announced_on_loopback() {
+        let first = start_node(&[]).await;
+        let mut nodes = vec![first.clone()];
+        for _ in 0..4 {
+            nodes.push(start_node(std::slice::from_ref(&first)).await);
+        }
+
+        let info_hash = [0x42; 20];
+        let found = nodes[1].announce(info_hash, 6881).await;
+        assert!(found.is_empty());
+
+        let found = nodes[4].get_peers(info_hash).await;
+        let expected = Peer::new(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 6881));
+        assert_eq!(found, vec![expected]);
+    }
+
+    #[tokio::test]
+    async fn caps_stored_peers() {
+        let node = start_node(&[]).await;
+        let info_hash = [1; 20];
+        for port in 0..=MAX_PEERS_PER_TORRENT as u16 {
+            node.store_peer(info_hash, SocketAddr::new(Ipv4Addr::LOCALHOST.into(), port));
+        }
+
+        let peers = node.peers.lock().unwrap();
+        let torrent_peers = &peers[&info_hash];
+        assert_eq!(torrent_peers.len(), MAX_PEERS_PER_TORRENT);
+        // The newest peer replaced the oldest one
+        let newest = MAX_PEERS_PER_TORRENT as u16;
+        assert!(torrent_peers.contains_key(&SocketAddr::new(Ipv4Addr::LOCALHOST.into(), newest)));
+    }
+
+    #[tokio::test]
+    async fn caps_stored_torrents() {
+        let node = start_node(&[]).await;
+        let addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 6881);
+        for i in 0..=MAX_TORRENTS as u32 {
+            let mut info_hash = [0; 20];
+            info_hash[..4].copy_from_slice(&i.to_be_bytes());
+            node.store_peer(info_hash, addr);
+        }
+
+        assert_eq!(node.peers.lock().unwrap().len(), MAX_TORRENTS);
+    }
+}
@@ -0,0 +1,125 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use super::{distance, NodeId, NODE_ID_LEN};
+
+// The most nodes kept in each bucket, and the number of closest nodes a lookup converges on
+pub const BUCKET_SIZE: usize = 8;
+
+// How long a node stays good after we last heard from it, before we ping it to check
+const NODE_LIFETIME: Duration = Duration::from_secs(15 * 60);
+
+// The number of queries in a row a node can fail to answer before it is dropped
+const MAX_FAILURES: u32 = 2;
+
+/// A node in the DHT, identified by its node id
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Node {
+    pub id: NodeId,
+    pub addr: SocketAddr,
+}
+
+#[derive(Debug)]
+struct Entry {
+    node: Node,
+    last_seen: Instant,
+    failures: u32,
+}
+
+/// The nodes we know about, grouped into buckets by how close they are to our own id. Bucket i
+/// holds the nodes whose ids share exactly i leading bits with ours, so we know many nodes close
+/// to us and only a few far away, as described in BEP 5.
+#[derive(Debug)]
+pub struct RoutingTable {
+    id: NodeId,
+    buckets: Vec<Vec<Entry>>,
+}
+
+impl RoutingTable {
+    pub fn new(id: NodeId) -> Self {
+        Self {
+            id,
+            buckets: (0..NODE_ID_LEN * 8).map(|_| Vec::new()).collect(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.buckets.iter().map(Vec::len).sum()
+    }
+
+    /// Returns the bucket a node belongs in, or None for our own id
+    fn bucket_index(&self, id: &NodeId) -> Option<usize> {
+        let distance = distance(&self.id, id);
+        let byte = distance.iter().position(|b| *b != 0)?;
+        Some(byte * 8 + distance[byte].leading_zeros() as usize)
+    }
+
+    /// Records that we heard from a node, adding it to its bucket if there is room or if it can
+    /// replace a node that stopped answering
+    pub fn insert(&mut self, node: Node) {
+        let Some(index) = self.bucket_index(&node.id) else {
+            return;
+        };
+        let bucket = &mut self.buckets[index];
+
+        if let Some(entry) = bucket.iter_mut().find(|entry| entry.node.addr == node.addr) {
+            entry.node = node;
+            entry.last_seen = Instant::now();
+            entry.failures = 0;
+            return;
+        }
+
+        let entry = Entry {
+            node,
+            last_seen: Instant::now(),
+            failures: 0,
+        };
+        if bucket.len() < BUCKET_SIZE {
+            bucket.push(entry);
+        } else if let Some(stale) = bucket.iter_mut().find(|entry| entry.failures > 0) {
+            *stale = entry;
+        }
+    }
+
+    /// Records that a node failed to answer a query, dropping it after too many failures
+    pub fn failed(&mut self, addr: SocketAddr) {
+        for bucket in &mut self.buckets {
+            if let Some(position) = bucket.iter().position(|entry| entry.node.addr == addr) {
+                bucket[position].failures += 1;
+                if bucket[position].failures > MAX_FAILURES {
+                    bucket.remove(position);
+                }
+                return;
+            }
+        }
+    }
+
+    /// Returns up to `count` of the nodes closest to `target`, closest first
+    pub fn closest(&self, target: &NodeId, count: usize) -> Vec<Node> {
+        let mut nodes = self.nodes();
+        nodes.sort_by_key(|node| distance(&node.id, target));
+        nodes.truncate(count);
+        nodes
+    }
+
+    /// Returns the nodes we haven't heard from in a while, which should be pinged to check they
+    /// are still there
+    pub fn questionable(&self) -> Vec<Node> {
+        self.buckets
+            .iter()
+            .flatten()
+            .filter(|entry| entry.last_seen.elapsed() >= NODE_LIFETIME)
+            .map(|entry| entry.node)
+            .collect()
+    }
+
+    pub fn nodes(&self) -> Vec<Node> {
+        self.buckets
+            .iter()
+            .flatten()
+            .map(|entry| entry.node)
+            .collect()
+    }
+}
@@ -0,0 +1,312 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use rand::Rng;
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tracing::{info, warn};
+
+use crate::magnet::parse_info_hash;
+use crate::tracker::Peer;
+use crate::types::InfoHash;
+
+// The multicast groups local service discovery announces are sent to (BEP 14)
+const LSD_PORT: u16 = 6771;
+const LSD_GROUP_V4: Ipv4Addr = Ipv4Addr::new(239, 192, 152, 143);
+const LSD_GROUP_V6: Ipv6Addr = Ipv6Addr::new(0xff15, 0, 0, 0, 0, 0, 0xefc0, 0x988f);
+
+// How often we announce the torrents we are running to the local network
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+const BUFFER_SIZE: usize = 1400;
+
+/// A torrent we announce on the local network, and where to send the peers found for it
+struct LocalTorrent {
+    port: u16,
+    peer_sender: mpsc::UnboundedSender<Vec<Peer>>,
+}
+
+/// Finds peers for our torrents on the local network by announcing them to a multicast group,
+/// and listens for the announces of other clients on the same network (BEP 14)
+pub struct LocalDiscovery {
+    /// The multicast sockets we joined, with the group each announces to
+    sockets: Vec<(UdpSocket, SocketAddr)>,
+    /// A random value sent with our announces, so we can ignore them when they loop back to us
+    cookie: String,
+    torrents: Mutex<HashMap<InfoHash, LocalTorrent>>,
+}
+
+impl LocalDiscovery {
+    /// Joins the IPv4 and IPv6 multicast groups, failing only if neither can be joined
+    pub async fn start() -> anyhow::Result<Arc<Self>> {
+        let mut sockets = Vec::new();
+        let groups = [IpAddr::V4(LSD_GROUP_V4), IpAddr::V6(LSD_GROUP_V6)];
+        for group in groups {
+            let group = SocketAddr::new(group, LSD_PORT);
+            match join_group(group, Ipv4Addr::UNSPECIFIED) {
+                Ok(socket) => sockets.push((socket, group)),
+                Err(error) => warn!("Failed to join LSD multicast group {}: {}", group, error),
+            }
+        }
+        if sockets.is_empty() {
+            return Result::Err(anyhow::anyhow!("Failed to join any LSD multicast group"));
+        }
+
+        Result::Ok(Self::with_sockets(sockets))
+    }
+
+    /// Starts listening on the given sockets, each of which sends announces to the address paired
+    /// with it
+    fn with_sockets(sockets: Vec<(UdpSocket, SocketAddr)>) -> Arc<Self> {
+        let cookie = format!("{:08x}", rand::thread_rng().gen::<u32>());
+        let lsd = Arc::new(Self {
+            sockets,
+            cookie,
+            torrents: Mutex::new(HashMap::new()),
+        });
+
+        for i in 0..lsd.sockets.len() {
+            tokio::spawn(lsd.clone().listen(i));
+        }
+        tokio::spawn(lsd.clone().announce_periodically());
+
+        lsd
+    }
+
+    /// Starts announcing a torrent we accept connections for on `port`, sending any peers found
+    /// for it on the local network to `peer_sender`
+    pub async fn add_torrent(
+        &self,
+        info_hash: InfoHash,
+        port: u16,
+        peer_sender: mpsc::UnboundedSender<Vec<Peer>>,
+    ) {
+        let torrent = LocalTorrent { port, peer_sender };
+        self.torrents.lock().unwrap().insert(info_hash, torrent);
+        self.announce(port, &[info_hash]).await;
+    }
+
+    pub fn remove_torrent(&self, info_hash: &InfoHash) {
+        self.torrents.lock().unwrap().remove(info_hash);
+    }
+
+    async fn announce_periodically(self: Arc<Self>) {
+        let mut interval = tokio::time::interval(ANNOUNCE_INTERVAL);
+        // Torrents are announced as they are added, so skip the first tick
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+
+            // A single announce can list several torrents, as long as they share a port
+            let mut by_port: HashMap<u16, Vec<InfoHash>> = HashMap::new();
+            for (info_hash, torrent) in self.torrents.lock().unwrap().iter() {
+                by_port.entry(torrent.port).or_default().push(*info_hash);
+            }
+            for (port, info_hashes) in by_port {
+                self.announce(port, &info_hashes).await;
+            }
+        }
+    }
+
+    async fn announce(&self, port: u16, info_hashes: &[InfoHash]) {
+        for (socket, group) in &self.sockets {
+            let msg = announce_message(group, port, info_hashes, &self.cookie);
+            if let Err(error) = socket.send_to(msg.as_bytes(), group).await {
+                warn!("Failed to send LSD announce to {}: {}", group, error);
+            }
+        }
+    }
+
+    /// Receives announces on one of our sockets, passing on peers for the torrents we are running
+    async fn listen(self: Arc<Self>, socket: usize) {
+        let mut buf = vec![0u8; BUFFER_SIZE];
+        loop {
+            let (len, addr) = match self.sockets[socket].0.recv_from(&mut buf).await {
+                Ok(received) => received,
+                Err(error) => {
+                    warn!("Failed to receive LSD announce: {}", error);
+                    continue;
+                }
+            };
+            let Some(announce) = parse_announce(&buf[..len]) else {
+                continue;
+            };
+            if announce.cookie.as_deref() == Some(self.cookie.as_str()) {
+                continue;
+            }
+
+            let peer = Peer::new(SocketAddr::new(addr.ip(), announce.port));
+            let torrents = self.torrents.lock().unwrap();
+            for info_hash in &announce.info_hashes {
+                if let Some(torrent) = torrents.get(info_hash) {
+                    info!("Found local peer {} through LSD", peer);
+                    let _ = torrent.peer_sender.send(vec![peer]);
+                }
+            }
+        }
+    }
+}
+
+/// Binds a socket to the group's port and joins the multicast group on it, using `interface` for
+/// IPv4. Other clients on the same machine may have done the same, so the port is shared.
+fn join_group(group: SocketAddr, interface: Ipv4Addr) -> anyhow::Result<UdpSocket> {
+    let (domain, any) = match group.ip() {
+        IpAddr::V4(_) => (Domain::IPV4, IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
+        IpAddr::V6(_) => (Domain::IPV6, IpAddr::V6(Ipv6Addr::UNSPECIFIED)),
+    };
+
+    let socket = Socket::new(domain, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    #[cfg(unix)]
+    socket.set_reuse_port(true)?;
+    match group.ip() {
+        IpAddr::V4(group) => {
+            socket.join_multicast_v4(&group, &interface)?;
+            socket.set_multicast_if_v4(&interface)?;
+            socket.set_multicast_loop_v4(true)?;
+        }
+        IpAddr::V6(group) => {
+            socket.set_only_v6(true)?;
+            socket.join_multicast_v6(&group, 0)?;
+            socket.set_multicast_loop_v6(true)?;
+        }
+    }
+    socket.bind(&SocketAddr::new(any, group.port()).into())?;
+    socket.set_nonblocking(true)?;
+
+    Result::Ok(UdpSocket::from_std(socket.into())?)
+}
+
+fn announce_message(
+    group: &SocketAddr,
+    port: u16,
+    info_hashes: &[InfoHash],
+    cookie: &str,
+) -> String {
+    let mut msg = format!(
+        "BT-SEARCH * HTTP/1.1\r\nHost: {}\r\nPort: {}\r\n",
+        group, port
+    );
+    for info_hash in info_hashes {
+        let hex: String = info_hash.iter().map(|b| format!("{:02x}", b)).collect();
+        msg.push_str(&format!("Infohash: {}\r\n", hex));
+    }
+    msg.push_str(&format!("cookie: {}\r\n\r\n\r\n", cookie));
+    msg
+}
+
+/// An announce from another client on the local network
+struct LsdAnnounce {
+    port: u16,
+    info_hashes: Vec<InfoHash>,
+    cookie: Option<String>,
+}
+
+/// Parses an announce, returning None for anything else sent to the group
+fn parse_announce(buf: &[u8]) -> Option<LsdAnnounce> {
+    let msg = std::str::from_utf8(buf).ok()?;
+    let mut lines = msg.lines();
+    if lines.next()?.trim() != "BT-SEARCH * HTTP/1.1" {
+        return None;
+    }
+
+    let mut port = None;
+    let mut info_hashes = Vec::new();
+    let mut cookie = None;
+    for line in lines {
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        let value = value.trim();
+        match name.trim().to_ascii_lowercase().as_str() {
+            "port" => port = value.parse().ok(),
+            "infohash" => info_hashes.extend(parse_info_hash(value).ok()),
+            "cookie" => cookie = Some(value.to_owned()),
+            _ => {}
+        }
+    }
+
+    Some(LsdAnnounce {
+        port: port?,
+        info_hashes,
+        cookie,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The IPv4 group on a free port, so other clients on the machine don't see our announces
+    fn loopback_group() -> SocketAddr {
+        let port = std::net::UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        SocketAddr::new(LSD_GROUP_V4.into(), port)
+    }
+
+    /// Starts a session that joins the group on the loopback interface, so announces sent to the
+    /// group loop back to every session on the machine
+    fn join_loopback(group: SocketAddr) -> Arc<LocalDiscovery> {
+        let socket = join_group(group, Ipv4Addr::LOCALHOST).unwrap();
+        LocalDiscovery::with_sockets(vec![(socket, group)])
+    }
+
+    #[tokio::test]
+    async fn finds_peers_between_sessions() {
+        let group = loopback_group();
+        let a = join_loopback(group);
+        let b = join_loopback(group);
+
+        let info_hash = [0x5a; 20];
+        let (sender_a, mut peers_a) = mpsc::unbounded_channel();
+        let (sender_b, mut peers_b) = mpsc::unbounded_channel();
+        b.add_torrent(info_hash, 7002, sender_b).await;
+        a.add_torrent(info_hash, 7001, sender_a).await;
+
+        let peers = tokio::time::timeout(Duration::from_secs(5), peers_b.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        let expected = Peer::new(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 7001));
+        assert_eq!(peers, vec![expected]);
+
+        // B's first announce may have arrived before A was running the torrent
+        b.announce(7002, &[info_hash]).await;
+        let peers = tokio::time::timeout(Duration::from_secs(5), peers_a.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        let expected = Peer::new(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 7002));
+        assert_eq!(peers, vec![expected]);
+    }
+
+    #[tokio::test]
+    async fn ignores_own_announces() {
+        let lsd = join_loopback(loopback_group());
+
+        let (sender, mut peers) = mpsc::unbounded_channel();
+        lsd.add_torrent([0x5a; 20], 7001, sender).await;
+
+        let received = tokio::time::timeout(Duration::from_millis(200), peers.recv()).await;
+        assert!(received.is_err());
+    }
+
+    #[test]
+    fn parses_announces() {
+        let group = SocketAddr::new(LSD_GROUP_V4.into(), LSD_PORT);
+        let msg = announce_message(&group, 6881, &[[0xab; 20], [0xcd; 20]], "cafe");
+        let announce = parse_announce(msg.as_bytes()).unwrap();
+        assert_eq!(announce.port, 6881);
+        assert_eq!(announce.info_hashes, vec![[0xab; 20], [0xcd; 20]]);
+        assert_eq!(announce.cookie.as_deref(), Some("cafe"));
+
+        assert!(parse_announce(b"M-SEARCH * HTTP/1.1\r\nPort: 6881\r\n\r\n").is_none());
+        assert!(parse_announce(b"BT-SEARCH * HTTP/1.1\r\nInfohash: 00\r\n\r\n").is_none());
+    }
+}
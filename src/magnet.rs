@@ -0,0 +1,158 @@
+use url::Url;
+
+use crate::types::{InfoHash, INFO_HASH_LEN};
+
+const BTIH_PREFIX: &str = "urn:btih:";
+const BASE32_ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+/// A parsed `magnet:` URI identifying a torrent by its info hash, as described in BEP 9
+#[derive(Debug)]
+pub struct MagnetLink {
+    pub info_hash: InfoHash,
+    pub name: Option<String>,
+    pub trackers: Vec<String>,
+}
+
+impl MagnetLink {
+    pub fn parse(uri: &str) -> anyhow::Result<Self> {
+        let url = Url::parse(uri)?;
+        if url.scheme() != "magnet" {
+            return Result::Err(anyhow::anyhow!(
+                "Invalid magnet link, expected scheme magnet got {}",
+                url.scheme()
+            ));
+        }
+
+        let mut info_hash = None;
+        let mut name = None;
+        let mut trackers = Vec::new();
+        for (key, value) in url.query_pairs() {
+            match key.as_ref() {
+                "xt" => {
+                    if let Some(hash) = value.strip_prefix(BTIH_PREFIX) {
+                        info_hash = Some(parse_info_hash(hash)?);
+                    }
+                }
+                "dn" => name = Some(value.into_owned()),
+                "tr" => trackers.push(value.into_owned()),
+                _ => {}
+            }
+        }
+
+        let info_hash = info_hash
+            .ok_or_else(|| anyhow::anyhow!("Invalid magnet link, missing urn:btih info hash"))?;
+
+        Result::Ok(Self {
+            info_hash,
+            name,
+            trackers,
+        })
+    }
+}
+
+pub fn parse_info_hash(hash: &str) -> anyhow::Result<InfoHash> {
+    let bytes = match hash.len() {
+        40 => decode_hex(hash)?,
+        32 => decode_base32(hash)?,
+        len => {
+            return Result::Err(anyhow::anyhow!(
+                "Invalid info hash length, expected 40 (hex) or 32 (base32) got {}",
+                len
+            ))
+        }
+    };
+
+    let mut info_hash = [0u8; INFO_HASH_LEN];
+    info_hash.copy_from_slice(&bytes);
+    Result::Ok(info_hash)
+}
+
+fn decode_hex(s: &str) -> anyhow::Result<Vec<u8>> {
+    // from_str_radix would also accept a sign in front of a digit
+    if !s.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Result::Err(anyhow::anyhow!("Invalid hex info hash: {}", s));
+    }
+
+    (0..s.len())
+        .step_by(2)
+        .map(|i| {
+            u8::from_str_radix(&s[i..i + 2], 16)
+                .map_err(|_| anyhow::anyhow!("Invalid hex info hash: {}", s))
+        })
+        .collect()
+}
+
+fn decode_base32(s: &str) -> anyhow::Result<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(s.len() * 5 / 8);
+    let mut buffer = 0u32;
+    let mut bits = 0;
+    for c in s.bytes() {
+        let value = BASE32_ALPHABET
+            .iter()
+            .position(|a| *a == c.to_ascii_uppercase())
+            .ok_or_else(|| anyhow::anyhow!("Invalid base32 info hash: {}", s))?;
+
+        buffer = (buffer << 5) | value as u32;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((buffer >> bits) as u8);
+        }
+    }
+
+    Result::Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INFO_HASH_HEX: &str = "c12fe1c06bba254a9dc9f519b335aa7c1367a88a";
+
+    fn expected_info_hash() -> InfoHash {
+        let mut info_hash = [0u8; INFO_HASH_LEN];
+        for (i, byte) in info_hash.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&INFO_HASH_HEX[2 * i..2 * i + 2], 16).unwrap();
+        }
+        info_hash
+    }
+
+    #[test]
+    fn parses_hex_magnet_links() {
+        let magnet = MagnetLink::parse(&format!(
+            "magnet:?xt=urn:btih:{}&dn=Some+File&tr=http%3A%2F%2Ftracker.example%2Fannounce\
+             &tr=udp%3A%2F%2Ftracker.example%3A6969",
+            INFO_HASH_HEX.to_uppercase()
+        ))
+        .unwrap();
+        assert_eq!(magnet.info_hash, expected_info_hash());
+        assert_eq!(magnet.name.as_deref(), Some("Some File"));
+        assert_eq!(
+            magnet.trackers,
+            vec![
+                "http://tracker.example/announce",
+                "udp://tracker.example:6969"
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_base32_magnet_links() {
+        let magnet =
+            MagnetLink::parse("magnet:?xt=urn:btih:yex6dqdlxisuvhoj6um3gnnkpqjwpkek").unwrap();
+        assert_eq!(magnet.info_hash, expected_info_hash());
+        assert_eq!(magnet.name, None);
+        assert!(magnet.trackers.is_empty());
+    }
+
+    #[test]
+    fn rejects_invalid_magnet_links() {
+        assert!(
+            MagnetLink::parse(&format!("http://example/?xt=urn:btih:{}", INFO_HASH_HEX)).is_err()
+        );
+        assert!(MagnetLink::parse("magnet:?dn=name").is_err());
+        assert!(parse_info_hash(&INFO_HASH_HEX[..38]).is_err());
+        assert!(parse_info_hash(&format!("+f{}", &INFO_HASH_HEX[2..])).is_err());
+        assert!(parse_info_hash("YEX6DQDLXISUVHOJ6UM3GNNKPQJWPKE1").is_err());
+    }
+}
@@ -1,21 +1,101 @@
+mod announcer;
+mod bencode;
+mod choker;
 mod client;
+mod dht;
+mod lsd;
+mod magnet;
+mod picker;
+mod ratelimit;
+mod resume;
+mod state;
 mod torrent;
 mod torrent_file;
 mod tracker;
 mod types;
+mod utp;
 mod worker;
 mod writer;
 
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
-use clap::{command, Parser};
-use client::TorrentClient;
+use clap::{Parser, Subcommand};
+use client::{ClientConfig, TorrentClient};
+use dht::DhtConfig;
+use magnet::MagnetLink;
+use ratelimit::RateConfig;
 use torrent_file::TorrentMetaInfo;
+use worker::EncryptionPolicy;
 
 #[derive(Debug, Parser)]
 #[command(author, version, about, long_about = None)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Args {
-    filename: String,
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// Path to a .torrent file, or a magnet link
+    #[arg(required = true)]
+    source: Option<String>,
+    /// Port to listen on for incoming peer connections
+    #[arg(short, long, default_value_t = 6881)]
+    port: u16,
+    /// Keep seeding after the download completes, until interrupted
+    #[arg(long)]
+    seed: bool,
+    /// Directory to save fast resume state in
+    #[arg(long, default_value = ".rustor")]
+    resume_dir: PathBuf,
+    /// Don't use the DHT to find peers
+    #[arg(long)]
+    no_dht: bool,
+    /// UDP port for the DHT node to listen on, the same as the peer port if not given
+    #[arg(long)]
+    dht_port: Option<u16>,
+    /// A host:port address of a DHT node to bootstrap from, which can be given more than once
+    #[arg(long = "dht-bootstrap", default_values = dht::DEFAULT_BOOTSTRAP_NODES)]
+    dht_bootstrap: Vec<String>,
+    /// Don't look for peers on the local network
+    #[arg(long)]
+    no_lsd: bool,
+    /// Whether to encrypt connections to peers
+    #[arg(long, value_enum, default_value = "prefer")]
+    encryption: EncryptionPolicy,
+    /// Only connect to peers over TCP, not uTP
+    #[arg(long)]
+    no_utp: bool,
+    /// Number of peers to upload to at once, besides one rotating optimistic unchoke
+    #[arg(long, default_value_t = choker::DEFAULT_UNCHOKE_SLOTS)]
+    unchoke_slots: usize,
+    /// Upload limit in KiB/s across every torrent, which can be changed while running by typing
+    /// `limit global upload <KiB/s>`
+    #[arg(long)]
+    upload_limit: Option<u64>,
+    /// Download limit in KiB/s across every torrent, changed with `limit global download <KiB/s>`
+    #[arg(long)]
+    download_limit: Option<u64>,
+    /// Upload limit in KiB/s for the torrent, changed with `limit torrent upload <KiB/s>`
+    #[arg(long)]
+    torrent_upload_limit: Option<u64>,
+    /// Download limit in KiB/s for the torrent, changed with `limit torrent download <KiB/s>`
+    #[arg(long)]
+    torrent_download_limit: Option<u64>,
+    /// Upload limit in KiB/s for tracker and DHT traffic, changed with
+    /// `limit overhead upload <KiB/s>`
+    #[arg(long)]
+    overhead_upload_limit: Option<u64>,
+    /// Download limit in KiB/s for tracker and DHT traffic, changed with
+    /// `limit overhead download <KiB/s>`
+    #[arg(long)]
+    overhead_download_limit: Option<u64>,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Print the number of seeders and leechers each tracker knows about, without downloading
+    Scrape {
+        /// Path to a .torrent file
+        torrent: PathBuf,
+    },
 }
 
 #[tokio::main]
@@ -24,7 +104,60 @@ async fn main() -> anyhow::Result<()> {
 
     let args = Args::parse();
 
-    let client = TorrentClient::new(6881);
-    let torrent_file = TorrentMetaInfo::from_file(Path::new(&args.filename))?;
-    client.download_file(torrent_file).await
+    if let Some(Command::Scrape { torrent }) = &args.command {
+        return scrape(torrent).await;
+    }
+    let source = args.source.unwrap_or_default();
+
+    let dht = (!args.no_dht).then(|| DhtConfig {
+        port: args.dht_port.unwrap_or(args.port),
+        bootstrap: args.dht_bootstrap,
+    });
+    let client = TorrentClient::new(ClientConfig {
+        port: args.port,
+        seed: args.seed,
+        resume_dir: args.resume_dir,
+        dht,
+        lsd: !args.no_lsd,
+        encryption: args.encryption,
+        utp: !args.no_utp,
+        unchoke_slots: args.unchoke_slots,
+        limits: RateConfig::from_kib(args.upload_limit, args.download_limit),
+        torrent_limits: RateConfig::from_kib(
+            args.torrent_upload_limit,
+            args.torrent_download_limit,
+        ),
+        overhead_limits: RateConfig::from_kib(
+            args.overhead_upload_limit,
+            args.overhead_download_limit,
+        ),
+    })
+    .await?;
+    if source.starts_with("magnet:") {
+        let magnet = MagnetLink::parse(&source)?;
+        client.download_magnet(magnet).await
+    } else {
+        let torrent_file = TorrentMetaInfo::from_file(Path::new(&source))?;
+        client.download_file(torrent_file).await
+    }
+}
+
+async fn scrape(path: &Path) -> anyhow::Result<()> {
+    let torrent = torrent::Torrent::try_from(TorrentMetaInfo::from_file(path)?)?;
+    println!("{}", torrent.name);
+
+    for tracker in torrent.announce_list.iter().flatten() {
+        match tracker::scrape(tracker, &[torrent.info_hash]).await {
+            Ok(stats) => match stats.get(&torrent.info_hash) {
+                Some(stats) => println!(
+                    "  {}: {} seeders, {} leechers, {} downloads",
+                    tracker, stats.complete, stats.incomplete, stats.downloaded
+                ),
+                None => println!("  {}: torrent not found", tracker),
+            },
+            Err(error) => println!("  {}: {}", tracker, error),
+        }
+    }
+
+    Result::Ok(())
 }
@@ -0,0 +1,294 @@
+use rand::seq::SliceRandom;
+
+use crate::worker::Bitfield;
+
+// Pick the first few pieces at random rather than rarest first, so that we quickly have something
+// to offer other peers even if the rarest pieces are slow to download
+const RANDOM_FIRST_PIECES: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PieceStatus {
+    Missing,
+    /// Being downloaded by this many peers, which is more than one only in endgame mode
+    InProgress(u32),
+    Have,
+}
+
+/// Chooses which piece each peer should download next, preferring the pieces that the fewest
+/// peers in the swarm have (rarest first).
+///
+/// Once every missing piece is being downloaded the picker switches to endgame mode, where pieces
+/// already in progress are handed out again so a single slow peer can't hold up the end of the
+/// download.
+#[derive(Debug)]
+pub struct PiecePicker {
+    availability: Vec<u32>,
+    status: Vec<PieceStatus>,
+    completed: usize,
+}
+
+impl PiecePicker {
+    pub fn new(have: &Bitfield, num_pieces: usize) -> Self {
+        let status: Vec<PieceStatus> = (0..num_pieces as u32)
+            .map(|index| {
+                if have.has(index) {
+                    PieceStatus::Have
+                } else {
+                    PieceStatus::Missing
+                }
+            })
+            .collect();
+        let completed = status.iter().filter(|s| **s == PieceStatus::Have).count();
+
+        Self {
+            availability: vec![0; num_pieces],
+            status,
+            completed,
+        }
+    }
+
+    /// Records the pieces of a newly connected peer
+    pub fn add_bitfield(&mut self, bitfield: &Bitfield) {
+        for index in 0..self.availability.len() as u32 {
+            if bitfield.has(index) {
+                self.availability[index as usize] += 1;
+            }
+        }
+    }
+
+    /// Forgets the pieces of a peer that disconnected
+    pub fn remove_bitfield(&mut self, bitfield: &Bitfield) {
+        for index in 0..self.availability.len() as u32 {
+            if bitfield.has(index) {
+                let count = &mut self.availability[index as usize];
+                *count = count.saturating_sub(1);
+            }
+        }
+    }
+
+    pub fn add_have(&mut self, index: u32) {
+        if let Some(count) = self.availability.get_mut(index as usize) {
+            *count += 1;
+        }
+    }
+
+    /// Picks the next piece for a peer with the given bitfield to download, marking it as in
+    /// progress. Returns None if the peer has nothing we still need that isn't being downloaded.
+    pub fn pick(&mut self, bitfield: &Bitfield, downloading: &[u32]) -> Option<u32> {
+        if self.is_endgame() {
+            return self.pick_endgame(bitfield, downloading);
+        }
+
+        let candidates: Vec<u32> = (0..self.status.len() as u32)
+            .filter(|index| {
+                self.status[*index as usize] == PieceStatus::Missing && bitfield.has(*index)
+            })
+            .collect();
+
+        let mut rng = rand::thread_rng();
+        let index = if self.completed < RANDOM_FIRST_PIECES {
+            *candidates.choose(&mut rng)?
+        } else {
+            // Break ties between equally rare pieces randomly so peers don't all pick the same one
+            let rarest = candidates
+                .iter()
+                .map(|index| self.availability[*index as usize])
+                .min()?;
+            let rarest_candidates: Vec<u32> = candidates
+                .into_iter()
+                .filter(|index| self.availability[*index as usize] == rarest)
+                .collect();
+            *rarest_candidates.choose(&mut rng)?
+        };
+
+        self.status[index as usize] = PieceStatus::InProgress(1);
+        Some(index)
+    }
+
+    /// Returns true once there are no pieces left that nobody is downloading
+    pub fn is_endgame(&self) -> bool {
+        !self.status.contains(&PieceStatus::Missing)
+    }
+
+    /// Returns true if more than one peer is downloading a piece
+    pub fn is_shared(&self, index: u32) -> bool {
+        matches!(
+            self.status.get(index as usize),
+            Some(PieceStatus::InProgress(downloaders)) if *downloaders > 1
+        )
+    }
+
+    /// Picks a piece already being downloaded by other peers, preferring the one with the fewest
+    /// downloaders
+    fn pick_endgame(&mut self, bitfield: &Bitfield, downloading: &[u32]) -> Option<u32> {
+        let (index, _) = self
+            .status
+            .iter()
+            .enumerate()
+            .filter_map(|(index, status)| match status {
+                PieceStatus::InProgress(downloaders)
+                    if bitfield.has(index as u32) && !downloading.contains(&(index as u32)) =>
+                {
+                    Some((index, *downloaders))
+                }
+                _ => None,
+            })
+            .min_by_key(|(_, downloaders)| *downloaders)?;
+
+        if let PieceStatus::InProgress(downloaders) = &mut self.status[index] {
+            *downloaders += 1;
+        }
+        Some(index as u32)
+    }
+
+    /// Returns a piece that failed to download so it can be picked again, unless other peers are
+    /// still downloading it
+    pub fn abort(&mut self, index: u32) {
+        if let Some(status) = self.status.get_mut(index as usize) {
+            match status {
+                PieceStatus::InProgress(1) => *status = PieceStatus::Missing,
+                PieceStatus::InProgress(downloaders) => *downloaders -= 1,
+                _ => {}
+            }
+        }
+    }
+
+    pub fn complete(&mut self, index: u32) {
+        if let Some(status) = self.status.get_mut(index as usize) {
+            if *status != PieceStatus::Have {
+                *status = PieceStatus::Have;
+                self.completed += 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A picker past the random first pieces, with the given availability for each missing piece
+    fn rarest_first_picker(availability: &[u32]) -> PiecePicker {
+        let num_pieces = RANDOM_FIRST_PIECES + availability.len();
+        let mut have = Bitfield::new(num_pieces);
+        for index in 0..RANDOM_FIRST_PIECES as u32 {
+            have.set(index);
+        }
+
+        let mut picker = PiecePicker::new(&have, num_pieces);
+        for (i, count) in availability.iter().enumerate() {
+            for _ in 0..*count {
+                picker.add_have((RANDOM_FIRST_PIECES + i) as u32);
+            }
+        }
+        picker
+    }
+
+    #[test]
+    fn picks_rarest_pieces_first() {
+        let mut picker = rarest_first_picker(&[3, 2, 1, 4]);
+        let all = Bitfield::full(RANDOM_FIRST_PIECES + 4);
+        let first = RANDOM_FIRST_PIECES as u32;
+
+        let picks: Vec<u32> = (0..4)
+            .map(|_| picker.pick(&all, &[]).unwrap() - first)
+            .collect();
+        assert_eq!(picks, vec![2, 1, 0, 3]);
+    }
+
+    #[test]
+    fn only_picks_pieces_the_peer_has() {
+        let mut picker = rarest_first_picker(&[3, 1]);
+        let mut bitfield = Bitfield::new(RANDOM_FIRST_PIECES + 2);
+        bitfield.set(RANDOM_FIRST_PIECES as u32);
+
+        assert_eq!(
+            picker.pick(&bitfield, &[]),
+            Some(RANDOM_FIRST_PIECES as u32)
+        );
+        assert_eq!(picker.pick(&bitfield, &[]), None);
+    }
+
+    #[test]
+    fn picks_random_pieces_until_enough_are_complete() {
+        let num_pieces = 16;
+        let all = Bitfield::full(num_pieces);
+        let new_picker = || {
+            // Every piece but the last is common
+            let mut picker = PiecePicker::new(&Bitfield::new(num_pieces), num_pieces);
+            picker.add_bitfield(&all);
+            for index in 0..num_pieces as u32 - 1 {
+                picker.add_have(index);
+            }
+            picker
+        };
+
+        // Picking the rarest piece every time by chance is practically impossible
+        let rarest = num_pieces as u32 - 1;
+        assert!((0..100).any(|_| new_picker().pick(&all, &[]) != Some(rarest)));
+
+        let mut picker = new_picker();
+        for index in 0..RANDOM_FIRST_PIECES as u32 {
+            picker.complete(index);
+        }
+        assert_eq!(picker.pick(&all, &[]), Some(rarest));
+    }
+
+    #[test]
+    fn hands_out_pieces_again_in_endgame() {
+        let all = Bitfield::full(2);
+        let mut picker = PiecePicker::new(&Bitfield::new(2), 2);
+        let first = picker.pick(&all, &[]).unwrap();
+        assert!(!picker.is_endgame());
+        let second = picker.pick(&all, &[first]).unwrap();
+        assert!(picker.is_endgame());
+        assert!(!picker.is_shared(first) && !picker.is_shared(second));
+
+        // Another peer is given one of the pieces already being downloaded
+        let shared = picker.pick(&all, &[]).unwrap();
+        assert!(picker.is_shared(shared));
+
+        // Pieces are shared evenly, and never twice with the same peer
+        let other = picker.pick(&all, &[shared]).unwrap();
+        assert_ne!(other, shared);
+        assert!(picker.is_shared(other));
+        assert_eq!(picker.pick(&all, &[first, second]), None);
+    }
+
+    #[test]
+    fn abort_returns_pieces_to_the_pool() {
+        let all = Bitfield::full(1);
+        let mut picker = PiecePicker::new(&Bitfield::new(1), 1);
+        assert_eq!(picker.pick(&all, &[]), Some(0));
+        assert!(picker.is_endgame());
+
+        // A shared piece stays in progress until its last downloader gives up
+        assert_eq!(picker.pick(&all, &[]), Some(0));
+        picker.abort(0);
+        assert!(picker.is_endgame());
+        assert!(!picker.is_shared(0));
+
+        picker.abort(0);
+        assert!(!picker.is_endgame());
+        assert_eq!(picker.pick(&all, &[]), Some(0));
+    }
+
+    #[test]
+    fn complete_removes_pieces_for_good() {
+        let all = Bitfield::full(2);
+        let mut picker = PiecePicker::new(&Bitfield::new(2), 2);
+        let index = picker.pick(&all, &[]).unwrap();
+        picker.complete(index);
+
+        // Late aborts from peers still downloading the piece don't bring it back
+        picker.abort(index);
+        let other = picker.pick(&all, &[]).unwrap();
+        assert_ne!(other, index);
+        assert!(picker.is_endgame());
+        assert_eq!(picker.pick(&all, &[]), Some(other));
+        assert_eq!(picker.pick(&all, &[other]), None);
+
+        picker.complete(other);
+        assert_eq!(picker.pick(&all, &[]), None);
+    }
+}
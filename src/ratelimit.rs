@@ -0,0 +1,197 @@
+use std::sync::Mutex;
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+// The longest a limiter sleeps before checking the bucket again, so that a rate changed at runtime
+// takes effect promptly
+const MAX_WAIT: Duration = Duration::from_millis(100);
+
+/// Upload and download rates in bytes per second, None meaning unlimited
+#[derive(Debug, Default, Clone, Copy)]
+pub struct RateConfig {
+    pub upload: Option<u64>,
+    pub download: Option<u64>,
+}
+
+impl RateConfig {
+    /// Takes the rates in KiB/s as given on the command line
+    pub fn from_kib(upload: Option<u64>, download: Option<u64>) -> Self {
+        Self {
+            upload: upload.and_then(from_kib),
+            download: download.and_then(from_kib),
+        }
+    }
+}
+
+/// Converts a rate in KiB/s to bytes per second, with 0 meaning unlimited
+pub fn from_kib(rate: u64) -> Option<u64> {
+    (rate > 0).then_some(rate * 1024)
+}
+
+/// A token bucket limiting some traffic to a number of bytes per second. The bucket holds up to a
+/// second's worth of bytes. Traffic is let through whenever the bucket isn't empty, going into
+/// debt that later traffic has to wait out, so messages bigger than the bucket still get through.
+pub struct RateLimiter {
+    bucket: Mutex<Bucket>,
+}
+
+struct Bucket {
+    /// The rate in bytes per second, or None if unlimited
+    rate: Option<u64>,
+    /// The bytes that can go through right away, negative while in debt
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self, rate: u64) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.refilled).as_secs_f64();
+        self.tokens = f64::min(self.tokens + elapsed * rate as f64, rate as f64);
+        self.refilled = now;
+    }
+}
+
+impl RateLimiter {
+    pub fn new(rate: Option<u64>) -> Self {
+        Self {
+            bucket: Mutex::new(Bucket {
+                rate,
+                tokens: 0.0,
+                refilled: Instant::now(),
+            }),
+        }
+    }
+
+    /// Changes the rate, which applies to traffic already waiting too
+    pub fn set_rate(&self, rate: Option<u64>) {
+        let mut bucket = self.bucket.lock().unwrap();
+        if let Some(rate) = bucket.rate {
+            bucket.refill(rate);
+        }
+        bucket.rate = rate;
+        // Don't let a burst built up at a higher rate through at the new one
+        if let Some(rate) = rate {
+            bucket.tokens = f64::min(bucket.tokens, rate as f64);
+        }
+    }
+
+    /// Waits until `bytes` more are allowed through
+    pub async fn acquire(&self, bytes: usize) {
+        loop {
+            let wait = {
+                let mut bucket = self.bucket.lock().unwrap();
+                let Some(rate) = bucket.rate else {
+                    return;
+                };
+                bucket.refill(rate);
+                if bucket.tokens >= 0.0 {
+                    bucket.tokens -= bytes as f64;
+                    return;
+                }
+                Duration::from_secs_f64(-bucket.tokens / rate as f64)
+            };
+            tokio::time::sleep(wait.min(MAX_WAIT)).await;
+        }
+    }
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        Self::new(None)
+    }
+}
+
+/// Limits on some traffic in each direction
+#[derive(Default)]
+pub struct RateLimits {
+    pub upload: RateLimiter,
+    pub download: RateLimiter,
+}
+
+impl RateLimits {
+    pub fn new(config: RateConfig) -> Self {
+        Self {
+            upload: RateLimiter::new(config.upload),
+            download: RateLimiter::new(config.download),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn lets_unlimited_traffic_through() {
+        let limiter = RateLimiter::default();
+        let started = Instant::now();
+        limiter.acquire(usize::MAX).await;
+        limiter.acquire(1024).await;
+        assert_eq!(started.elapsed(), Duration::ZERO);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn limits_to_the_rate() {
+        let limiter = RateLimiter::new(Some(1000));
+        let started = Instant::now();
+        // The first acquire goes through straight away, each of the others waits out the debt
+        for _ in 0..10 {
+            limiter.acquire(1000).await;
+        }
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_secs(9), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_secs(9) + MAX_WAIT, "{:?}", elapsed);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn caps_bursts_at_a_second_of_traffic() {
+        let limiter = RateLimiter::new(Some(1000));
+        tokio::time::sleep(Duration::from_secs(60)).await;
+
+        let started = Instant::now();
+        for _ in 0..3 {
+            limiter.acquire(1000).await;
+        }
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_secs(1), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_secs(1) + MAX_WAIT, "{:?}", elapsed);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn applies_rate_changes_to_waiting_traffic() {
+        let limiter = Arc::new(RateLimiter::new(Some(100)));
+        limiter.acquire(1000).await;
+
+        let started = Instant::now();
+        let waiting = tokio::spawn({
+            let limiter = limiter.clone();
+            async move { limiter.acquire(1000).await }
+        });
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        limiter.set_rate(None);
+        waiting.await.unwrap();
+        assert!(started.elapsed() <= Duration::from_secs(1) + MAX_WAIT);
+
+        // A lower rate doesn't keep a burst built up at a higher one
+        limiter.set_rate(Some(1_000_000));
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        limiter.set_rate(Some(100));
+        let started = Instant::now();
+        limiter.acquire(100).await;
+        limiter.acquire(100).await;
+        limiter.acquire(100).await;
+        assert!(started.elapsed() >= Duration::from_secs(1));
+    }
+
+    #[test]
+    fn converts_kib() {
+        assert_eq!(from_kib(0), None);
+        assert_eq!(from_kib(2), Some(2048));
+        let config = RateConfig::from_kib(Some(1), None);
+        assert_eq!((config.upload, config.download), (Some(1024), None));
+    }
+}
@@ -0,0 +1,242 @@
+use std::path::{Path, PathBuf};
+
+use serde_bytes::ByteBuf;
+use serde_derive::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+
+use crate::types::InfoHash;
+
+/// Fast resume state for a torrent, saved periodically so that restarting a download doesn't
+/// require hashing every piece on disk again
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct ResumeData {
+    pub info_hash: ByteBuf,
+    /// The pieces we have completed and verified
+    pub bitfield: ByteBuf,
+    /// The blocks written to disk for pieces that are not complete yet
+    pub partial: Vec<ResumePartialPiece>,
+    /// The size and modification time of each file when the state was saved
+    pub files: Vec<ResumeFile>,
+    pub uploaded: u64,
+    pub downloaded: u64,
+    /// IPv4 peers we knew about, in compact form
+    pub peers: ByteBuf,
+    /// IPv6 peers we knew about, in compact form
+    #[serde(default)]
+    pub peers6: ByteBuf,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ResumePartialPiece {
+    pub index: u32,
+    pub blocks: ByteBuf,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ResumeFile {
+    pub length: u64,
+    pub mtime: u64,
+}
+
+impl ResumeData {
+    /// Returns the path of the resume file for a torrent within `dir`
+    pub fn path(dir: &Path, info_hash: &InfoHash) -> PathBuf {
+        let name: String = info_hash.iter().map(|b| format!("{:02x}", b)).collect();
+        dir.join(format!("{}.resume", name))
+    }
+
+    pub fn load(path: &Path) -> anyhow::Result<Option<Self>> {
+        match std::fs::read(path) {
+            Ok(bytes) => Result::Ok(Some(serde_bencode::from_bytes::<Self>(&bytes)?)),
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => Result::Ok(None),
+            Err(error) => Result::Err(error.into()),
+        }
+    }
+
+    /// Saves the resume data by writing to a temporary file and renaming it into place, so a
+    /// crash part way through never leaves a corrupt resume file behind
+    pub async fn save(&self, path: &Path) -> anyhow::Result<()> {
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+
+        // The data has to reach the disk before the rename does, or a crash could still leave an
+        // empty file in place of the old one
+        let tmp_path = path.with_extension("resume.tmp");
+        let mut file = tokio::fs::File::create(&tmp_path).await?;
+        file.write_all(&serde_bencode::to_bytes(self)?).await?;
+        file.sync_all().await?;
+        tokio::fs::rename(&tmp_path, path).await?;
+
+        Result::Ok(())
+    }
+
+    /// Returns true if the saved state can be trusted for the files currently on disk
+    pub fn matches(&self, info_hash: &InfoHash, num_pieces: usize, files: &[ResumeFile]) -> bool {
+        self.info_hash.as_ref() == info_hash
+            && self.bitfield.len() == num_pieces.div_ceil(8)
+            && self.files == files
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::{Duration, SystemTime};
+
+    use sha1::{Digest, Sha1};
+
+    use super::*;
+    use crate::ratelimit::RateLimits;
+    use crate::state::TorrentState;
+    use crate::torrent::{Torrent, TorrentFile};
+    use crate::tracker::Peer;
+    use crate::worker::{Bitfield, EncryptionPolicy, TransportConfig, MAX_BLOCK_SIZE};
+    use crate::writer::TorrentWriter;
+
+    const PIECE_LENGTH: u64 = 2 * MAX_BLOCK_SIZE as u64;
+
+    struct TestTorrent {
+        data: Vec<u8>,
+        path: PathBuf,
+        resume_path: PathBuf,
+    }
+
+    impl TestTorrent {
+        async fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("rustor-resume-test-{}", name));
+            let _ = tokio::fs::remove_dir_all(&dir).await;
+            tokio::fs::create_dir_all(&dir).await.unwrap();
+            Self {
+                data: (0..2 * PIECE_LENGTH).map(|i| (i % 251) as u8).collect(),
+                path: dir.join("data"),
+                resume_path: dir.join("torrent.resume"),
+            }
+        }
+
+        fn torrent(&self) -> Torrent {
+            Torrent {
+                name: "resume".to_owned(),
+                announce_list: Vec::new(),
+                length: self.data.len() as u64,
+                info_hash: [3; 20],
+                piece_length: PIECE_LENGTH,
+                piece_hashes: self
+                    .data
+                    .chunks(PIECE_LENGTH as usize)
+                    .map(|piece| Sha1::digest(piece).into())
+                    .collect(),
+                files: vec![TorrentFile {
+                    length: self.data.len() as u64,
+                    path: self.path.clone(),
+                }],
+            }
+        }
+
+        /// Opens the torrent's file without checking what is already in it
+        async fn state(&self) -> TorrentState {
+            let torrent = self.torrent();
+            let writer = TorrentWriter::from_torrent(&torrent).await.unwrap();
+            let transport = TransportConfig {
+                encryption: EncryptionPolicy::Plaintext,
+                utp: None,
+                limits: Arc::new(RateLimits::default()),
+            };
+            let have = Bitfield::new(torrent.piece_hashes.len());
+            TorrentState::new(torrent, [4; 20], 6881, None, transport, writer, have)
+        }
+
+        /// Saves the state of a download with the first piece and one block of the second done
+        async fn save(&self) -> TorrentState {
+            let state = self.state().await;
+            for begin in [0, MAX_BLOCK_SIZE] {
+                let block = &self.data[begin as usize..(begin + MAX_BLOCK_SIZE) as usize];
+                state.write_block(0, begin, block).await.unwrap();
+            }
+            state.complete_piece(0).await.unwrap();
+            let block = &self.data[PIECE_LENGTH as usize..][..MAX_BLOCK_SIZE as usize];
+            state.write_block(1, 0, block).await.unwrap();
+            state.add_peers([Peer::new("10.0.0.1:6881".parse().unwrap())]);
+            state.add_uploaded(1234);
+
+            state.save(&self.resume_path).await.unwrap();
+            state
+        }
+
+        async fn matches(&self, state: &TorrentState) -> bool {
+            let files = state.writer.lock().await.file_stats().await.unwrap();
+            let resume = ResumeData::load(&self.resume_path).unwrap().unwrap();
+            resume.matches(&[3; 20], 2, &files)
+        }
+    }
+
+    #[tokio::test]
+    async fn restores_saved_state() {
+        let test = TestTorrent::new("round-trip").await;
+        test.save().await;
+
+        let state = test.state().await;
+        assert!(test.matches(&state).await);
+        let resume = ResumeData::load(&test.resume_path).unwrap().unwrap();
+        state.restore(&resume).await.unwrap();
+
+        assert!(state.has(0) && !state.has(1));
+        assert!(state.partial_blocks(0).is_none());
+        let blocks = state.partial_blocks(1).unwrap();
+        assert!(blocks.has(0) && !blocks.has(1));
+        assert_eq!(
+            state.peers(),
+            vec![Peer::new("10.0.0.1:6881".parse().unwrap())]
+        );
+        assert_eq!(state.uploaded(), 1234);
+        assert_eq!(state.downloaded(), 3 * MAX_BLOCK_SIZE as u64);
+    }
+
+    #[tokio::test]
+    async fn rejects_files_with_a_different_size() {
+        let test = TestTorrent::new("size").await;
+        let state = test.save().await;
+        assert!(test.matches(&state).await);
+
+        let file = std::fs::OpenOptions::new()
+            .write(true)
+            .open(&test.path)
+            .unwrap();
+        file.set_len(test.data.len() as u64 + 1).unwrap();
+        assert!(!test.matches(&state).await);
+    }
+
+    #[tokio::test]
+    async fn rejects_files_modified_since_saving() {
+        let test = TestTorrent::new("mtime").await;
+        let state = test.save().await;
+        assert!(test.matches(&state).await);
+
+        let file = std::fs::OpenOptions::new()
+            .write(true)
+            .open(&test.path)
+            .unwrap();
+        file.set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(1))
+            .unwrap();
+        assert!(!test.matches(&state).await);
+    }
+
+    #[test]
+    fn rejects_resume_data_for_other_torrents() {
+        let files = vec![ResumeFile {
+            length: 10,
+            mtime: 20,
+        }];
+        let resume = ResumeData {
+            info_hash: ByteBuf::from(vec![3; 20]),
+            bitfield: ByteBuf::from(vec![0; 2]),
+            files: files.clone(),
+            ..Default::default()
+        };
+
+        assert!(resume.matches(&[3; 20], 9, &files));
+        assert!(!resume.matches(&[5; 20], 9, &files));
+        assert!(!resume.matches(&[3; 20], 17, &files));
+        assert!(!resume.matches(&[3; 20], 9, &[]));
+    }
+}
@@ -0,0 +1,407 @@
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use serde_bytes::ByteBuf;
+use sha1::{Digest, Sha1};
+use tokio::sync::futures::Notified;
+use tokio::sync::{broadcast, Notify};
+use tracing::warn;
+
+use crate::choker::Choker;
+use crate::dht::Dht;
+use crate::picker::PiecePicker;
+use crate::ratelimit::RateLimits;
+use crate::resume::{ResumeData, ResumePartialPiece};
+use crate::torrent::Torrent;
+use crate::tracker::{Peer, COMPACT_LEN_V4, COMPACT_LEN_V6};
+use crate::types::{PeerID, PieceHash};
+use crate::worker::{Bitfield, PexPeer, PieceInfo, TransportConfig, MAX_BLOCK_SIZE};
+use crate::writer::TorrentWriter;
+
+// How many received blocks can be queued up for each worker in endgame mode before they are dropped
+const BLOCK_CHANNEL_CAPACITY: usize = 256;
+
+/// State for a single torrent shared between the client and every peer connection
+pub struct TorrentState {
+    pub torrent: Torrent,
+    pub peer_id: PeerID,
+    pub port: u16,
+    /// Our DHT node, if the DHT is enabled
+    pub dht: Option<Arc<Dht>>,
+    /// How connections to peers are made
+    pub transport: TransportConfig,
+    /// Decides which peers we upload to
+    pub choker: Choker,
+    /// Limits on the blocks exchanged with this torrent's peers, within the global limits
+    pub limits: RateLimits,
+    pub writer: tokio::sync::Mutex<TorrentWriter>,
+    have: Mutex<Bitfield>,
+    haves: broadcast::Sender<u32>,
+    blocks: broadcast::Sender<(u32, u32)>,
+    picker: Mutex<PiecePicker>,
+    pieces_available: Notify,
+    partial: Mutex<HashMap<u32, Bitfield>>,
+    peers: Mutex<HashSet<Peer>>,
+    /// The listen addresses of the peers we are connected to, with their peer exchange flags
+    connected: Mutex<HashMap<SocketAddr, u8>>,
+    uploaded: AtomicU64,
+    downloaded: AtomicU64,
+}
+
+impl TorrentState {
+    pub fn new(
+        torrent: Torrent,
+        peer_id: PeerID,
+        port: u16,
+        dht: Option<Arc<Dht>>,
+        transport: TransportConfig,
+        writer: TorrentWriter,
+        have: Bitfield,
+    ) -> Self {
+        let num_pieces = torrent.piece_hashes.len();
+        let (haves, _) = broadcast::channel(usize::max(num_pieces, 1));
+        let (blocks, _) = broadcast::channel(BLOCK_CHANNEL_CAPACITY);
+        Self {
+            torrent,
+            peer_id,
+            port,
+            dht,
+            transport,
+            choker: Choker::default(),
+            limits: RateLimits::default(),
+            writer: tokio::sync::Mutex::new(writer),
+            picker: Mutex::new(PiecePicker::new(&have, num_pieces)),
+            pieces_available: Notify::new(),
+            have: Mutex::new(have),
+            haves,
+            blocks,
+            partial: Mutex::new(HashMap::new()),
+            peers: Mutex::new(HashSet::new()),
+            connected: Mutex::new(HashMap::new()),
+            uploaded: AtomicU64::new(0),
+            downloaded: AtomicU64::new(0),
+        }
+    }
+
+    /// Restores the state saved in a resume file, which must already have been checked against
+    /// the files on disk
+    pub async fn restore(&self, resume: &ResumeData) -> anyhow::Result<()> {
+        let have = Bitfield::from_bytes(resume.bitfield.to_vec());
+        {
+            let mut writer = self.writer.lock().await;
+            for index in 0..self.num_pieces() as u32 {
+                if have.has(index) {
+                    let (offset, length) = self.piece_bounds(index);
+                    writer.mark_complete(offset, length as usize)?;
+                }
+            }
+        }
+        *self.picker.lock().unwrap() = PiecePicker::new(&have, self.num_pieces());
+        *self.have.lock().unwrap() = have;
+
+        *self.partial.lock().unwrap() = resume
+            .partial
+            .iter()
+            .map(|p| (p.index, Bitfield::from_bytes(p.blocks.to_vec())))
+            .collect();
+
+        self.add_peers(
+            resume
+                .peers
+                .chunks_exact(COMPACT_LEN_V4)
+                .chain(resume.peers6.chunks_exact(COMPACT_LEN_V6))
+                .map(Peer::from_compact),
+        );
+        self.uploaded.store(resume.uploaded, Ordering::Relaxed);
+        self.downloaded.store(resume.downloaded, Ordering::Relaxed);
+
+        Result::Ok(())
+    }
+
+    /// Saves the state needed to resume this torrent without verifying every piece
+    pub async fn save(&self, path: &Path) -> anyhow::Result<()> {
+        let files = self.writer.lock().await.file_stats().await?;
+        let (peers, peers6): (Vec<Peer>, Vec<Peer>) = self
+            .peers()
+            .into_iter()
+            .partition(|peer| peer.addr.is_ipv4());
+
+        let resume = ResumeData {
+            info_hash: ByteBuf::from(self.torrent.info_hash.to_vec()),
+            bitfield: ByteBuf::from(self.bitfield().as_bytes().to_vec()),
+            partial: self
+                .partial
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(index, blocks)| ResumePartialPiece {
+                    index: *index,
+                    blocks: ByteBuf::from(blocks.as_bytes().to_vec()),
+                })
+                .collect(),
+            files,
+            uploaded: self.uploaded(),
+            downloaded: self.downloaded(),
+            peers: ByteBuf::from(
+                peers
+                    .iter()
+                    .flat_map(|peer| peer.to_compact())
+                    .collect::<Vec<u8>>(),
+            ),
+            peers6: ByteBuf::from(
+                peers6
+                    .iter()
+                    .flat_map(|peer| peer.to_compact())
+                    .collect::<Vec<u8>>(),
+            ),
+        };
+
+        resume.save(path).await
+    }
+
+    pub fn num_pieces(&self) -> usize {
+        self.torrent.piece_hashes.len()
+    }
+
+    /// Returns a snapshot of the pieces we have
+    pub fn bitfield(&self) -> Bitfield {
+        self.have.lock().unwrap().clone()
+    }
+
+    pub fn has(&self, index: u32) -> bool {
+        self.have.lock().unwrap().has(index)
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.have.lock().unwrap().is_complete(self.num_pieces())
+    }
+
+    /// Writes a block of a piece we are downloading straight to disk, so that it survives a
+    /// restart before the rest of the piece arrives
+    pub async fn write_block(&self, index: u32, begin: u32, block: &[u8]) -> anyhow::Result<()> {
+        let (offset, length) = self.piece_bounds(index);
+        {
+            let mut writer = self.writer.lock().await;
+            // A block arriving late in endgame mode must not overwrite a piece that was already
+            // verified, which we may be serving to peers
+            if self.has(index) {
+                return Result::Ok(());
+            }
+            writer.write(offset + begin as u64, block).await?;
+        }
+
+        self.downloaded
+            .fetch_add(block.len() as u64, Ordering::Relaxed);
+
+        // Only whole blocks can be resumed
+        if begin.is_multiple_of(MAX_BLOCK_SIZE)
+            && block.len() as u64 == u64::min(MAX_BLOCK_SIZE as u64, length - begin as u64)
+        {
+            let num_blocks = length.div_ceil(MAX_BLOCK_SIZE as u64) as usize;
+            self.partial
+                .lock()
+                .unwrap()
+                .entry(index)
+                .or_insert_with(|| Bitfield::new(num_blocks))
+                .set(begin / MAX_BLOCK_SIZE);
+
+            // Let the other peers downloading this piece in endgame mode cancel their requests
+            if self.picker.lock().unwrap().is_shared(index) {
+                let _ = self.blocks.send((index, begin));
+            }
+        }
+
+        Result::Ok(())
+    }
+
+    /// Returns the blocks of a piece that were already written to disk
+    pub fn partial_blocks(&self, index: u32) -> Option<Bitfield> {
+        self.partial.lock().unwrap().get(&index).cloned()
+    }
+
+    /// Forgets the blocks written for a piece, e.g. after it failed its integrity check
+    pub fn clear_partial(&self, index: u32) {
+        self.partial.lock().unwrap().remove(&index);
+    }
+
+    /// Records that we have verified a piece written to disk, and announces it to every peer. The
+    /// piece is checked again as it is on disk, since in endgame mode other peers may have
+    /// written their own copies of some of its blocks after the worker verified the blocks it
+    /// received. A piece that no longer matches its hash is downloaded again.
+    pub async fn complete_piece(&self, index: u32) -> anyhow::Result<()> {
+        let (offset, length) = self.piece_bounds(index);
+        let mut writer = self.writer.lock().await;
+
+        // In endgame mode several peers can finish the same piece, so check and claim it in one go
+        // for it to only be counted and announced once
+        {
+            let mut have = self.have.lock().unwrap();
+            if have.has(index) {
+                return Result::Ok(());
+            }
+            have.set(index);
+        }
+
+        // Holding the writer lock keeps blocks from being written to the piece while we check it
+        let data = writer.read(offset, length as usize).await?;
+        let mut sha1 = Sha1::new();
+        sha1.update(&data);
+        let hash: PieceHash = sha1.finalize().into();
+        if hash != self.torrent.piece_hashes[index as usize] {
+            warn!(
+                "Piece {} was overwritten on disk, downloading it again",
+                index
+            );
+            self.have.lock().unwrap().clear(index);
+            self.partial.lock().unwrap().remove(&index);
+            drop(writer);
+            self.abort_piece(index);
+            return Result::Ok(());
+        }
+
+        writer.mark_complete(offset, length as usize)?;
+        self.partial.lock().unwrap().remove(&index);
+        self.picker.lock().unwrap().complete(index);
+        // Sending only fails if no peers are connected
+        let _ = self.haves.send(index);
+
+        Result::Ok(())
+    }
+
+    /// Picks the next piece to download from a peer with the given bitfield, other than the ones
+    /// already being downloaded from it
+    pub fn pick_piece(&self, bitfield: &Bitfield, downloading: &[u32]) -> Option<u32> {
+        let mut picker = self.picker.lock().unwrap();
+        let was_endgame = picker.is_endgame();
+        let index = picker.pick(bitfield, downloading);
+
+        // Workers with nothing left to download can now help with the pieces in progress
+        if !was_endgame && picker.is_endgame() {
+            self.pieces_available.notify_waiters();
+        }
+
+        index
+    }
+
+    /// Returns a piece that failed to download to the picker, waking any workers waiting for
+    /// something to download
+    pub fn abort_piece(&self, index: u32) {
+        self.picker.lock().unwrap().abort(index);
+        self.pieces_available.notify_waiters();
+    }
+
+    /// Completes once a piece is aborted or endgame mode starts. The future only sees
+    /// notifications sent after it is first polled or enabled.
+    pub fn pieces_available(&self) -> Notified<'_> {
+        self.pieces_available.notified()
+    }
+
+    pub fn add_peer_bitfield(&self, bitfield: &Bitfield) {
+        self.picker.lock().unwrap().add_bitfield(bitfield);
+    }
+
+    pub fn remove_peer_bitfield(&self, bitfield: &Bitfield) {
+        self.picker.lock().unwrap().remove_bitfield(bitfield);
+    }
+
+    pub fn add_peer_have(&self, index: u32) {
+        self.picker.lock().unwrap().add_have(index);
+    }
+
+    pub fn subscribe_haves(&self) -> broadcast::Receiver<u32> {
+        self.haves.subscribe()
+    }
+
+    /// Subscribes to the blocks received for pieces that several peers are downloading
+    pub fn subscribe_blocks(&self) -> broadcast::Receiver<(u32, u32)> {
+        self.blocks.subscribe()
+    }
+
+    pub fn add_peers(&self, peers: impl IntoIterator<Item = Peer>) {
+        self.peers.lock().unwrap().extend(peers);
+    }
+
+    pub fn peers(&self) -> Vec<Peer> {
+        self.peers.lock().unwrap().iter().copied().collect()
+    }
+
+    /// Records a connection to a peer listening on `addr`, to tell other peers about
+    pub fn add_connected(&self, addr: SocketAddr, flags: u8) {
+        self.connected.lock().unwrap().insert(addr, flags);
+    }
+
+    /// Adds to the flags of a peer we are connected to
+    pub fn add_connected_flags(&self, addr: SocketAddr, flags: u8) {
+        if let Some(existing) = self.connected.lock().unwrap().get_mut(&addr) {
+            *existing |= flags;
+        }
+    }
+
+    pub fn remove_connected(&self, addr: SocketAddr) {
+        self.connected.lock().unwrap().remove(&addr);
+    }
+
+    pub fn connected_peers(&self) -> Vec<PexPeer> {
+        self.connected
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(addr, flags)| PexPeer {
+                addr: *addr,
+                flags: *flags,
+            })
+            .collect()
+    }
+
+    /// Waits until both the global and the torrent's upload limits let a block through
+    pub async fn throttle_upload(&self, bytes: usize) {
+        self.transport.limits.upload.acquire(bytes).await;
+        self.limits.upload.acquire(bytes).await;
+    }
+
+    /// Waits until both the global and the torrent's download limits let a block through
+    pub async fn throttle_download(&self, bytes: usize) {
+        self.transport.limits.download.acquire(bytes).await;
+        self.limits.download.acquire(bytes).await;
+    }
+
+    pub fn add_uploaded(&self, bytes: u64) {
+        self.uploaded.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn uploaded(&self) -> u64 {
+        self.uploaded.load(Ordering::Relaxed)
+    }
+
+    pub fn downloaded(&self) -> u64 {
+        self.downloaded.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of bytes of the torrent we have yet to download
+    pub fn left(&self) -> u64 {
+        let have = self.have.lock().unwrap();
+        (0..self.num_pieces() as u32)
+            .filter(|index| !have.has(*index))
+            .map(|index| self.piece_bounds(index).1)
+            .sum()
+    }
+
+    pub fn piece_info(&self, index: u32) -> PieceInfo {
+        let (_, length) = self.piece_bounds(index);
+        PieceInfo::new(
+            index,
+            self.torrent.piece_hashes[index as usize],
+            length as u32,
+        )
+    }
+
+    /// Returns the offset and length of a piece within the torrent
+    pub fn piece_bounds(&self, index: u32) -> (u64, u64) {
+        let begin = index as u64 * self.torrent.piece_length;
+        let end = u64::min(begin + self.torrent.piece_length, self.torrent.length);
+        (begin, end - begin)
+    }
+}
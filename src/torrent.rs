@@ -7,7 +7,7 @@ use crate::{
 
 pub struct Torrent {
     pub name: String,
-    pub announce: String,
+    pub announce_list: Vec<Vec<String>>,
     pub length: u64,
     pub info_hash: InfoHash,
     pub piece_length: u64,
@@ -26,13 +26,14 @@ impl TryFrom<TorrentMetaInfo> for Torrent {
     fn try_from(i: TorrentMetaInfo) -> Result<Self, Self::Error> {
         let info_hash = i.info_hash()?;
         let piece_hashes = i.piece_hashes()?;
+        let announce_list = i.announce_tiers();
 
         if let Some(length) = i.info.length {
             // Single file case
             let path = PathBuf::from(&i.info.name);
             Result::Ok(Self {
                 name: i.info.name,
-                announce: i.announce,
+                announce_list,
                 length,
                 info_hash,
                 piece_length: i.info.piece_length,
@@ -43,7 +44,7 @@ impl TryFrom<TorrentMetaInfo> for Torrent {
             // Multi-file case
             Result::Ok(Self {
                 name: i.info.name,
-                announce: i.announce,
+                announce_list,
                 length: files.iter().map(|f| f.length).sum(),
                 info_hash,
                 piece_length: i.info.piece_length,
@@ -4,12 +4,18 @@ use serde_bytes::ByteBuf;
 use serde_derive::{Deserialize, Serialize};
 use sha1::{Digest, Sha1};
 
+use crate::bencode;
 use crate::types::{InfoHash, PieceHash, PIECE_HASH_LEN};
 
 #[derive(Debug, Deserialize)]
 pub struct TorrentMetaInfo {
-    pub announce: String,
+    pub announce: Option<String>,
+    #[serde(rename = "announce-list")]
+    pub announce_list: Option<Vec<Vec<String>>>,
     pub info: TorrentMetaInfoInfo,
+    /// The exact bytes of the bencoded info dictionary, which the info hash is computed from
+    #[serde(skip)]
+    pub info_bytes: Vec<u8>,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -31,15 +37,57 @@ pub struct TorrentMetaInfoInfoFile {
 impl TorrentMetaInfo {
     pub fn from_file(path: &Path) -> anyhow::Result<Self> {
         let bytes = std::fs::read(path)?;
-        let torrent_file = serde_bencode::from_bytes::<Self>(&bytes)?;
+        Self::from_bytes(&bytes)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> anyhow::Result<Self> {
+        let mut torrent_file = serde_bencode::from_bytes::<Self>(bytes)?;
+
+        // Keep the original bytes of the info dictionary around, re-serializing the parsed info
+        // would drop any keys we don't model and produce the wrong info hash
+        let info_span = bencode::dict_value_span(bytes, b"info")?
+            .ok_or_else(|| anyhow::anyhow!("Invalid torrent meta info, missing info dictionary"))?;
+        torrent_file.info_bytes = bytes[info_span].to_vec();
+
         Result::Ok(torrent_file)
     }
 
+    /// Builds the meta info for a torrent from an info dictionary fetched from peers, e.g. when
+    /// starting from a magnet link
+    pub fn from_info_bytes(info_bytes: Vec<u8>, trackers: Vec<String>) -> anyhow::Result<Self> {
+        let info = serde_bencode::from_bytes::<TorrentMetaInfoInfo>(&info_bytes)?;
+        Result::Ok(Self {
+            announce: None,
+            announce_list: Some(vec![trackers]),
+            info,
+            info_bytes,
+        })
+    }
+
     pub fn info_hash(&self) -> anyhow::Result<InfoHash> {
-        let info_bytes = serde_bencode::to_bytes(&self.info)?;
         let mut hash = Sha1::new();
-        hash.update(info_bytes);
-        Result::Ok(hash.finalize().try_into()?)
+        hash.update(&self.info_bytes);
+        Result::Ok(hash.finalize().into())
+    }
+
+    /// Returns the tiers of tracker URLs for this torrent. If an announce list is present it takes
+    /// precedence over the single announce URL, as described in BEP 12.
+    pub fn announce_tiers(&self) -> Vec<Vec<String>> {
+        let tiers: Vec<Vec<String>> = self
+            .announce_list
+            .iter()
+            .flatten()
+            .filter(|tier| !tier.is_empty())
+            .cloned()
+            .collect();
+
+        if !tiers.is_empty() {
+            tiers
+        } else if let Some(announce) = &self.announce {
+            vec![vec![announce.clone()]]
+        } else {
+            Vec::new()
+        }
     }
 
     pub fn piece_hashes(&self) -> anyhow::Result<Vec<PieceHash>> {
@@ -47,10 +95,34 @@ impl TorrentMetaInfo {
             .info
             .pieces
             .chunks_exact(PIECE_HASH_LEN)
-            .map(|chunk| TryInto::<PieceHash>::try_into(chunk))
+            .map(TryInto::<PieceHash>::try_into)
             .filter_map(|chunk_result| chunk_result.ok())
             .collect();
 
         Result::Ok(hashes)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hashes_info_with_unknown_keys() {
+        let mut info = b"d6:lengthi5e4:name4:test12:piece lengthi16384e6:pieces20:".to_vec();
+        info.extend_from_slice(&[7; 20]);
+        info.extend_from_slice(b"7:privatei1ee");
+        let mut bytes = b"d8:announce19:http://tracker/test4:info".to_vec();
+        bytes.extend_from_slice(&info);
+        bytes.push(b'e');
+
+        let torrent = TorrentMetaInfo::from_bytes(&bytes).unwrap();
+        assert_eq!(torrent.info_bytes, info);
+        let expected: InfoHash = Sha1::digest(&info).into();
+        assert_eq!(torrent.info_hash().unwrap(), expected);
+
+        // Re-serializing the parsed info would have dropped the private flag
+        let reserialized = serde_bencode::to_bytes(&torrent.info).unwrap();
+        assert_ne!(reserialized, info);
+    }
+}
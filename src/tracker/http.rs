@@ -1,45 +1,481 @@
-use std::net::Ipv4Addr;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
 
 use serde_bytes::ByteBuf;
 use serde_derive::Deserialize;
+use tracing::warn;
+use url::Url;
 
-use crate::{torrent::Torrent, types::PeerID};
-
-use super::Peer;
+use super::{
+    local_ipv6, Announce, AnnounceResponse, Peer, ScrapeStats, TrackerError, COMPACT_LEN_V4,
+    COMPACT_LEN_V6,
+};
+use crate::ratelimit::RateLimits;
+use crate::types::InfoHash;
 
+// Everything is optional, since a tracker that fails a request only has to send the reason
 #[derive(Debug, Deserialize)]
 struct TrackerResponse {
-    peers: ByteBuf,
+    #[serde(rename = "failure reason")]
+    failure_reason: Option<ByteBuf>,
+    #[serde(rename = "warning message")]
+    warning_message: Option<ByteBuf>,
+    peers: Option<TrackerPeers>,
+    peers6: Option<ByteBuf>,
+    interval: Option<u64>,
+    #[serde(rename = "min interval")]
+    min_interval: Option<u64>,
+    #[serde(rename = "tracker id")]
+    tracker_id: Option<ByteBuf>,
+    complete: Option<u32>,
+    incomplete: Option<u32>,
+}
+
+/// Trackers send peers either as a compact string of addresses, or in the original dictionary
+/// model as a list of dictionaries
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum TrackerPeers {
+    Compact(ByteBuf),
+    Dictionary(Vec<TrackerPeer>),
 }
 
-pub async fn get_peers(
-    peer_id: &PeerID,
+#[derive(Debug, Deserialize)]
+struct TrackerPeer {
+    /// An IPv4 or IPv6 address, or a DNS name
+    ip: String,
     port: u16,
-    torrent: &Torrent,
-) -> anyhow::Result<Vec<Peer>> {
-    let peer_id_encoded = urlencoding::encode_binary(peer_id);
-    let info_hash_encoded = urlencoding::encode_binary(&torrent.info_hash);
-    let tracker_url = format!(
-        "{}?peer_id={}&info_hash={}&port={}&left={}&compact=1&uploaded=0&downloaded=0",
-        torrent.announce,
-        peer_id_encoded,
-        info_hash_encoded,
-        port.to_string(),
-        torrent.length.to_string()
-    );
+    #[serde(rename = "peer id")]
+    peer_id: Option<ByteBuf>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ScrapeResponse {
+    #[serde(rename = "failure reason")]
+    failure_reason: Option<ByteBuf>,
+    #[serde(default)]
+    files: HashMap<ByteBuf, ScrapeFile>,
+}
+
+// Some trackers leave out counts they don't track
+#[derive(Debug, Deserialize)]
+struct ScrapeFile {
+    #[serde(default)]
+    complete: u32,
+    #[serde(default)]
+    incomplete: u32,
+    #[serde(default)]
+    downloaded: u32,
+}
 
+pub async fn announce(
+    tracker: &Url,
+    announce: &Announce,
+    tracker_id: Option<&[u8]>,
+    limits: &RateLimits,
+) -> anyhow::Result<AnnounceResponse> {
+    // Let trackers reached over IPv4 hand out our IPv6 address too (BEP 7)
+    let tracker_url = announce_url(tracker, announce, tracker_id, local_ipv6().await);
+
+    // The request is all in the URL, and the response is read in one go, so they are counted
+    // against the limits as a whole
+    limits.upload.acquire(tracker_url.as_str().len()).await;
     let response = reqwest::get(tracker_url).await?.bytes().await?;
-    let tracker_response = serde_bencode::from_bytes::<TrackerResponse>(&response)?;
-
-    let peers: Vec<Peer> = tracker_response
-        .peers
-        .chunks(6)
-        .map(|chunk| {
-            let ip = Ipv4Addr::new(chunk[0], chunk[1], chunk[2], chunk[3]);
-            let port = u16::from_be_bytes([chunk[4], chunk[5]]);
-            Peer::new(ip, port)
+    limits.download.acquire(response.len()).await;
+    parse_announce(tracker, &response).await
+}
+
+/// Parses an announce response, turning a failure reason into a TrackerError
+async fn parse_announce(tracker: &Url, response: &[u8]) -> anyhow::Result<AnnounceResponse> {
+    let tracker_response = serde_bencode::from_bytes::<TrackerResponse>(response)?;
+
+    if let Some(reason) = tracker_response.failure_reason {
+        return Result::Err(
+            TrackerError {
+                reason: String::from_utf8_lossy(&reason).into_owned(),
+            }
+            .into(),
+        );
+    }
+    if let Some(warning) = &tracker_response.warning_message {
+        warn!(
+            "Warning from tracker {}: {}",
+            tracker,
+            String::from_utf8_lossy(warning)
+        );
+    }
+
+    let mut peers = match tracker_response.peers {
+        Some(TrackerPeers::Compact(peers)) => parse_compact_peers(&peers, COMPACT_LEN_V4)?,
+        Some(TrackerPeers::Dictionary(peers)) => resolve_peers(peers).await,
+        None => return Result::Err(anyhow::anyhow!("Tracker response has no peers")),
+    };
+    if let Some(peers6) = &tracker_response.peers6 {
+        peers.extend(parse_compact_peers(peers6, COMPACT_LEN_V6)?);
+    }
+
+    Result::Ok(AnnounceResponse {
+        peers,
+        interval: tracker_response.interval.map(Duration::from_secs),
+        min_interval: tracker_response.min_interval.map(Duration::from_secs),
+        seeders: tracker_response.complete,
+        leechers: tracker_response.incomplete,
+        tracker_id: tracker_response.tracker_id.map(ByteBuf::into_vec),
+    })
+}
+
+fn parse_compact_peers(peers: &[u8], peer_len: usize) -> anyhow::Result<Vec<Peer>> {
+    if !peers.len().is_multiple_of(peer_len) {
+        return Result::Err(anyhow::anyhow!(
+            "Invalid compact peers, length {} is not a multiple of {}",
+            peers.len(),
+            peer_len
+        ));
+    }
+
+    Result::Ok(
+        peers
+            .chunks_exact(peer_len)
+            .map(Peer::from_compact)
+            .collect(),
+    )
+}
+
+/// Converts peers in the dictionary model into addresses, looking up any given by DNS name.
+/// Peers that can't be resolved are skipped.
+async fn resolve_peers(tracker_peers: Vec<TrackerPeer>) -> Vec<Peer> {
+    let mut peers = Vec::with_capacity(tracker_peers.len());
+    for tracker_peer in tracker_peers {
+        let addr = match tracker_peer.ip.parse::<IpAddr>() {
+            Ok(ip) => SocketAddr::new(ip, tracker_peer.port),
+            Err(_) => {
+                let lookup = tokio::net::lookup_host((tracker_peer.ip.as_str(), tracker_peer.port));
+                match lookup.await.ok().and_then(|mut addrs| addrs.next()) {
+                    Some(addr) => addr,
+                    None => {
+                        warn!("Could not resolve peer address {}", tracker_peer.ip);
+                        continue;
+                    }
+                }
+            }
+        };
+
+        let mut peer = Peer::new(addr);
+        peer.peer_id = tracker_peer
+            .peer_id
+            .and_then(|peer_id| peer_id.as_ref().try_into().ok());
+        peers.push(peer);
+    }
+
+    peers
+}
+
+pub async fn scrape(
+    tracker: &Url,
+    info_hashes: &[InfoHash],
+) -> anyhow::Result<HashMap<InfoHash, ScrapeStats>> {
+    let mut scrape_url = scrape_url(tracker)?;
+    let info_hashes_encoded: Vec<String> = info_hashes
+        .iter()
+        .map(|info_hash| format!("info_hash={}", urlencoding::encode_binary(info_hash)))
+        .collect();
+    // Info hashes are already percent encoded, so add them to the query as is
+    let query = match scrape_url.query() {
+        Some(query) => format!("{}&{}", query, info_hashes_encoded.join("&")),
+        None => info_hashes_encoded.join("&"),
+    };
+    scrape_url.set_query(Some(&query));
+
+    let response = reqwest::get(scrape_url).await?.bytes().await?;
+    parse_scrape(&response)
+}
+
+/// Parses a scrape response, leaving out entries whose info hash is invalid
+fn parse_scrape(response: &[u8]) -> anyhow::Result<HashMap<InfoHash, ScrapeStats>> {
+    let scrape_response = serde_bencode::from_bytes::<ScrapeResponse>(response)?;
+    if let Some(reason) = scrape_response.failure_reason {
+        return Result::Err(
+            TrackerError {
+                reason: String::from_utf8_lossy(&reason).into_owned(),
+            }
+            .into(),
+        );
+    }
+
+    let stats = scrape_response
+        .files
+        .into_iter()
+        .filter_map(|(info_hash, file)| {
+            let info_hash: InfoHash = info_hash.as_ref().try_into().ok()?;
+            let stats = ScrapeStats {
+                complete: file.complete,
+                incomplete: file.incomplete,
+                downloaded: file.downloaded,
+            };
+            Some((info_hash, stats))
         })
         .collect();
 
-    Result::Ok(peers)
+    Result::Ok(stats)
+}
+
+/// Adds the announce parameters to the tracker URL, keeping any query it already has
+fn announce_url(
+    tracker: &Url,
+    announce: &Announce,
+    tracker_id: Option<&[u8]>,
+    ipv6: Option<Ipv6Addr>,
+) -> Url {
+    let mut params = vec![
+        format!("peer_id={}", urlencoding::encode_binary(&announce.peer_id)),
+        format!(
+            "info_hash={}",
+            urlencoding::encode_binary(&announce.info_hash)
+        ),
+        format!("port={}", announce.port),
+        format!("uploaded={}", announce.uploaded),
+        format!("downloaded={}", announce.downloaded),
+        format!("left={}", announce.left),
+        "compact=1".to_string(),
+    ];
+    if let Some(event) = announce.event.as_str() {
+        params.push(format!("event={}", event));
+    }
+    if let Some(tracker_id) = tracker_id {
+        params.push(format!(
+            "trackerid={}",
+            urlencoding::encode_binary(tracker_id)
+        ));
+    }
+    if let Some(ip) = ipv6 {
+        params.push(format!("ipv6={}", urlencoding::encode(&ip.to_string())));
+    }
+
+    // The parameters are already percent encoded, so add them to the query as is
+    let mut tracker_url = tracker.clone();
+    let query = match tracker.query() {
+        Some(query) => format!("{}&{}", query, params.join("&")),
+        None => params.join("&"),
+    };
+    tracker_url.set_query(Some(&query));
+    tracker_url
+}
+
+/// Derives the scrape URL from an announce URL by replacing `announce` at the start of the last
+/// path segment with `scrape`, the convention trackers follow if they support scraping
+fn scrape_url(tracker: &Url) -> anyhow::Result<Url> {
+    let path = tracker.path();
+    let (dir, last) = path.rsplit_once('/').unwrap_or(("", path));
+    let rest = last
+        .strip_prefix("announce")
+        .ok_or_else(|| anyhow::anyhow!("Tracker {} does not support scraping", tracker))?;
+
+    let mut scrape_url = tracker.clone();
+    scrape_url.set_path(&format!("{}/scrape{}", dir, rest));
+    Result::Ok(scrape_url)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tracker::AnnounceEvent;
+
+    fn test_announce() -> Announce {
+        Announce {
+            info_hash: [0xab; 20],
+            peer_id: *b"-RT0001-123456789012",
+            port: 6881,
+            uploaded: 1,
+            downloaded: 2,
+            left: 3,
+            event: AnnounceEvent::Started,
+        }
+    }
+
+    #[test]
+    fn announce_url_keeps_existing_query() {
+        let tracker = Url::parse("http://tracker.example/announce?passkey=abc").unwrap();
+        let ipv6 = "2001:db8::1".parse().ok();
+        let url = announce_url(&tracker, &test_announce(), Some(b"id"), ipv6);
+
+        let query = url.query().unwrap();
+        assert!(query.starts_with("passkey=abc&peer_id=-RT0001-123456789012&"));
+        assert!(query.contains(&format!("&info_hash={}&", "%AB".repeat(20))));
+        assert!(query.contains("&left=3&compact=1&event=started&trackerid=id"));
+        assert!(query.ends_with("&ipv6=2001%3Adb8%3A%3A1"));
+        assert_eq!(url.path(), "/announce");
+    }
+
+    #[test]
+    fn announce_url_without_query() {
+        let tracker = Url::parse("http://tracker.example/announce").unwrap();
+        let url = announce_url(&tracker, &test_announce(), None, None);
+
+        let query = url.query().unwrap();
+        assert!(query.starts_with("peer_id="));
+        assert!(!query.contains("trackerid"));
+        assert!(!query.contains("ipv6"));
+    }
+
+    fn tracker() -> Url {
+        Url::parse("http://tracker.example/announce").unwrap()
+    }
+
+    #[tokio::test]
+    async fn parses_ipv6_peers() {
+        let mut response = b"d8:intervali1800e5:peers6:".to_vec();
+        response.extend_from_slice(&[10, 0, 0, 1, 0x1a, 0xe1]);
+        response.extend_from_slice(b"6:peers636:");
+        for last in [1, 2] {
+            response.extend_from_slice(&[0x20, 0x01, 0x0d, 0xb8]);
+            response.extend_from_slice(&[0; 11]);
+            response.extend_from_slice(&[last, 0x1a, 0xe1]);
+        }
+        response.push(b'e');
+
+        let response = parse_announce(&tracker(), &response).await.unwrap();
+        let peers: Vec<SocketAddr> = response.peers.iter().map(|peer| peer.addr).collect();
+        assert_eq!(
+            peers,
+            vec![
+                "10.0.0.1:6881".parse().unwrap(),
+                "[2001:db8::1]:6881".parse().unwrap(),
+                "[2001:db8::2]:6881".parse().unwrap(),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_truncated_ipv6_peers() {
+        let mut response = b"d5:peers0:6:peers617:".to_vec();
+        response.extend_from_slice(&[0; 17]);
+        response.push(b'e');
+        assert!(parse_announce(&tracker(), &response).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn parses_dictionary_peers() {
+        let response = b"d5:peersl\
+            d2:ip8:10.0.0.17:peer id20:-XX0001-0123456789ab4:porti6881ee\
+            d2:ip11:2001:db8::14:porti6882ee\
+            d2:ip9:localhost4:porti6883ee\
+            ee";
+
+        let response = parse_announce(&tracker(), response).await.unwrap();
+        assert_eq!(response.peers.len(), 3);
+        assert_eq!(response.peers[0].addr, "10.0.0.1:6881".parse().unwrap());
+        assert_eq!(response.peers[0].peer_id, Some(*b"-XX0001-0123456789ab"));
+        assert_eq!(
+            response.peers[1].addr,
+            "[2001:db8::1]:6882".parse().unwrap()
+        );
+        assert_eq!(response.peers[1].peer_id, None);
+        // Peers given by DNS name are looked up
+        assert!(response.peers[2].addr.ip().is_loopback());
+        assert_eq!(response.peers[2].addr.port(), 6883);
+    }
+
+    #[tokio::test]
+    async fn requires_peers() {
+        assert!(parse_announce(&tracker(), b"d8:intervali1800ee")
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn returns_failure_reasons() {
+        let response = b"d14:failure reason17:torrent not founde";
+        let error = parse_announce(&tracker(), response).await.unwrap_err();
+        let error = error.downcast::<TrackerError>().unwrap();
+        assert_eq!(error.reason, "torrent not found");
+    }
+
+    #[tokio::test]
+    async fn parses_intervals_and_tracker_ids() {
+        let response = b"d8:completei4e10:incompletei6e8:intervali1800e12:min intervali900e\
+            5:peers0:10:tracker id3:abc15:warning message4:slowe";
+
+        let response = parse_announce(&tracker(), response).await.unwrap();
+        assert!(response.peers.is_empty());
+        assert_eq!(response.interval, Some(Duration::from_secs(1800)));
+        assert_eq!(response.min_interval, Some(Duration::from_secs(900)));
+        assert_eq!(response.seeders, Some(4));
+        assert_eq!(response.leechers, Some(6));
+        assert_eq!(response.tracker_id.as_deref(), Some(&b"abc"[..]));
+    }
+
+    #[test]
+    fn round_trips_compact_peers() {
+        for addr in ["10.0.0.1:6881", "[2001:db8::1]:51413"] {
+            let peer = Peer::new(addr.parse().unwrap());
+            let compact = peer.to_compact();
+            assert_eq!(
+                compact.len(),
+                if peer.addr.is_ipv6() {
+                    COMPACT_LEN_V6
+                } else {
+                    COMPACT_LEN_V4
+                }
+            );
+            assert_eq!(Peer::from_compact(&compact), peer);
+        }
+    }
+
+    #[test]
+    fn maps_ipv4_mapped_addresses_to_ipv4() {
+        let peer = Peer::new("[::ffff:10.0.0.1]:6881".parse().unwrap());
+        assert_eq!(peer.addr, "10.0.0.1:6881".parse().unwrap());
+    }
+
+    #[test]
+    fn derives_scrape_urls() {
+        let scrape =
+            |announce: &str| scrape_url(&Url::parse(announce).unwrap()).map(|url| url.to_string());
+        assert_eq!(
+            scrape("http://example.com/announce").unwrap(),
+            "http://example.com/scrape"
+        );
+        assert_eq!(
+            scrape("http://example.com/x/announce.php?passkey=abc").unwrap(),
+            "http://example.com/x/scrape.php?passkey=abc"
+        );
+        assert!(scrape("http://example.com/a").is_err());
+        assert!(scrape("http://example.com/announce/x").is_err());
+    }
+
+    #[test]
+    fn parses_scrape_responses() {
+        let mut response = b"d5:filesd20:".to_vec();
+        response.extend_from_slice(&[0xab; 20]);
+        response.extend_from_slice(b"d8:completei5e10:downloadedi50e10:incompletei10ee");
+        // Counts the tracker doesn't keep are left out
+        response.extend_from_slice(b"20:");
+        response.extend_from_slice(&[0xcd; 20]);
+        response.extend_from_slice(b"d8:completei1ee");
+        // Keys that aren't info hashes are skipped
+        response.extend_from_slice(b"3:badd8:completei1eeee");
+
+        let stats = parse_scrape(&response).unwrap();
+        assert_eq!(stats.len(), 2);
+        assert_eq!(
+            stats[&[0xab; 20]],
+            ScrapeStats {
+                complete: 5,
+                incomplete: 10,
+                downloaded: 50,
+            }
+        );
+        assert_eq!(
+            stats[&[0xcd; 20]],
+            ScrapeStats {
+                complete: 1,
+                incomplete: 0,
+                downloaded: 0,
+            }
+        );
+
+        let error = parse_scrape(b"d14:failure reason6:deniede").unwrap_err();
+        assert_eq!(error.downcast::<TrackerError>().unwrap().reason, "denied");
+    }
 }
@@ -1,44 +1,445 @@
 mod http;
 mod udp;
 
-use std::{fmt::Display, net::Ipv4Addr};
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::hash::{Hash, Hasher};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
 
+use rand::seq::SliceRandom;
+use tokio::net::UdpSocket;
+use tracing::warn;
 use url::Url;
 
-use crate::{torrent::Torrent, types::PeerID};
+use crate::ratelimit::RateLimits;
+use crate::types::{InfoHash, PeerID};
 
+// How long to give a single tracker to answer before moving on to the next, since UDP
+// retransmissions alone can take hours to give up on a dead tracker
+const TRACKER_TIMEOUT: Duration = Duration::from_secs(60);
+
+// The length of a peer in compact form, its IP address followed by a 2 byte port
+pub const COMPACT_LEN_V4: usize = 6;
+pub const COMPACT_LEN_V6: usize = 18;
+
+/// A peer in a torrent's swarm, identified by its address
 #[derive(Debug, Clone, Copy)]
 pub struct Peer {
-    pub ip: Ipv4Addr,
-    pub port: u16,
+    pub addr: SocketAddr,
+    /// The peer id the tracker told us to expect in the peer's handshake, which is only known for
+    /// trackers that don't send peers in compact form
+    pub peer_id: Option<PeerID>,
+}
+
+impl PartialEq for Peer {
+    fn eq(&self, other: &Self) -> bool {
+        self.addr == other.addr
+    }
+}
+
+impl Eq for Peer {}
+
+impl Hash for Peer {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.addr.hash(state);
+    }
 }
 
 impl Display for Peer {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.write_fmt(format_args!("{}:{}", self.ip, self.port))?;
+        f.write_fmt(format_args!("{}", self.addr))?;
         Result::Ok(())
     }
 }
 
 impl Peer {
-    fn new(ip: Ipv4Addr, port: u16) -> Self {
-        Peer { ip, port }
+    pub fn new(addr: SocketAddr) -> Self {
+        // Connections accepted on a dual stack socket report IPv4 peers as IPv4-mapped IPv6
+        // addresses, so turn those back into IPv4 addresses to match the peers trackers give us
+        let addr = match addr {
+            SocketAddr::V6(v6) => match v6.ip().to_ipv4_mapped() {
+                Some(ip) => SocketAddr::new(IpAddr::V4(ip), v6.port()),
+                None => addr,
+            },
+            SocketAddr::V4(_) => addr,
+        };
+
+        Peer {
+            addr,
+            peer_id: None,
+        }
+    }
+
+    /// Parses a peer from its compact form, either 6 bytes for an IPv4 peer or 18 bytes for an
+    /// IPv6 peer
+    pub fn from_compact(bytes: &[u8]) -> Self {
+        let (ip, port) = if bytes.len() == COMPACT_LEN_V6 {
+            let octets: [u8; 16] = bytes[..16].try_into().unwrap();
+            (IpAddr::V6(Ipv6Addr::from(octets)), [bytes[16], bytes[17]])
+        } else {
+            let ip = Ipv4Addr::new(bytes[0], bytes[1], bytes[2], bytes[3]);
+            (IpAddr::V4(ip), [bytes[4], bytes[5]])
+        };
+        Peer::new(SocketAddr::new(ip, u16::from_be_bytes(port)))
+    }
+
+    pub fn to_compact(self) -> Vec<u8> {
+        let mut bytes = match self.addr.ip() {
+            IpAddr::V4(ip) => ip.octets().to_vec(),
+            IpAddr::V6(ip) => ip.octets().to_vec(),
+        };
+        bytes.extend_from_slice(&self.addr.port().to_be_bytes());
+        bytes
+    }
+}
+
+/// Returns the IPv6 address other hosts can reach us on, if we have one. Connecting a UDP socket
+/// doesn't send anything, but makes the OS pick the address it would route through.
+async fn local_ipv6() -> Option<Ipv6Addr> {
+    let socket = UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0)).await.ok()?;
+    socket
+        .connect((Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1), 80))
+        .await
+        .ok()?;
+
+    match socket.local_addr().ok()?.ip() {
+        // Link local addresses (fe80::/10) are only reachable on the local network
+        IpAddr::V6(ip) if !ip.is_loopback() && (ip.segments()[0] & 0xffc0) != 0xfe80 => Some(ip),
+        _ => None,
+    }
+}
+
+/// The parameters we announce to a tracker
+#[derive(Debug, Clone, Copy)]
+pub struct Announce {
+    pub info_hash: InfoHash,
+    pub peer_id: PeerID,
+    pub port: u16,
+    pub uploaded: u64,
+    pub downloaded: u64,
+    pub left: u64,
+    pub event: AnnounceEvent,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AnnounceEvent {
+    /// A regular announce made at the interval the tracker asked for
+    None,
+    Completed,
+    Started,
+    Stopped,
+}
+
+impl AnnounceEvent {
+    /// The value of the `event` parameter of an HTTP announce, which is left out for regular
+    /// announces
+    pub fn as_str(self) -> Option<&'static str> {
+        match self {
+            AnnounceEvent::None => None,
+            AnnounceEvent::Completed => Some("completed"),
+            AnnounceEvent::Started => Some("started"),
+            AnnounceEvent::Stopped => Some("stopped"),
+        }
+    }
+
+    /// The event's value in a UDP announce, as defined by BEP 15
+    pub fn as_udp(self) -> i32 {
+        match self {
+            AnnounceEvent::None => 0,
+            AnnounceEvent::Completed => 1,
+            AnnounceEvent::Started => 2,
+            AnnounceEvent::Stopped => 3,
+        }
+    }
+}
+
+/// A tracker's answer to an announce
+#[derive(Debug, Clone)]
+pub struct AnnounceResponse {
+    pub peers: Vec<Peer>,
+    /// How long the tracker wants us to wait before announcing again
+    pub interval: Option<Duration>,
+    /// The shortest time the tracker allows between announces
+    pub min_interval: Option<Duration>,
+    pub seeders: Option<u32>,
+    pub leechers: Option<u32>,
+    /// An id the tracker wants echoed back on our next announces to it
+    pub tracker_id: Option<Vec<u8>>,
+}
+
+/// A tracker refusing a request, with the reason it gave
+#[derive(Debug, Clone)]
+pub struct TrackerError {
+    pub reason: String,
+}
+
+impl Display for TrackerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!("Tracker failure: {}", self.reason))
+    }
+}
+
+impl std::error::Error for TrackerError {}
+
+/// The health of a torrent's swarm as reported by a tracker scrape
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ScrapeStats {
+    /// The number of peers with the whole torrent (seeders)
+    pub complete: u32,
+    /// The number of peers still downloading (leechers)
+    pub incomplete: u32,
+    /// The number of times the torrent has been downloaded in full
+    pub downloaded: u32,
+}
+
+/// The tiers of trackers for a torrent, as described in BEP 12. Trackers within a tier are
+/// shuffled once up front, and a tracker that answers is moved to the front of its tier so that it
+/// is tried first on the next announce.
+#[derive(Debug, Clone)]
+pub struct TrackerTiers {
+    tiers: Vec<Vec<String>>,
+    /// The tracker ids trackers have sent us, by tracker URL
+    tracker_ids: HashMap<String, Vec<u8>>,
+}
+
+impl TrackerTiers {
+    pub fn new(announce_list: &[Vec<String>]) -> Self {
+        let mut rng = rand::thread_rng();
+        let tiers = announce_list
+            .iter()
+            .map(|tier| {
+                let mut tier = tier.clone();
+                tier.shuffle(&mut rng);
+                tier
+            })
+            .collect();
+
+        Self {
+            tiers,
+            tracker_ids: HashMap::new(),
+        }
+    }
+
+    /// Announces to the first tracker that answers, keeping to the given limits on tracker traffic
+    pub async fn announce(
+        &mut self,
+        announce: &Announce,
+        limits: &RateLimits,
+    ) -> anyhow::Result<AnnounceResponse> {
+        for tier in self.tiers.iter_mut() {
+            for i in 0..tier.len() {
+                let tracker_id = self.tracker_ids.get(&tier[i]).map(Vec::as_slice);
+                let result = tokio::time::timeout(
+                    TRACKER_TIMEOUT,
+                    self::announce(&tier[i], announce, tracker_id, limits),
+                )
+                .await
+                .unwrap_or_else(|_| Result::Err(anyhow::anyhow!("Timed out")));
+                match result {
+                    Ok(response) => {
+                        if let Some(tracker_id) = &response.tracker_id {
+                            self.tracker_ids.insert(tier[i].clone(), tracker_id.clone());
+                        }
+                        // Promote the tracker that answered to the front of its tier
+                        let tracker = tier.remove(i);
+                        tier.insert(0, tracker);
+                        return Result::Ok(response);
+                    }
+                    Err(error) => {
+                        warn!("Failed to announce to tracker {}: {}", &tier[i], error);
+                    }
+                }
+            }
+        }
+
+        Result::Err(anyhow::anyhow!("Failed to announce to any tracker"))
     }
 }
 
-pub async fn get_peers(
-    peer_id: &PeerID,
-    port: u16,
-    torrent: &Torrent,
-) -> anyhow::Result<Vec<Peer>> {
-    // TODO: Support announce list
-    let url = Url::parse(&torrent.announce)?;
+/// Announces to a single tracker, passing along the tracker id it gave us before, if any
+async fn announce(
+    tracker: &str,
+    announce: &Announce,
+    tracker_id: Option<&[u8]>,
+    limits: &RateLimits,
+) -> anyhow::Result<AnnounceResponse> {
+    let url = Url::parse(tracker)?;
     match url.scheme() {
-        "http" | "https" => http::get_peers(peer_id, port, torrent).await,
-        "udp" => udp::get_peers(peer_id, port, torrent).await,
+        "http" | "https" => http::announce(&url, announce, tracker_id, limits).await,
+        "udp" => udp::announce(&url, announce, limits).await,
         scheme => Result::Err(anyhow::anyhow!(
             "Unsupported tracker URL scheme: {}",
             scheme
         )),
     }
 }
+
+/// Asks a tracker for the swarm statistics of each of the given torrents. Torrents the tracker
+/// doesn't know about are left out of the result.
+pub async fn scrape(
+    tracker: &str,
+    info_hashes: &[InfoHash],
+) -> anyhow::Result<HashMap<InfoHash, ScrapeStats>> {
+    let url = Url::parse(tracker)?;
+    let scrape = async {
+        match url.scheme() {
+            "http" | "https" => http::scrape(&url, info_hashes).await,
+            "udp" => udp::scrape(&url, info_hashes).await,
+            scheme => Result::Err(anyhow::anyhow!(
+                "Unsupported tracker URL scheme: {}",
+                scheme
+            )),
+        }
+    };
+    tokio::time::timeout(TRACKER_TIMEOUT, scrape)
+        .await
+        .unwrap_or_else(|_| Result::Err(anyhow::anyhow!("Timed out scraping tracker {}", tracker)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    fn test_announce() -> Announce {
+        Announce {
+            info_hash: [0xab; 20],
+            peer_id: *b"-RT0001-123456789012",
+            port: 6881,
+            uploaded: 0,
+            downloaded: 0,
+            left: 1,
+            event: AnnounceEvent::Started,
+        }
+    }
+
+    /// Runs an HTTP tracker on loopback that answers every announce with `body`, returning its URL
+    /// and how many announces it has had
+    async fn stub_tracker(body: Vec<u8>) -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let url = format!("http://{}/announce", listener.local_addr().unwrap());
+        let announces = Arc::new(AtomicUsize::new(0));
+
+        let counter = announces.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                counter.fetch_add(1, Ordering::SeqCst);
+
+                let mut request = Vec::new();
+                let mut buf = [0u8; 1024];
+                while !request.ends_with(b"\r\n\r\n") {
+                    let len = stream.read(&mut buf).await.unwrap();
+                    if len == 0 {
+                        break;
+                    }
+                    request.extend_from_slice(&buf[..len]);
+                }
+
+                let header = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    body.len()
+                );
+                stream.write_all(header.as_bytes()).await.unwrap();
+                stream.write_all(&body).await.unwrap();
+            }
+        });
+
+        (url, announces)
+    }
+
+    /// A tracker that answers with a single peer on `port`
+    async fn working_tracker(port: u16) -> (String, Arc<AtomicUsize>) {
+        let mut body = b"d8:intervali1800e5:peers6:".to_vec();
+        body.extend_from_slice(&Peer::new(SocketAddr::from(([10, 0, 0, 1], port))).to_compact());
+        body.push(b'e');
+        stub_tracker(body).await
+    }
+
+    async fn failing_tracker() -> (String, Arc<AtomicUsize>) {
+        stub_tracker(b"d14:failure reason17:torrent not founde".to_vec()).await
+    }
+
+    /// Tiers in a fixed order, rather than shuffled
+    fn tiers(tiers: &[&[&str]]) -> TrackerTiers {
+        TrackerTiers {
+            tiers: tiers
+                .iter()
+                .map(|tier| tier.iter().map(|url| url.to_string()).collect())
+                .collect(),
+            tracker_ids: HashMap::new(),
+        }
+    }
+
+    async fn announced_port(trackers: &mut TrackerTiers) -> u16 {
+        let response = trackers
+            .announce(&test_announce(), &RateLimits::default())
+            .await
+            .unwrap();
+        response.peers[0].addr.port()
+    }
+
+    #[tokio::test]
+    async fn falls_back_within_a_tier_and_promotes_the_tracker_that_answered() {
+        let (failing, failures) = failing_tracker().await;
+        let (working, successes) = working_tracker(1001).await;
+        let (backup, backups) = working_tracker(1002).await;
+        let mut trackers = tiers(&[&[&failing, &working], &[&backup]]);
+
+        assert_eq!(announced_port(&mut trackers).await, 1001);
+        assert_eq!(trackers.tiers[0], vec![working.clone(), failing.clone()]);
+
+        // The tracker that answered is tried first next time
+        assert_eq!(announced_port(&mut trackers).await, 1001);
+        assert_eq!(failures.load(Ordering::SeqCst), 1);
+        assert_eq!(successes.load(Ordering::SeqCst), 2);
+        assert_eq!(backups.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_the_next_tier() {
+        let (failing, failures) = failing_tracker().await;
+        let (also_failing, _) = failing_tracker().await;
+        let (failing_backup, _) = failing_tracker().await;
+        let (backup, _) = working_tracker(1002).await;
+        let mut trackers = tiers(&[&[&failing, &also_failing], &[&failing_backup, &backup]]);
+
+        assert_eq!(announced_port(&mut trackers).await, 1002);
+        // Only the tier of the tracker that answered is reordered
+        assert_eq!(trackers.tiers[0], vec![failing, also_failing]);
+        assert_eq!(trackers.tiers[1], vec![backup, failing_backup]);
+        assert_eq!(failures.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn fails_when_no_tracker_answers() {
+        let (failing, failures) = failing_tracker().await;
+        let mut trackers = tiers(&[&[&failing], &[]]);
+        assert!(trackers
+            .announce(&test_announce(), &RateLimits::default())
+            .await
+            .is_err());
+        assert_eq!(failures.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn gives_each_tracker_a_deadline() {
+        // A tracker that never answers, which would otherwise take hours of retransmissions
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let tracker = format!("udp://{}", socket.local_addr().unwrap());
+        let mut trackers = TrackerTiers::new(&[vec![tracker]]);
+
+        let started = tokio::time::Instant::now();
+        assert!(trackers
+            .announce(&test_announce(), &RateLimits::default())
+            .await
+            .is_err());
+        assert_eq!(started.elapsed(), TRACKER_TIMEOUT);
+    }
+}
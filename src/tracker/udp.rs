@@ -1,60 +1,99 @@
-use std::net::Ipv4Addr;
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::time::Duration;
 
-use crate::{
-    torrent::Torrent,
-    types::{InfoHash, PeerID},
+use super::{
+    Announce, AnnounceResponse, Peer, ScrapeStats, TrackerError, COMPACT_LEN_V4, COMPACT_LEN_V6,
 };
-
-use super::Peer;
+use crate::ratelimit::RateLimits;
+use crate::types::InfoHash;
 
 use bytes::{Buf, BufMut, BytesMut};
 use rand::Rng;
 use tokio::net::UdpSocket;
+use tokio::time::Instant;
 use url::Url;
 
-const BUFFER_SIZE: usize = 1024;
+// The largest UDP payload, since announce responses grow with the number of peers
+const BUFFER_SIZE: usize = 65535;
 
 const PROTOCOL_ID: i64 = 0x41727101980;
 const ACTION_CONNECT: i32 = 0;
 const ACTION_ANNOUNCE: i32 = 1;
+const ACTION_SCRAPE: i32 = 2;
+const ACTION_ERROR: i32 = 3;
+
+// The size of an announce request (BEP 15)
+const ANNOUNCE_REQUEST_LEN: usize = 98;
+
+// The most info hashes that can be scraped at once, as limited by BEP 15
+const MAX_SCRAPE_INFO_HASHES: usize = 74;
+
+// The most times a request is retransmitted before giving up on the tracker
+const MAX_RETRANSMISSIONS: u32 = 8;
+
+// How long a connection id can be used for after the tracker sends it
+const CONNECTION_ID_LIFETIME: Duration = Duration::from_secs(60);
 
 fn generate_transaction_id() -> i32 {
     rand::thread_rng().gen()
 }
 
 struct UdpTrackerConnection {
-    info_hash: InfoHash,
-    length: u64,
     socket: UdpSocket,
-    backoff: u32,
-    connection_id: Option<i64>,
+    /// The connection id and when we received it
+    connection: Option<(i64, Instant)>,
 }
 
 impl UdpTrackerConnection {
-    async fn new(torrent: &Torrent) -> anyhow::Result<Self> {
-        let url = Url::parse(&torrent.announce)?;
-        let socket_addr = &*url.socket_addrs(|| None)?;
-        let socket = UdpSocket::bind(("0.0.0.0", 0)).await?;
+    async fn new(url: &Url) -> anyhow::Result<Self> {
+        let socket_addr = *url
+            .socket_addrs(|| None)?
+            .first()
+            .ok_or_else(|| anyhow::anyhow!("Could not resolve UDP tracker {}", url))?;
+        let socket = if socket_addr.is_ipv6() {
+            UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0)).await?
+        } else {
+            UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?
+        };
         socket.connect(socket_addr).await?;
 
         Result::Ok(Self {
-            info_hash: torrent.info_hash,
-            length: torrent.length,
             socket,
-            backoff: 0,
-            connection_id: None,
+            connection: None,
         })
     }
 
-    async fn connect(&mut self) -> anyhow::Result<()> {
-        let transaction_id = generate_transaction_id();
+    /// Returns a valid connection id, connecting to the tracker first if we don't have one or the
+    /// one we have has expired
+    async fn connection_id(&mut self) -> anyhow::Result<i64> {
+        if let Some((connection_id, received)) = self.connection {
+            if received.elapsed() < CONNECTION_ID_LIFETIME {
+                return Result::Ok(connection_id);
+            }
+        }
 
-        // TODO: timeouts and retransmission
-        self.send_connect(transaction_id).await?;
-        let connection_id = self.recv_connect(transaction_id).await?;
-        self.connection_id = Some(connection_id);
+        let transaction_id = generate_transaction_id();
+        let mut retransmissions = 0;
+        loop {
+            self.send_connect(transaction_id).await?;
+            if let Some(mut buf) = self
+                .recv_response(ACTION_CONNECT, transaction_id, retransmissions)
+                .await?
+            {
+                if buf.len() < 8 {
+                    return Result::Err(anyhow::anyhow!(
+                        "Invalid UDP connect response, expected at least 16 bytes got {}",
+                        buf.len() + 8
+                    ));
+                }
 
-        Result::Ok(())
+                let connection_id = buf.get_i64();
+                self.connection = Some((connection_id, Instant::now()));
+                return Result::Ok(connection_id);
+            }
+            retransmit(&mut retransmissions)?;
+        }
     }
 
     async fn send_connect(&self, transaction_id: i32) -> anyhow::Result<()> {
@@ -75,114 +114,196 @@ impl UdpTrackerConnection {
         }
     }
 
-    async fn recv_connect(&mut self, transaction_id: i32) -> anyhow::Result<i64> {
-        let mut buf = self.recv().await?;
-        if buf.len() < 16 {
-            return Result::Err(anyhow::anyhow!(
-                "Invalid UDP connect response, expected at least 16 bytes got {}",
-                buf.len()
-            ));
-        }
-
-        let action = buf.get_i32();
-        if action != ACTION_CONNECT {
-            return Result::Err(anyhow::anyhow!(
-                "Invalid UDP connect response, expected action {} got {}",
-                ACTION_CONNECT,
-                action
-            ));
-        }
-
-        let tid = buf.get_i32();
-        if tid != transaction_id {
-            return Result::Err(anyhow::anyhow!(
-                "Invalid UDP connect response, expected transaction ID {} got {}",
-                transaction_id,
-                tid
-            ));
-        }
-
-        let connection_id = buf.get_i64();
-        Result::Ok(connection_id)
-    }
-
-    async fn announce(&mut self, peer_id: &PeerID, port: u16) -> anyhow::Result<Vec<Peer>> {
-        let connection_id = self
-            .connection_id
-            .ok_or_else(|| anyhow::anyhow!("Cannot announce without connection id"))?;
+    async fn announce(
+        &mut self,
+        announce: &Announce,
+        limits: &RateLimits,
+    ) -> anyhow::Result<AnnounceResponse> {
         let transaction_id = generate_transaction_id();
+        let mut retransmissions = 0;
+        loop {
+            // The connection id may expire while we wait for a response, so check it before every
+            // retransmission
+            let connection_id = self.connection_id().await?;
+            limits.upload.acquire(ANNOUNCE_REQUEST_LEN).await;
+            self.send_announce(announce, connection_id, transaction_id)
+                .await?;
 
-        // TODO: timeouts and retransmission
-        self.send_announce(peer_id, port, connection_id, transaction_id)
-            .await?;
-
-        self.recv_announce(transaction_id).await
+            if let Some(buf) = self
+                .recv_response(ACTION_ANNOUNCE, transaction_id, retransmissions)
+                .await?
+            {
+                // The action and transaction id were already taken off the response
+                limits.download.acquire(8 + buf.len()).await;
+                return self.parse_announce(buf);
+            }
+            retransmit(&mut retransmissions)?;
+        }
     }
 
     async fn send_announce(
         &self,
-        peer_id: &PeerID,
-        port: u16,
+        announce: &Announce,
         connection_id: i64,
         transaction_id: i32,
     ) -> anyhow::Result<()> {
-        let mut buf = BytesMut::with_capacity(98);
+        let mut buf = BytesMut::with_capacity(ANNOUNCE_REQUEST_LEN);
         buf.put_i64(connection_id); // connection_id
         buf.put_i32(ACTION_ANNOUNCE); // action
         buf.put_i32(transaction_id); // transaction_id
-        buf.put_slice(&self.info_hash); // info_hash
-        buf.put_slice(peer_id); // peer_id
-        buf.put_i64(0); // downloaded
-        buf.put_i64(self.length.try_into()?); // left
-        buf.put_i64(0); // uploaded
-        buf.put_i32(0); // event
+        buf.put_slice(&announce.info_hash); // info_hash
+        buf.put_slice(&announce.peer_id); // peer_id
+        buf.put_i64(announce.downloaded.try_into()?); // downloaded
+        buf.put_i64(announce.left.try_into()?); // left
+        buf.put_i64(announce.uploaded.try_into()?); // uploaded
+        buf.put_i32(announce.event.as_udp()); // event
         buf.put_i32(0); // IP address
         buf.put_i32(0); // key
         buf.put_i32(-1); // num_want
-        buf.put_u16(port); // port
+        buf.put_u16(announce.port); // port
         self.socket.send(&buf).await?;
         Result::Ok(())
     }
 
-    async fn recv_announce(&mut self, transaction_id: i32) -> anyhow::Result<Vec<Peer>> {
-        let mut buf = self.recv().await?;
-        if buf.len() < 16 {
+    fn parse_announce(&self, mut buf: BytesMut) -> anyhow::Result<AnnounceResponse> {
+        if buf.len() < 12 {
             return Result::Err(anyhow::anyhow!(
-                "Invalid UDP announce response, expected at least 16 bytes got {}",
-                buf.len()
+                "Invalid UDP announce response, expected at least 20 bytes got {}",
+                buf.len() + 8
             ));
         }
 
-        let action = buf.get_i32();
-        if action != ACTION_ANNOUNCE {
-            return Result::Err(anyhow::anyhow!(
-                "Invalid UDP announce response, expected action {} got {}",
-                ACTION_ANNOUNCE,
-                action
-            ));
+        let interval = buf.get_u32();
+        let leechers = buf.get_u32();
+        let seeders = buf.get_u32();
+
+        // Trackers reached over IPv6 respond with IPv6 peers (BEP 15)
+        let peer_len = if self.socket.peer_addr()?.is_ipv6() {
+            COMPACT_LEN_V6
+        } else {
+            COMPACT_LEN_V4
+        };
+        let peers = buf.chunks_exact(peer_len).map(Peer::from_compact).collect();
+
+        Result::Ok(AnnounceResponse {
+            peers,
+            interval: Some(Duration::from_secs(interval as u64)),
+            min_interval: None,
+            seeders: Some(seeders),
+            leechers: Some(leechers),
+            tracker_id: None,
+        })
+    }
+
+    /// Scrapes up to MAX_SCRAPE_INFO_HASHES torrents at once
+    async fn scrape(&mut self, info_hashes: &[InfoHash]) -> anyhow::Result<Vec<ScrapeStats>> {
+        let transaction_id = generate_transaction_id();
+        let mut retransmissions = 0;
+        loop {
+            let connection_id = self.connection_id().await?;
+            self.send_scrape(info_hashes, connection_id, transaction_id)
+                .await?;
+
+            if let Some(buf) = self
+                .recv_response(ACTION_SCRAPE, transaction_id, retransmissions)
+                .await?
+            {
+                return Self::parse_scrape(buf, info_hashes.len());
+            }
+            retransmit(&mut retransmissions)?;
+        }
+    }
+
+    async fn send_scrape(
+        &self,
+        info_hashes: &[InfoHash],
+        connection_id: i64,
+        transaction_id: i32,
+    ) -> anyhow::Result<()> {
+        let mut buf = BytesMut::with_capacity(16 + 20 * info_hashes.len());
+        buf.put_i64(connection_id); // connection_id
+        buf.put_i32(ACTION_SCRAPE); // action
+        buf.put_i32(transaction_id); // transaction_id
+        for info_hash in info_hashes {
+            buf.put_slice(info_hash); // info_hash
         }
+        self.socket.send(&buf).await?;
+        Result::Ok(())
+    }
 
-        let tid = buf.get_i32();
-        if tid != transaction_id {
+    fn parse_scrape(mut buf: BytesMut, num_info_hashes: usize) -> anyhow::Result<Vec<ScrapeStats>> {
+        if buf.len() < 12 * num_info_hashes {
             return Result::Err(anyhow::anyhow!(
-                "Invalid UDP announce response, expected transaction ID {} got {}",
-                transaction_id,
-                tid
+                "Invalid UDP scrape response, expected at least {} bytes got {}",
+                8 + 12 * num_info_hashes,
+                buf.len() + 8
             ));
         }
 
-        let _interval = buf.get_i32();
-        let _leechers = buf.get_i32();
-        let _seeders = buf.get_i32();
+        // Stats come back in the same order as the info hashes in the request
+        let stats = (0..num_info_hashes)
+            .map(|_| {
+                let complete = buf.get_u32();
+                let downloaded = buf.get_u32();
+                let incomplete = buf.get_u32();
+                ScrapeStats {
+                    complete,
+                    incomplete,
+                    downloaded,
+                }
+            })
+            .collect();
 
-        let mut peers = Vec::<Peer>::new();
-        while buf.remaining() >= 6 {
-            let ip = Ipv4Addr::new(buf.get_u8(), buf.get_u8(), buf.get_u8(), buf.get_u8());
-            let port = buf.get_u16();
-            peers.push(Peer { ip, port });
-        }
+        Result::Ok(stats)
+    }
 
-        Result::Ok(peers)
+    /// Waits for the response to a request until it is time to retransmit it, after 15 * 2^n
+    /// seconds where n is the number of retransmissions so far. Returns the rest of the response
+    /// after the action and transaction id, or None if the request timed out. Datagrams for other
+    /// transactions, such as late responses to an earlier request, are discarded.
+    async fn recv_response(
+        &mut self,
+        action: i32,
+        transaction_id: i32,
+        retransmissions: u32,
+    ) -> anyhow::Result<Option<BytesMut>> {
+        let timeout = Duration::from_secs(15 * 2u64.pow(retransmissions));
+        let deadline = Instant::now() + timeout;
+        loop {
+            let mut buf = match tokio::time::timeout_at(deadline, self.recv()).await {
+                Ok(buf) => buf?,
+                Err(_) => return Result::Ok(None),
+            };
+
+            if buf.len() < 8 {
+                continue;
+            }
+
+            let response_action = buf.get_i32();
+            let tid = buf.get_i32();
+            if tid != transaction_id {
+                continue;
+            }
+
+            if response_action == ACTION_ERROR {
+                return Result::Err(
+                    TrackerError {
+                        reason: String::from_utf8_lossy(&buf).into_owned(),
+                    }
+                    .into(),
+                );
+            }
+
+            if response_action != action {
+                return Result::Err(anyhow::anyhow!(
+                    "Invalid UDP tracker response, expected action {} got {}",
+                    action,
+                    response_action
+                ));
+            }
+
+            return Result::Ok(Some(buf));
+        }
     }
 
     async fn recv(&mut self) -> anyhow::Result<BytesMut> {
@@ -191,20 +312,263 @@ impl UdpTrackerConnection {
         buf.truncate(n);
         Result::Ok(buf)
     }
+}
+
+/// Counts a retransmission of a request that timed out, failing once we have waited as long as
+/// BEP 15 allows
+fn retransmit(retransmissions: &mut u32) -> anyhow::Result<()> {
+    if *retransmissions >= MAX_RETRANSMISSIONS {
+        return Result::Err(anyhow::anyhow!(
+            "UDP tracker did not respond after {} retransmissions",
+            retransmissions
+        ));
+    }
+
+    *retransmissions += 1;
+    Result::Ok(())
+}
+
+pub async fn announce(
+    url: &Url,
+    announce: &Announce,
+    limits: &RateLimits,
+) -> anyhow::Result<AnnounceResponse> {
+    let mut conn = UdpTrackerConnection::new(url).await?;
+    conn.announce(announce, limits).await
+}
 
-    // TODO: To be used once timeouts and retransmission is implemented
-    #[allow(dead_code)]
-    fn timeout(&self) -> u64 {
-        15 * 2u64.pow(self.backoff)
+pub async fn scrape(
+    url: &Url,
+    info_hashes: &[InfoHash],
+) -> anyhow::Result<HashMap<InfoHash, ScrapeStats>> {
+    let mut conn = UdpTrackerConnection::new(url).await?;
+    let mut stats = HashMap::new();
+    for batch in info_hashes.chunks(MAX_SCRAPE_INFO_HASHES) {
+        let batch_stats = conn.scrape(batch).await?;
+        stats.extend(batch.iter().copied().zip(batch_stats));
     }
+
+    Result::Ok(stats)
 }
 
-pub async fn get_peers(
-    peer_id: &PeerID,
-    port: u16,
-    torrent: &Torrent,
-) -> anyhow::Result<Vec<Peer>> {
-    let mut conn = UdpTrackerConnection::new(torrent).await?;
-    conn.connect().await?;
-    conn.announce(peer_id, port).await
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::tracker::AnnounceEvent;
+
+    const CONNECTION_ID: i64 = 0x1234_5678;
+
+    /// Runs a tracker on a loopback socket that answers each request with the datagrams `respond`
+    /// returns for it, returning the tracker's URL
+    async fn fake_tracker<F>(mut respond: F) -> Url
+    where
+        F: FnMut(&[u8]) -> Vec<Vec<u8>> + Send + 'static,
+    {
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let url = Url::parse(&format!("udp://{}", socket.local_addr().unwrap())).unwrap();
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; BUFFER_SIZE];
+            loop {
+                let (len, addr) = socket.recv_from(&mut buf).await.unwrap();
+                for response in respond(&buf[..len]) {
+                    socket.send_to(&response, addr).await.unwrap();
+                }
+            }
+        });
+        url
+    }
+
+    /// The start of a response to `request`, echoing its transaction id
+    fn response_header(action: i32, request: &[u8]) -> Vec<u8> {
+        let mut buf = action.to_be_bytes().to_vec();
+        buf.extend_from_slice(&request[12..16]);
+        buf
+    }
+
+    /// Answers connect requests, leaving everything else to `respond`
+    fn connecting<F>(mut respond: F) -> impl FnMut(&[u8]) -> Vec<Vec<u8>> + Send + 'static
+    where
+        F: FnMut(&[u8]) -> Vec<Vec<u8>> + Send + 'static,
+    {
+        move |request| {
+            if request[8..12] == ACTION_CONNECT.to_be_bytes() {
+                assert_eq!(request[..8], PROTOCOL_ID.to_be_bytes());
+                let mut response = response_header(ACTION_CONNECT, request);
+                response.extend_from_slice(&CONNECTION_ID.to_be_bytes());
+                vec![response]
+            } else {
+                assert_eq!(request[..8], CONNECTION_ID.to_be_bytes());
+                respond(request)
+            }
+        }
+    }
+
+    fn test_announce() -> Announce {
+        Announce {
+            info_hash: [0xab; 20],
+            peer_id: *b"-RT0001-123456789012",
+            port: 6881,
+            uploaded: 1,
+            downloaded: 2,
+            left: 3,
+            event: AnnounceEvent::Started,
+        }
+    }
+
+    fn announce_response(request: &[u8]) -> Vec<u8> {
+        assert_eq!(request.len(), ANNOUNCE_REQUEST_LEN);
+        assert_eq!(request[8..12], ACTION_ANNOUNCE.to_be_bytes());
+        assert_eq!(request[16..36], [0xab; 20]);
+        assert_eq!(request[96..98], 6881u16.to_be_bytes());
+
+        let mut response = response_header(ACTION_ANNOUNCE, request);
+        response.extend_from_slice(&1800u32.to_be_bytes()); // interval
+        response.extend_from_slice(&2u32.to_be_bytes()); // leechers
+        response.extend_from_slice(&3u32.to_be_bytes()); // seeders
+        response.extend_from_slice(&[10, 0, 0, 1, 0x1a, 0xe1]);
+        response.extend_from_slice(&[10, 0, 0, 2, 0x1a, 0xe2]);
+        response
+    }
+
+    #[tokio::test]
+    async fn announces() {
+        let url = fake_tracker(connecting(|request| vec![announce_response(request)])).await;
+        let response = announce(&url, &test_announce(), &RateLimits::default())
+            .await
+            .unwrap();
+
+        assert_eq!(response.interval, Some(Duration::from_secs(1800)));
+        assert_eq!(response.leechers, Some(2));
+        assert_eq!(response.seeders, Some(3));
+        let peers: Vec<SocketAddr> = response.peers.iter().map(|peer| peer.addr).collect();
+        assert_eq!(
+            peers,
+            vec![
+                "10.0.0.1:6881".parse().unwrap(),
+                "10.0.0.2:6882".parse().unwrap()
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn ignores_responses_to_other_transactions() {
+        let url = fake_tracker(connecting(|request| {
+            let mut stray = announce_response(request);
+            stray[4] ^= 0xff;
+            vec![stray, announce_response(request)]
+        }))
+        .await;
+        let response = announce(&url, &test_announce(), &RateLimits::default())
+            .await
+            .unwrap();
+        assert_eq!(response.peers.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn returns_tracker_errors() {
+        let url = fake_tracker(connecting(|request| {
+            let mut response = response_header(ACTION_ERROR, request);
+            response.extend_from_slice(b"unregistered torrent");
+            vec![response]
+        }))
+        .await;
+        let error = announce(&url, &test_announce(), &RateLimits::default())
+            .await
+            .unwrap_err();
+        let error = error.downcast::<TrackerError>().unwrap();
+        assert_eq!(error.reason, "unregistered torrent");
+    }
+
+    #[tokio::test]
+    async fn rejects_short_announce_responses() {
+        let url = fake_tracker(connecting(|request| {
+            vec![response_header(ACTION_ANNOUNCE, request)]
+        }))
+        .await;
+        assert!(announce(&url, &test_announce(), &RateLimits::default())
+            .await
+            .is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retransmits_unanswered_requests() {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+        let mut answer = connecting(|request| vec![announce_response(request)]);
+        // Drop the first request, as if it was lost on the way
+        let url = fake_tracker(
+            move |request| match counter.fetch_add(1, Ordering::SeqCst) {
+                0 => Vec::new(),
+                _ => answer(request),
+            },
+        )
+        .await;
+
+        let started = Instant::now();
+        let response = announce(&url, &test_announce(), &RateLimits::default())
+            .await
+            .unwrap();
+        assert_eq!(response.peers.len(), 2);
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+        assert!(started.elapsed() >= Duration::from_secs(15));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn gives_up_on_unresponsive_trackers() {
+        let url = fake_tracker(|_| Vec::new()).await;
+        let error = announce(&url, &test_announce(), &RateLimits::default())
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("8 retransmissions"));
+    }
+
+    /// Answers scrape requests with stats derived from each info hash's first byte
+    fn scrape_response(request: &[u8]) -> Vec<u8> {
+        assert_eq!(request[8..12], ACTION_SCRAPE.to_be_bytes());
+        let mut response = response_header(ACTION_SCRAPE, request);
+        for info_hash in request[16..].chunks(20) {
+            let n = info_hash[0] as u32;
+            response.extend_from_slice(&n.to_be_bytes()); // complete
+            response.extend_from_slice(&(n * 10).to_be_bytes()); // downloaded
+            response.extend_from_slice(&(n * 2).to_be_bytes()); // incomplete
+        }
+        response
+    }
+
+    #[tokio::test]
+    async fn scrapes_in_batches() {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+        let url = fake_tracker(connecting(move |request| {
+            assert!(request.len() <= 16 + 20 * MAX_SCRAPE_INFO_HASHES);
+            counter.fetch_add(1, Ordering::SeqCst);
+            vec![scrape_response(request)]
+        }))
+        .await;
+
+        let info_hashes: Vec<InfoHash> = (0..=MAX_SCRAPE_INFO_HASHES as u8)
+            .map(|i| [i; 20])
+            .collect();
+        let stats = scrape(&url, &info_hashes).await.unwrap();
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+        assert_eq!(stats.len(), info_hashes.len());
+        assert_eq!(
+            stats[&[7; 20]],
+            ScrapeStats {
+                complete: 7,
+                incomplete: 14,
+                downloaded: 70,
+            }
+        );
+    }
+
+    #[test]
+    fn rejects_short_scrape_responses() {
+        let buf = BytesMut::from(&[0u8; 12 * 2 - 1][..]);
+        assert!(UdpTrackerConnection::parse_scrape(buf, 2).is_err());
+    }
 }
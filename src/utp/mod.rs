@@ -0,0 +1,190 @@
+mod packet;
+mod stream;
+
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use rand::Rng;
+use tokio::net::UdpSocket;
+use tokio::sync::{mpsc, oneshot};
+use tracing::{info, warn};
+
+use self::packet::{Packet, PacketType};
+use self::stream::Connection;
+pub use self::stream::UtpStream;
+
+// Big enough for any packet we accept, and for DHT messages passed on to the DHT
+const BUFFER_SIZE: usize = 4096;
+
+// How long to wait for a peer to answer our SYN
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// A datagram that isn't a uTP packet, with who sent it
+pub type Datagram = (Vec<u8>, SocketAddr);
+
+/// A UDP socket carrying uTP connections (BEP 29), the congestion controlled transport that backs
+/// off when other traffic shares the link. Datagrams that aren't uTP packets are passed on, so
+/// the DHT can share the socket.
+pub struct UtpSocket {
+    socket: Arc<UdpSocket>,
+    /// The connections on the socket, by peer address and the connection id the peer sends to
+    connections: Mutex<HashMap<(SocketAddr, u16), mpsc::UnboundedSender<Packet>>>,
+    incoming: tokio::sync::Mutex<mpsc::UnboundedReceiver<UtpStream>>,
+    incoming_sender: mpsc::UnboundedSender<UtpStream>,
+    other: Mutex<Option<mpsc::UnboundedSender<Datagram>>>,
+}
+
+impl UtpSocket {
+    /// Binds the socket and starts receiving packets in the background
+    pub async fn bind(port: u16) -> anyhow::Result<Arc<Self>> {
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, port)).await?;
+        info!("uTP listening on port {}", port);
+
+        let (incoming_sender, incoming) = mpsc::unbounded_channel();
+        let utp = Arc::new(Self {
+            socket: Arc::new(socket),
+            connections: Mutex::new(HashMap::new()),
+            incoming: tokio::sync::Mutex::new(incoming),
+            incoming_sender,
+            other: Mutex::new(None),
+        });
+        tokio::spawn(utp.clone().run());
+
+        Result::Ok(utp)
+    }
+
+    /// Returns the underlying socket for sending, and the datagrams received on it that aren't
+    /// uTP packets
+    pub fn share(&self) -> (Arc<UdpSocket>, mpsc::UnboundedReceiver<Datagram>) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        *self.other.lock().unwrap() = Some(sender);
+        (self.socket.clone(), receiver)
+    }
+
+    /// Connects to a peer
+    pub async fn connect(self: &Arc<Self>, addr: SocketAddr) -> anyhow::Result<UtpStream> {
+        let (recv_id, packets) = {
+            let mut connections = self.connections.lock().unwrap();
+            let mut rng = rand::thread_rng();
+            loop {
+                let recv_id: u16 = rng.gen();
+                // The peer sends to our id and we send to the one after it
+                if connections.contains_key(&(addr, recv_id))
+                    || connections.contains_key(&(addr, recv_id.wrapping_add(1)))
+                {
+                    continue;
+                }
+                let (sender, packets) = mpsc::unbounded_channel();
+                connections.insert((addr, recv_id), sender);
+                break (recv_id, packets);
+            }
+        };
+
+        let (connected_sender, connected) = oneshot::channel();
+        let (connection, stream) = Connection::new(
+            self.socket.clone(),
+            addr,
+            recv_id.wrapping_add(1),
+            1,
+            0,
+            packets,
+            Some(connected_sender),
+        );
+        self.spawn(connection, (addr, recv_id));
+
+        match tokio::time::timeout(CONNECT_TIMEOUT, connected).await {
+            Ok(Ok(())) => Result::Ok(stream),
+            Ok(Err(_)) => Result::Err(anyhow::anyhow!("Connection refused")),
+            Err(_) => Result::Err(anyhow::anyhow!("Connection timed out")),
+        }
+    }
+
+    /// Waits for a peer to connect to us
+    pub async fn accept(&self) -> Option<UtpStream> {
+        self.incoming.lock().await.recv().await
+    }
+
+    fn spawn(self: &Arc<Self>, connection: Connection, key: (SocketAddr, u16)) {
+        let utp = self.clone();
+        tokio::spawn(async move {
+            connection.run().await;
+            utp.connections.lock().unwrap().remove(&key);
+        });
+    }
+
+    async fn run(self: Arc<Self>) {
+        let mut buf = vec![0u8; BUFFER_SIZE];
+        loop {
+            let (len, addr) = match self.socket.recv_from(&mut buf).await {
+                Ok(received) => received,
+                Err(error) => {
+                    warn!("Failed to receive from uTP socket: {}", error);
+                    continue;
+                }
+            };
+
+            match Packet::decode(&buf[..len]) {
+                Some(packet) => {
+                    if let Some(packet) = self.route(addr, packet) {
+                        self.reset(addr, &packet).await;
+                    }
+                }
+                None => {
+                    if let Some(other) = &*self.other.lock().unwrap() {
+                        let _ = other.send((buf[..len].to_vec(), addr));
+                    }
+                }
+            }
+        }
+    }
+
+    /// Passes a packet on to its connection, setting up a new connection for a SYN. Returns the
+    /// packet if there is no connection for it.
+    fn route(self: &Arc<Self>, addr: SocketAddr, packet: Packet) -> Option<Packet> {
+        // A SYN carries the id the peer will send to, which is one less than the one it receives on
+        let recv_id = match packet.packet_type {
+            PacketType::Syn => packet.connection_id.wrapping_add(1),
+            _ => packet.connection_id,
+        };
+
+        let mut connections = self.connections.lock().unwrap();
+        if let Some(connection) = connections.get(&(addr, recv_id)) {
+            let _ = connection.send(packet);
+            return None;
+        }
+        if packet.packet_type != PacketType::Syn {
+            return Some(packet);
+        }
+
+        let (sender, packets) = mpsc::unbounded_channel();
+        connections.insert((addr, recv_id), sender);
+        let (connection, stream) = Connection::new(
+            self.socket.clone(),
+            addr,
+            packet.connection_id,
+            rand::thread_rng().gen(),
+            packet.seq_nr,
+            packets,
+            None,
+        );
+        self.spawn(connection, (addr, recv_id));
+        let _ = self.incoming_sender.send(stream);
+        None
+    }
+
+    /// Tells a peer the connection a packet was sent on is gone, unless it is telling us the same
+    async fn reset(&self, addr: SocketAddr, packet: &Packet) {
+        if packet.packet_type == PacketType::Reset {
+            return;
+        }
+        let reset = Packet::new(
+            PacketType::Reset,
+            packet.connection_id,
+            rand::thread_rng().gen(),
+            packet.seq_nr,
+        );
+        let _ = self.socket.send_to(&reset.encode(), addr).await;
+    }
+}
@@ -0,0 +1,239 @@
+use std::sync::OnceLock;
+use std::time::Instant;
+
+use bytes::{Buf, BufMut, BytesMut};
+
+// The uTP version we speak, sent in the low bits of the first byte
+const VERSION: u8 = 1;
+
+// The length of a packet header without extensions
+pub const HEADER_LEN: usize = 20;
+
+// Extension types, the first marking the end of the extension list
+const EXTENSION_NONE: u8 = 0;
+const EXTENSION_SELECTIVE_ACK: u8 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PacketType {
+    Data,
+    Fin,
+    State,
+    Reset,
+    Syn,
+}
+
+impl PacketType {
+    fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(Self::Data),
+            1 => Some(Self::Fin),
+            2 => Some(Self::State),
+            3 => Some(Self::Reset),
+            4 => Some(Self::Syn),
+            _ => None,
+        }
+    }
+
+    fn to_u8(self) -> u8 {
+        match self {
+            Self::Data => 0,
+            Self::Fin => 1,
+            Self::State => 2,
+            Self::Reset => 3,
+            Self::Syn => 4,
+        }
+    }
+}
+
+/// A uTP packet (BEP 29). Selective acks are the only extension understood, others are skipped.
+#[derive(Debug, Clone)]
+pub struct Packet {
+    pub packet_type: PacketType,
+    pub connection_id: u16,
+    /// When the packet was sent, in microseconds on the sender's clock
+    pub timestamp: u32,
+    /// The one way delay of the last packet the sender received from us, in microseconds
+    pub timestamp_difference: u32,
+    /// The bytes the sender can still receive
+    pub wnd_size: u32,
+    pub seq_nr: u16,
+    pub ack_nr: u16,
+    /// A bitmask of the packets received after `ack_nr + 1`, starting with `ack_nr + 2` in the
+    /// least significant bit of the first byte
+    pub selective_ack: Option<Vec<u8>>,
+    pub payload: Vec<u8>,
+}
+
+impl Packet {
+    pub fn new(packet_type: PacketType, connection_id: u16, seq_nr: u16, ack_nr: u16) -> Self {
+        Self {
+            packet_type,
+            connection_id,
+            timestamp: 0,
+            timestamp_difference: 0,
+            wnd_size: 0,
+            seq_nr,
+            ack_nr,
+            selective_ack: None,
+            payload: Vec::new(),
+        }
+    }
+
+    /// Parses a packet, returning None for anything that isn't a uTP packet we understand
+    pub fn decode(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < HEADER_LEN || bytes[0] & 0x0f != VERSION {
+            return None;
+        }
+
+        let mut buf = bytes;
+        let packet_type = PacketType::from_u8(buf.get_u8() >> 4)?;
+        let mut extension = buf.get_u8();
+        let connection_id = buf.get_u16();
+        let timestamp = buf.get_u32();
+        let timestamp_difference = buf.get_u32();
+        let wnd_size = buf.get_u32();
+        let seq_nr = buf.get_u16();
+        let ack_nr = buf.get_u16();
+
+        let mut selective_ack = None;
+        while extension != EXTENSION_NONE {
+            if buf.remaining() < 2 {
+                return None;
+            }
+            let next = buf.get_u8();
+            let len = buf.get_u8() as usize;
+            if buf.remaining() < len {
+                return None;
+            }
+            if extension == EXTENSION_SELECTIVE_ACK {
+                selective_ack = Some(buf[..len].to_vec());
+            }
+            buf.advance(len);
+            extension = next;
+        }
+
+        Some(Self {
+            packet_type,
+            connection_id,
+            timestamp,
+            timestamp_difference,
+            wnd_size,
+            seq_nr,
+            ack_nr,
+            selective_ack,
+            payload: buf.to_vec(),
+        })
+    }
+
+    pub fn encode(&self) -> BytesMut {
+        let mut buf = BytesMut::with_capacity(HEADER_LEN + self.payload.len());
+        buf.put_u8(self.packet_type.to_u8() << 4 | VERSION);
+        match self.selective_ack {
+            Some(_) => buf.put_u8(EXTENSION_SELECTIVE_ACK),
+            None => buf.put_u8(EXTENSION_NONE),
+        }
+        buf.put_u16(self.connection_id);
+        buf.put_u32(self.timestamp);
+        buf.put_u32(self.timestamp_difference);
+        buf.put_u32(self.wnd_size);
+        buf.put_u16(self.seq_nr);
+        buf.put_u16(self.ack_nr);
+        if let Some(selective_ack) = &self.selective_ack {
+            buf.put_u8(EXTENSION_NONE);
+            buf.put_u8(selective_ack.len() as u8);
+            buf.put_slice(selective_ack);
+        }
+        buf.put_slice(&self.payload);
+        buf
+    }
+}
+
+/// The current time in microseconds, as sent in packet timestamps. Only differences between
+/// timestamps mean anything, so the clock starts whenever it is first used.
+pub fn timestamp() -> u32 {
+    static START: OnceLock<Instant> = OnceLock::new();
+    START.get_or_init(Instant::now).elapsed().as_micros() as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn data_packet() -> Packet {
+        let mut packet = Packet::new(PacketType::Data, 0x1234, 10, 7);
+        packet.timestamp = 0x01020304;
+        packet.timestamp_difference = 0x05060708;
+        packet.wnd_size = 0x0000ffff;
+        packet.payload = b"payload".to_vec();
+        packet
+    }
+
+    #[test]
+    fn encodes_headers() {
+        let bytes = data_packet().encode();
+        assert_eq!(
+            bytes[..HEADER_LEN],
+            [
+                0x01, 0x00, 0x12, 0x34, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x00, 0x00,
+                0xff, 0xff, 0x00, 0x0a, 0x00, 0x07
+            ]
+        );
+        assert_eq!(&bytes[HEADER_LEN..], b"payload");
+    }
+
+    #[test]
+    fn round_trips_selective_acks() {
+        let mut packet = data_packet();
+        packet.packet_type = PacketType::State;
+        packet.selective_ack = Some(vec![0b0000_0101, 0, 0, 0x80]);
+
+        let bytes = packet.encode();
+        assert_eq!(bytes[0], 0x21);
+        assert_eq!(bytes[1], EXTENSION_SELECTIVE_ACK);
+        assert_eq!(bytes[HEADER_LEN..HEADER_LEN + 2], [EXTENSION_NONE, 4]);
+
+        let decoded = Packet::decode(&bytes).unwrap();
+        assert_eq!(decoded.packet_type, PacketType::State);
+        assert_eq!(decoded.connection_id, 0x1234);
+        assert_eq!(decoded.timestamp, 0x01020304);
+        assert_eq!(decoded.timestamp_difference, 0x05060708);
+        assert_eq!(decoded.wnd_size, 0xffff);
+        assert_eq!((decoded.seq_nr, decoded.ack_nr), (10, 7));
+        assert_eq!(decoded.selective_ack, packet.selective_ack);
+        assert_eq!(decoded.payload, b"payload");
+    }
+
+    #[test]
+    fn skips_unknown_extensions() {
+        let mut bytes = Packet::new(PacketType::Data, 1, 2, 3).encode().to_vec();
+        // An unknown extension followed by a selective ack, then the payload
+        bytes[1] = 2;
+        bytes.extend_from_slice(&[EXTENSION_SELECTIVE_ACK, 2, 0xaa, 0xbb]);
+        bytes.extend_from_slice(&[EXTENSION_NONE, 4, 1, 0, 0, 0]);
+        bytes.extend_from_slice(b"data");
+
+        let decoded = Packet::decode(&bytes).unwrap();
+        assert_eq!(decoded.selective_ack, Some(vec![1, 0, 0, 0]));
+        assert_eq!(decoded.payload, b"data");
+    }
+
+    #[test]
+    fn rejects_invalid_packets() {
+        let bytes = data_packet().encode();
+        assert!(Packet::decode(&bytes[..HEADER_LEN - 1]).is_none());
+
+        let mut wrong_version = bytes.to_vec();
+        wrong_version[0] = 0x02;
+        assert!(Packet::decode(&wrong_version).is_none());
+
+        let mut wrong_type = bytes.to_vec();
+        wrong_type[0] = 0x51;
+        assert!(Packet::decode(&wrong_type).is_none());
+
+        // A selective ack longer than the rest of the packet
+        let mut packet = Packet::new(PacketType::State, 1, 2, 3);
+        packet.selective_ack = Some(vec![0; 4]);
+        let bytes = packet.encode();
+        assert!(Packet::decode(&bytes[..bytes.len() - 1]).is_none());
+    }
+}
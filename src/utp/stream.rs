@@ -0,0 +1,726 @@
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::UdpSocket;
+use tokio::sync::{mpsc, oneshot, Notify};
+use tokio::time::Instant;
+
+use super::packet::{timestamp, Packet, PacketType, HEADER_LEN};
+
+// The largest payload we put in a packet, keeping packets under common path MTUs
+const MAX_PAYLOAD: usize = 1400 - HEADER_LEN;
+
+// The most data buffered on each side of a connection, which also caps the congestion window
+const BUFFER_SIZE: usize = 1024 * 1024;
+
+// The queuing delay LEDBAT aims for, in microseconds. Above it we back off, below it we speed up.
+const TARGET_DELAY: f64 = 100_000.0;
+
+// How much the congestion window can grow by each round trip
+const MAX_CWND_INCREASE: f64 = 3000.0;
+
+// The smallest congestion window, which still lets a packet through each round trip
+const MIN_CWND: f64 = MAX_PAYLOAD as f64;
+
+// How long the base delay is remembered for. The lowest delay seen over the last two intervals is
+// taken as the delay of the path with empty queues.
+const BASE_DELAY_INTERVAL: Duration = Duration::from_secs(60);
+
+// Retransmission timeouts, which double each time the same packet times out
+const INITIAL_TIMEOUT: Duration = Duration::from_secs(1);
+const MIN_TIMEOUT: Duration = Duration::from_millis(500);
+const MAX_TIMEOUT: Duration = Duration::from_secs(30);
+
+// How many times a packet is sent again before giving up on the connection
+const MAX_RETRANSMITS: u32 = 5;
+
+// How many acks for the same packet suggest the packet after it was lost
+const DUPLICATE_ACKS: u32 = 3;
+
+/// State shared between a stream and the task running its connection
+#[derive(Default)]
+struct Shared {
+    /// Data received in order, waiting to be read
+    read_buf: VecDeque<u8>,
+    read_waker: Option<Waker>,
+    /// Data written, waiting to be sent
+    write_buf: VecDeque<u8>,
+    write_waker: Option<Waker>,
+    /// Set once the peer has finished sending
+    eof: bool,
+    /// Set once we have finished writing
+    closing: bool,
+    /// Set once the stream is dropped, so nobody will read what the peer sends
+    dropped: bool,
+    /// Set once the connection is over, with the error that ended it if it failed
+    closed: bool,
+    error: Option<io::ErrorKind>,
+}
+
+impl Shared {
+    fn wake(&mut self) {
+        if let Some(waker) = self.read_waker.take() {
+            waker.wake();
+        }
+        if let Some(waker) = self.write_waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+struct Inner {
+    shared: Mutex<Shared>,
+    /// Wakes the connection task when there is data to send or the stream is closed
+    notify: Notify,
+}
+
+/// A uTP connection to a peer, read and written like a TCP stream
+pub struct UtpStream {
+    addr: SocketAddr,
+    inner: Arc<Inner>,
+}
+
+impl UtpStream {
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+impl AsyncRead for UtpStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let mut shared = self.inner.shared.lock().unwrap();
+        if !shared.read_buf.is_empty() {
+            let len = usize::min(buf.remaining(), shared.read_buf.len());
+            let (front, back) = shared.read_buf.as_slices();
+            let from_front = usize::min(len, front.len());
+            buf.put_slice(&front[..from_front]);
+            buf.put_slice(&back[..len - from_front]);
+            shared.read_buf.drain(..len);
+            return Poll::Ready(Ok(()));
+        }
+
+        if let Some(kind) = shared.error {
+            return Poll::Ready(Err(kind.into()));
+        }
+        if shared.eof || shared.closed {
+            return Poll::Ready(Ok(()));
+        }
+
+        shared.read_waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl AsyncWrite for UtpStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut shared = self.inner.shared.lock().unwrap();
+        if let Some(kind) = shared.error {
+            return Poll::Ready(Err(kind.into()));
+        }
+        if shared.closing || shared.closed {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+
+        let len = usize::min(buf.len(), BUFFER_SIZE - shared.write_buf.len());
+        if len == 0 {
+            shared.write_waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        shared.write_buf.extend(&buf[..len]);
+        drop(shared);
+
+        self.inner.notify.notify_one();
+        Poll::Ready(Ok(len))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.inner.shared.lock().unwrap().closing = true;
+        self.inner.notify.notify_one();
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for UtpStream {
+    fn drop(&mut self) {
+        let mut shared = self.inner.shared.lock().unwrap();
+        shared.closing = true;
+        shared.dropped = true;
+        drop(shared);
+        self.inner.notify.notify_one();
+    }
+}
+
+/// A packet we sent that hasn't been acked yet
+struct SentPacket {
+    packet: Packet,
+    sent_at: Instant,
+    transmissions: u32,
+    /// Set once the peer has told us it received the packet in a selective ack
+    selective_acked: bool,
+}
+
+/// The lowest one way delay seen recently, taken as the delay of the path with empty queues
+struct BaseDelay {
+    current: Option<u32>,
+    previous: Option<u32>,
+    rotated: Instant,
+}
+
+impl BaseDelay {
+    fn new() -> Self {
+        Self {
+            current: None,
+            previous: None,
+            rotated: Instant::now(),
+        }
+    }
+
+    /// Records a delay sample, returning the base delay
+    fn update(&mut self, delay: u32) -> u32 {
+        if self.rotated.elapsed() >= BASE_DELAY_INTERVAL {
+            self.previous = self.current.take();
+            self.rotated = Instant::now();
+        }
+        self.current = Some(self.current.map_or(delay, |current| current.min(delay)));
+        self.previous
+            .map_or(delay, |previous| previous.min(delay))
+            .min(self.current.unwrap())
+    }
+}
+
+/// The sending and receiving state of a connection, driven by its own task
+pub struct Connection {
+    socket: Arc<UdpSocket>,
+    addr: SocketAddr,
+    inner: Arc<Inner>,
+    packets: mpsc::UnboundedReceiver<Packet>,
+    send_id: u16,
+    /// The sequence number of the next packet we send
+    seq_nr: u16,
+    /// The sequence number of the last packet we received in order
+    ack_nr: u16,
+    /// Set while we wait for the peer to answer our SYN, with who to tell once it does
+    connecting: Option<oneshot::Sender<()>>,
+    unacked: VecDeque<SentPacket>,
+    /// Payload bytes sent but not yet acked
+    in_flight: usize,
+    /// Packets received ahead of a gap, by sequence number
+    out_of_order: HashMap<u16, Packet>,
+    fin_sent: bool,
+    need_ack: bool,
+    /// The congestion window in bytes
+    cwnd: f64,
+    peer_wnd: u32,
+    base_delay: BaseDelay,
+    /// The one way delay of the last packet the peer sent us, echoed back in our packets
+    reply_delay: u32,
+    rtt: Option<Duration>,
+    rtt_var: Duration,
+    rto: Duration,
+    timeout_at: Option<Instant>,
+    retransmits: u32,
+    last_ack: u16,
+    duplicate_acks: u32,
+    /// Set while recovering from a lost packet, with the last packet sent when the loss was found
+    recovery: Option<u16>,
+}
+
+impl Connection {
+    /// Creates a connection and the stream used to read and write it. A connection created with
+    /// `connecting` sends a SYN when it starts, and reports when the peer answers it.
+    pub fn new(
+        socket: Arc<UdpSocket>,
+        addr: SocketAddr,
+        send_id: u16,
+        seq_nr: u16,
+        ack_nr: u16,
+        packets: mpsc::UnboundedReceiver<Packet>,
+        connecting: Option<oneshot::Sender<()>>,
+    ) -> (Self, UtpStream) {
+        let inner = Arc::new(Inner {
+            shared: Mutex::new(Shared::default()),
+            notify: Notify::new(),
+        });
+        let stream = UtpStream {
+            addr,
+            inner: inner.clone(),
+        };
+
+        let connection = Self {
+            socket,
+            addr,
+            inner,
+            packets,
+            send_id,
+            seq_nr,
+            ack_nr,
+            // A peer that sent us a SYN is waiting for us to ack it
+            need_ack: connecting.is_none(),
+            connecting,
+            unacked: VecDeque::new(),
+            in_flight: 0,
+            out_of_order: HashMap::new(),
+            fin_sent: false,
+            cwnd: MIN_CWND * 2.0,
+            peer_wnd: BUFFER_SIZE as u32,
+            base_delay: BaseDelay::new(),
+            reply_delay: 0,
+            rtt: None,
+            rtt_var: Duration::ZERO,
+            rto: INITIAL_TIMEOUT,
+            timeout_at: None,
+            retransmits: 0,
+            last_ack: ack_nr,
+            duplicate_acks: 0,
+            recovery: None,
+        };
+        (connection, stream)
+    }
+
+    /// Runs the connection until both sides are done with it or it fails
+    pub async fn run(mut self) {
+        let result = self.drive().await;
+
+        let mut shared = self.inner.shared.lock().unwrap();
+        shared.closed = true;
+        if let Err(error) = result {
+            shared.error = Some(error.kind());
+        }
+        shared.wake();
+    }
+
+    async fn drive(&mut self) -> io::Result<()> {
+        if self.connecting.is_some() {
+            // The SYN carries the id the peer will send to, which is one less than ours
+            let syn = Packet::new(
+                PacketType::Syn,
+                self.send_id.wrapping_sub(1),
+                self.seq_nr,
+                0,
+            );
+            self.seq_nr = self.seq_nr.wrapping_add(1);
+            self.transmit(syn).await?;
+        }
+
+        loop {
+            self.send_data().await?;
+            if self.need_ack {
+                self.send_state().await?;
+            }
+            if self.is_finished() {
+                return Result::Ok(());
+            }
+
+            let timeout_at = self.timeout_at;
+            tokio::select! {
+                packet = self.packets.recv() => {
+                    let Some(packet) = packet else {
+                        return Result::Err(io::ErrorKind::ConnectionAborted.into());
+                    };
+                    self.handle_packet(packet).await?;
+                    // Handle everything that arrived together before acking it
+                    while let Ok(packet) = self.packets.try_recv() {
+                        self.handle_packet(packet).await?;
+                    }
+                }
+                _ = self.inner.notify.notified() => {}
+                _ = tokio::time::sleep_until(timeout_at.unwrap_or_else(Instant::now)),
+                    if timeout_at.is_some() =>
+                {
+                    self.handle_timeout().await?;
+                }
+            }
+        }
+    }
+
+    fn is_finished(&self) -> bool {
+        let shared = self.inner.shared.lock().unwrap();
+        if shared.dropped && self.connecting.is_some() {
+            return true;
+        }
+        (shared.dropped || shared.eof) && self.fin_sent && self.unacked.is_empty()
+    }
+
+    async fn handle_packet(&mut self, packet: Packet) -> io::Result<()> {
+        self.peer_wnd = packet.wnd_size;
+        self.reply_delay = timestamp().wrapping_sub(packet.timestamp);
+
+        match packet.packet_type {
+            PacketType::Reset => return Result::Err(io::ErrorKind::ConnectionReset.into()),
+            // The peer didn't get our answer to its SYN
+            PacketType::Syn => {
+                self.need_ack = true;
+                return Result::Ok(());
+            }
+            _ => {}
+        }
+
+        // The first packet from the peer tells us where its sequence numbers start
+        if let Some(connecting) = self.connecting.take() {
+            self.ack_nr = packet.seq_nr.wrapping_sub(1);
+            let _ = connecting.send(());
+        }
+
+        self.handle_ack(&packet).await?;
+        if matches!(packet.packet_type, PacketType::Data | PacketType::Fin) {
+            self.handle_data(packet);
+        }
+
+        Result::Ok(())
+    }
+
+    async fn handle_ack(&mut self, packet: &Packet) -> io::Result<()> {
+        let mut acked_bytes = 0;
+        let mut acked = false;
+        while let Some(sent) = self.unacked.front() {
+            if !seq_less_or_equal(sent.packet.seq_nr, packet.ack_nr) {
+                break;
+            }
+            let sent = self.unacked.pop_front().unwrap();
+            acked = true;
+            if !sent.selective_acked {
+                acked_bytes += sent.packet.payload.len();
+            }
+            // Only packets sent once give an unambiguous round trip time
+            if sent.transmissions == 1 {
+                self.update_rtt(sent.sent_at.elapsed());
+            }
+        }
+        if let Some(selective_ack) = &packet.selective_ack {
+            acked_bytes += self.handle_selective_ack(packet.ack_nr, selective_ack);
+        }
+        self.in_flight -= acked_bytes;
+        if acked_bytes > 0 {
+            self.update_cwnd(packet.timestamp_difference, acked_bytes);
+        }
+
+        if acked {
+            self.retransmits = 0;
+            self.duplicate_acks = 0;
+            self.rto = self.estimated_rto();
+            self.timeout_at = if self.unacked.is_empty() {
+                None
+            } else {
+                Some(Instant::now() + self.rto)
+            };
+
+            // An ack that stops short of what was sent before the loss means the packet after it
+            // was lost as well, so send it again without waiting for more acks
+            if let Some(recovery) = self.recovery {
+                if seq_less_or_equal(recovery, packet.ack_nr) {
+                    self.recovery = None;
+                } else if !self.unacked[0].selective_acked {
+                    self.retransmit(0).await?;
+                }
+            }
+        } else if packet.packet_type == PacketType::State
+            && packet.ack_nr == self.last_ack
+            && !self.unacked.is_empty()
+        {
+            self.duplicate_acks += 1;
+            if self.duplicate_acks == DUPLICATE_ACKS && self.recovery.is_none() {
+                self.enter_recovery();
+                self.retransmit(0).await?;
+            }
+        }
+        self.last_ack = packet.ack_nr;
+
+        self.retransmit_lost().await
+    }
+
+    /// Marks the packets the peer received after a gap, returning how many bytes they carried
+    fn handle_selective_ack(&mut self, ack_nr: u16, mask: &[u8]) -> usize {
+        let Some(first) = self.unacked.front().map(|sent| sent.packet.seq_nr) else {
+            return 0;
+        };
+
+        let mut acked_bytes = 0;
+        for bit in 0..mask.len() * 8 {
+            if mask[bit / 8] & (1 << (bit % 8)) == 0 {
+                continue;
+            }
+            // Unacked packets have consecutive sequence numbers
+            let seq_nr = ack_nr.wrapping_add(2).wrapping_add(bit as u16);
+            let index = seq_nr.wrapping_sub(first) as usize;
+            if let Some(sent) = self.unacked.get_mut(index) {
+                if !sent.selective_acked {
+                    sent.selective_acked = true;
+                    acked_bytes += sent.packet.payload.len();
+                }
+            }
+        }
+        acked_bytes
+    }
+
+    /// Sends again the packets the peer has received several packets after, which were most
+    /// likely lost. Each is only sent again this way once, after that it is up to the timeout.
+    async fn retransmit_lost(&mut self) -> io::Result<()> {
+        let mut lost = Vec::new();
+        let mut acked_after = 0;
+        for (index, sent) in self.unacked.iter().enumerate().rev() {
+            if sent.selective_acked {
+                acked_after += 1;
+            } else if acked_after >= DUPLICATE_ACKS && sent.transmissions == 1 {
+                lost.push(index);
+            }
+        }
+        if lost.is_empty() {
+            return Result::Ok(());
+        }
+
+        if self.recovery.is_none() {
+            self.enter_recovery();
+        }
+        for index in lost.into_iter().rev() {
+            self.retransmit(index).await?;
+        }
+
+        Result::Ok(())
+    }
+
+    /// Halves the congestion window after a loss, once for everything in flight when it was found
+    fn enter_recovery(&mut self) {
+        self.recovery = Some(self.seq_nr.wrapping_sub(1));
+        self.cwnd = (self.cwnd / 2.0).max(MIN_CWND);
+    }
+
+    fn handle_data(&mut self, packet: Packet) {
+        self.need_ack = true;
+
+        // Ignore packets we already have, and any too far ahead to be buffered
+        let offset = packet.seq_nr.wrapping_sub(self.ack_nr.wrapping_add(1)) as usize;
+        if offset >= BUFFER_SIZE / MAX_PAYLOAD {
+            return;
+        }
+        self.out_of_order.insert(packet.seq_nr, packet);
+
+        let mut shared = self.inner.shared.lock().unwrap();
+        if shared.eof {
+            return;
+        }
+        while let Some(packet) = self.out_of_order.remove(&self.ack_nr.wrapping_add(1)) {
+            self.ack_nr = packet.seq_nr;
+            if packet.packet_type == PacketType::Fin {
+                shared.eof = true;
+                self.out_of_order.clear();
+                break;
+            }
+            if !shared.dropped {
+                shared.read_buf.extend(&packet.payload);
+            }
+        }
+        if let Some(waker) = shared.read_waker.take() {
+            waker.wake();
+        }
+    }
+
+    async fn handle_timeout(&mut self) -> io::Result<()> {
+        if self.unacked.is_empty() {
+            self.timeout_at = None;
+            return Result::Ok(());
+        }
+
+        self.retransmits += 1;
+        if self.retransmits > MAX_RETRANSMITS {
+            return Result::Err(io::ErrorKind::TimedOut.into());
+        }
+
+        // A timeout means the path is congested, so start again from the smallest window
+        self.recovery = Some(self.seq_nr.wrapping_sub(1));
+        self.cwnd = MIN_CWND;
+        self.rto = (self.rto * 2).min(MAX_TIMEOUT);
+        self.retransmit(0).await?;
+        self.timeout_at = Some(Instant::now() + self.rto);
+
+        Result::Ok(())
+    }
+
+    /// Measures the round trip time
+    fn update_rtt(&mut self, sample: Duration) {
+        match self.rtt {
+            None => {
+                self.rtt = Some(sample);
+                self.rtt_var = sample / 2;
+            }
+            Some(rtt) => {
+                self.rtt_var = (self.rtt_var * 3 + rtt.abs_diff(sample)) / 4;
+                self.rtt = Some((rtt * 7 + sample) / 8);
+            }
+        }
+    }
+
+    /// Returns the retransmission timeout for the measured round trip time, before any backoff
+    fn estimated_rto(&self) -> Duration {
+        match self.rtt {
+            Some(rtt) => (rtt + self.rtt_var * 4).max(MIN_TIMEOUT),
+            None => INITIAL_TIMEOUT,
+        }
+    }
+
+    /// Grows or shrinks the congestion window depending on how far the queuing delay the peer
+    /// measured is from the target (LEDBAT), so we back off as soon as our packets start queuing
+    /// up behind other traffic
+    fn update_cwnd(&mut self, delay: u32, acked_bytes: usize) {
+        // The peer can't measure the delay until it has received a packet from us
+        let queuing_delay = match delay {
+            0 => 0,
+            delay => delay - self.base_delay.update(delay),
+        };
+        let off_target = (TARGET_DELAY - queuing_delay as f64) / TARGET_DELAY;
+        self.cwnd += MAX_CWND_INCREASE * off_target * acked_bytes as f64 / self.cwnd;
+        self.cwnd = self.cwnd.clamp(MIN_CWND, BUFFER_SIZE as f64);
+    }
+
+    /// Sends as much of the written data as the congestion window and the peer's receive window
+    /// allow, followed by a FIN once the stream is closed and everything has been sent
+    async fn send_data(&mut self) -> io::Result<()> {
+        if self.connecting.is_some() {
+            return Result::Ok(());
+        }
+
+        loop {
+            // Always allow one packet in flight, so a closed window is probed
+            let window = self.cwnd.min(self.peer_wnd as f64) as usize;
+            let space = match self.in_flight {
+                0 => MAX_PAYLOAD,
+                in_flight => window.saturating_sub(in_flight).min(MAX_PAYLOAD),
+            };
+            if space == 0 {
+                return Result::Ok(());
+            }
+
+            let payload: Vec<u8> = {
+                let mut shared = self.inner.shared.lock().unwrap();
+                let len = space.min(shared.write_buf.len());
+                let payload = shared.write_buf.drain(..len).collect();
+                if let Some(waker) = shared.write_waker.take() {
+                    waker.wake();
+                }
+                payload
+            };
+            if payload.is_empty() {
+                break;
+            }
+
+            let mut packet = Packet::new(PacketType::Data, self.send_id, self.seq_nr, 0);
+            packet.payload = payload;
+            self.seq_nr = self.seq_nr.wrapping_add(1);
+            self.transmit(packet).await?;
+        }
+
+        let closing = {
+            let shared = self.inner.shared.lock().unwrap();
+            shared.closing && shared.write_buf.is_empty()
+        };
+        if closing && !self.fin_sent {
+            let fin = Packet::new(PacketType::Fin, self.send_id, self.seq_nr, 0);
+            self.seq_nr = self.seq_nr.wrapping_add(1);
+            self.fin_sent = true;
+            self.transmit(fin).await?;
+        }
+
+        Result::Ok(())
+    }
+
+    /// Sends a packet that needs to be acked, keeping it to send again if it isn't
+    async fn transmit(&mut self, packet: Packet) -> io::Result<()> {
+        let mut sent = SentPacket {
+            packet,
+            sent_at: Instant::now(),
+            transmissions: 0,
+            selective_acked: false,
+        };
+        self.send(&mut sent).await?;
+
+        self.in_flight += sent.packet.payload.len();
+        if self.timeout_at.is_none() {
+            self.timeout_at = Some(Instant::now() + self.rto);
+        }
+        self.unacked.push_back(sent);
+
+        Result::Ok(())
+    }
+
+    /// Sends a packet that hasn't been acked again, by its position in the unacked packets
+    async fn retransmit(&mut self, index: usize) -> io::Result<()> {
+        let Some(mut sent) = self.unacked.remove(index) else {
+            return Result::Ok(());
+        };
+        let result = self.send(&mut sent).await;
+        self.unacked.insert(index, sent);
+        result
+    }
+
+    async fn send(&mut self, sent: &mut SentPacket) -> io::Result<()> {
+        let packet = &mut sent.packet;
+        packet.ack_nr = self.ack_nr;
+        packet.timestamp = timestamp();
+        packet.timestamp_difference = self.reply_delay;
+        packet.wnd_size = self.receive_window();
+        packet.selective_ack = self.selective_ack();
+        self.socket.send_to(&packet.encode(), self.addr).await?;
+
+        sent.sent_at = Instant::now();
+        sent.transmissions += 1;
+        // Every packet acks what we have received
+        self.need_ack = false;
+
+        Result::Ok(())
+    }
+
+    /// Acks what we have received without sending any data
+    async fn send_state(&mut self) -> io::Result<()> {
+        let mut packet = Packet::new(PacketType::State, self.send_id, self.seq_nr, self.ack_nr);
+        packet.timestamp = timestamp();
+        packet.timestamp_difference = self.reply_delay;
+        packet.wnd_size = self.receive_window();
+        packet.selective_ack = self.selective_ack();
+        self.socket.send_to(&packet.encode(), self.addr).await?;
+        self.need_ack = false;
+
+        Result::Ok(())
+    }
+
+    fn receive_window(&self) -> u32 {
+        let shared = self.inner.shared.lock().unwrap();
+        BUFFER_SIZE.saturating_sub(shared.read_buf.len()) as u32
+    }
+
+    /// Describes the packets we received after a gap, so the peer only sends the gap again
+    fn selective_ack(&self) -> Option<Vec<u8>> {
+        let bits: Vec<usize> = self
+            .out_of_order
+            .keys()
+            .map(|seq_nr| seq_nr.wrapping_sub(self.ack_nr).wrapping_sub(2) as usize)
+            .collect();
+        // The bitmask is a multiple of four bytes long
+        let mut mask = vec![0u8; (bits.iter().max()? / 32 + 1) * 4];
+        for bit in bits {
+            mask[bit / 8] |= 1 << (bit % 8);
+        }
+        Some(mask)
+    }
+}
+
+/// Compares sequence numbers, which wrap around
+fn seq_less_or_equal(a: u16, b: u16) -> bool {
+    b.wrapping_sub(a) < 0x8000
+}
@@ -0,0 +1,169 @@
+use std::collections::{BTreeMap, HashMap};
+use std::net::IpAddr;
+
+use serde_bytes::ByteBuf;
+use serde_derive::{Deserialize, Serialize};
+
+use super::message::Message;
+
+// The extended message ID reserved for the extension handshake
+const EXTENDED_HANDSHAKE_ID: u8 = 0;
+
+// The client name and version we advertise in the extension handshake
+const CLIENT_VERSION: &str = concat!("rustor ", env!("CARGO_PKG_VERSION"));
+
+// The number of outstanding requests we are willing to queue from a peer
+pub const LOCAL_REQQ: u32 = 250;
+
+/// The extension handshake dictionary, as described in BEP 10
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct ExtendedHandshake {
+    #[serde(default)]
+    pub m: BTreeMap<String, u8>,
+    /// The client name and version, which isn't necessarily UTF-8
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub v: Option<ByteBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub p: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reqq: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub yourip: Option<ByteBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metadata_size: Option<usize>,
+}
+
+/// A decoded extended message
+#[derive(Debug)]
+pub enum ExtendedMessage {
+    Handshake,
+    Extension(&'static str, Vec<u8>),
+}
+
+/// Tracks the extensions we support and the message IDs negotiated with a peer. Extensions plug in
+/// by registering their name, which assigns them the local message ID peers use to reach them.
+#[derive(Debug, Default)]
+pub struct ExtensionRegistry {
+    local: Vec<&'static str>,
+    remote: HashMap<String, u8>,
+    peer_handshake: Option<ExtendedHandshake>,
+}
+
+impl ExtensionRegistry {
+    pub fn new(names: &[&'static str]) -> Self {
+        Self {
+            local: names.to_vec(),
+            ..Default::default()
+        }
+    }
+
+    /// Builds our extension handshake message for a peer at `peer_ip`, advertising our listen
+    /// `port`. Extension specific fields can be filled in on the returned handshake.
+    pub fn handshake(&self, peer_ip: IpAddr, port: u16) -> ExtendedHandshake {
+        let yourip = match peer_ip {
+            IpAddr::V4(ip) => ip.octets().to_vec(),
+            IpAddr::V6(ip) => ip.octets().to_vec(),
+        };
+
+        ExtendedHandshake {
+            m: self
+                .local
+                .iter()
+                .enumerate()
+                .map(|(i, name)| (name.to_string(), i as u8 + 1))
+                .collect(),
+            v: Some(ByteBuf::from(CLIENT_VERSION.as_bytes())),
+            p: Some(port),
+            reqq: Some(LOCAL_REQQ),
+            yourip: Some(ByteBuf::from(yourip)),
+            metadata_size: None,
+        }
+    }
+
+    pub fn handshake_message(handshake: &ExtendedHandshake) -> anyhow::Result<Message> {
+        Result::Ok(Message::Extended(
+            EXTENDED_HANDSHAKE_ID,
+            serde_bencode::to_bytes(handshake)?,
+        ))
+    }
+
+    /// Decodes an extended message received from the peer, recording the peer's message IDs if
+    /// it is an extension handshake. Returns None for messages to extensions we don't support.
+    pub fn decode(&mut self, id: u8, payload: Vec<u8>) -> anyhow::Result<Option<ExtendedMessage>> {
+        if id == EXTENDED_HANDSHAKE_ID {
+            let handshake = serde_bencode::from_bytes::<ExtendedHandshake>(&payload)?;
+
+            // Handshakes may be sent more than once, a message ID of 0 disables an extension
+            for (name, id) in &handshake.m {
+                if *id == 0 {
+                    self.remote.remove(name);
+                } else {
+                    self.remote.insert(name.clone(), *id);
+                }
+            }
+            self.peer_handshake = Some(handshake);
+
+            return Result::Ok(Some(ExtendedMessage::Handshake));
+        }
+
+        Result::Ok(
+            self.local
+                .get(id as usize - 1)
+                .map(|name| ExtendedMessage::Extension(name, payload)),
+        )
+    }
+
+    /// Builds a message to the extension `name` on the peer, if the peer supports it
+    pub fn message(&self, name: &str, payload: Vec<u8>) -> Option<Message> {
+        self.remote
+            .get(name)
+            .map(|id| Message::Extended(*id, payload))
+    }
+
+    pub fn supports(&self, name: &str) -> bool {
+        self.remote.contains_key(name)
+    }
+
+    pub fn peer_handshake(&self) -> Option<&ExtendedHandshake> {
+        self.peer_handshake.as_ref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_handshake_with_non_utf8_client_name() {
+        let mut registry = ExtensionRegistry::new(&["ut_pex"]);
+        let payload = b"d1:md6:ut_pexi3ee1:v5:\xb5Tor\xffe".to_vec();
+
+        let decoded = registry.decode(EXTENDED_HANDSHAKE_ID, payload).unwrap();
+
+        assert!(matches!(decoded, Some(ExtendedMessage::Handshake)));
+        assert!(registry.supports("ut_pex"));
+        let v = registry.peer_handshake().unwrap().v.as_ref().unwrap();
+        assert_eq!(v.as_ref(), b"\xb5Tor\xff");
+    }
+
+    #[test]
+    fn routes_messages_by_negotiated_ids() {
+        let mut registry = ExtensionRegistry::new(&["ut_metadata", "ut_pex"]);
+        registry
+            .decode(EXTENDED_HANDSHAKE_ID, b"d1:md6:ut_pexi7eee".to_vec())
+            .unwrap();
+
+        // Peers reach our extensions by the IDs we assigned in our handshake
+        let decoded = registry.decode(2, b"payload".to_vec()).unwrap();
+        assert!(matches!(
+            decoded,
+            Some(ExtendedMessage::Extension("ut_pex", _))
+        ));
+        // And we reach theirs by the IDs they assigned
+        assert!(matches!(
+            registry.message("ut_pex", Vec::new()),
+            Some(Message::Extended(7, _))
+        ));
+        assert!(registry.message("ut_metadata", Vec::new()).is_none());
+    }
+}
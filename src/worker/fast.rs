@@ -0,0 +1,102 @@
+use std::collections::HashSet;
+use std::net::{IpAddr, Ipv4Addr};
+
+use sha1::{Digest, Sha1};
+
+use crate::types::InfoHash;
+
+// How many pieces we let a peer download from us while it is choked
+const ALLOWED_FAST_COUNT: usize = 10;
+
+/// Generates the pieces a peer at `ip` may request while choked, using the canonical algorithm so
+/// the set is the same however often the peer reconnects (BEP 6). Only defined for IPv4 peers.
+pub fn allowed_fast_set(ip: IpAddr, info_hash: &InfoHash, num_pieces: usize) -> HashSet<u32> {
+    let ip = match ip {
+        IpAddr::V4(ip) => ip,
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => ip,
+            None => return HashSet::new(),
+        },
+    };
+    generate_set(
+        ip,
+        info_hash,
+        num_pieces,
+        ALLOWED_FAST_COUNT.min(num_pieces),
+    )
+}
+
+/// Generates the first `count` pieces of the canonical sequence for a peer at `ip`
+fn generate_set(
+    ip: Ipv4Addr,
+    info_hash: &InfoHash,
+    num_pieces: usize,
+    count: usize,
+) -> HashSet<u32> {
+    let mut allowed = HashSet::new();
+
+    // Peers on the same /24 share a set, so a peer can't get more by using several addresses
+    let mut x = (u32::from(ip) & 0xffffff00).to_be_bytes().to_vec();
+    x.extend_from_slice(info_hash);
+    while allowed.len() < count {
+        let mut sha1 = Sha1::new();
+        sha1.update(&x);
+        x = sha1.finalize().to_vec();
+        for chunk in x.chunks_exact(4) {
+            if allowed.len() >= count {
+                break;
+            }
+            let y = u32::from_be_bytes(chunk.try_into().unwrap());
+            allowed.insert(y % num_pieces as u32);
+        }
+    }
+
+    allowed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The example from BEP 6
+    const IP: Ipv4Addr = Ipv4Addr::new(80, 4, 4, 200);
+    const INFO_HASH: InfoHash = [0xaa; 20];
+    const NUM_PIECES: usize = 1313;
+
+    #[test]
+    fn matches_reference_sets() {
+        let seven = generate_set(IP, &INFO_HASH, NUM_PIECES, 7);
+        assert_eq!(seven, HashSet::from([1059, 431, 808, 1217, 287, 376, 1188]));
+        let nine = generate_set(IP, &INFO_HASH, NUM_PIECES, 9);
+        assert_eq!(
+            nine,
+            HashSet::from([1059, 431, 808, 1217, 287, 376, 1188, 353, 508])
+        );
+    }
+
+    #[test]
+    fn shares_sets_within_a_subnet() {
+        let set = allowed_fast_set(IP.into(), &INFO_HASH, NUM_PIECES);
+        assert_eq!(set.len(), ALLOWED_FAST_COUNT);
+        assert!(set.is_superset(&generate_set(IP, &INFO_HASH, NUM_PIECES, 9)));
+
+        let neighbour = Ipv4Addr::new(80, 4, 4, 1);
+        assert_eq!(
+            allowed_fast_set(neighbour.into(), &INFO_HASH, NUM_PIECES),
+            set
+        );
+        let mapped = IpAddr::V6(IP.to_ipv6_mapped());
+        assert_eq!(allowed_fast_set(mapped, &INFO_HASH, NUM_PIECES), set);
+    }
+
+    #[test]
+    fn handles_small_torrents_and_ipv6() {
+        assert_eq!(
+            allowed_fast_set(IP.into(), &INFO_HASH, 3),
+            HashSet::from([0, 1, 2])
+        );
+        assert!(allowed_fast_set(IP.into(), &INFO_HASH, 0).is_empty());
+        let ipv6 = "2001:db8::1".parse().unwrap();
+        assert!(allowed_fast_set(ipv6, &INFO_HASH, NUM_PIECES).is_empty());
+    }
+}
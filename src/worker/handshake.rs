@@ -1,28 +1,81 @@
 use bytes::{Buf, BufMut, BytesMut};
-use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::TcpStream,
-};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
+use super::mse::PeerStream;
 use crate::types::{InfoHash, PeerID, INFO_HASH_LEN, PEER_ID_LEN};
 
 const PSTR: &str = "BitTorrent protocol";
 const EXTENSIONS_LEN: usize = 8;
 
+// Reserved bit advertising support for the extension protocol (BEP 10)
+const EXTENSION_PROTOCOL_BYTE: usize = 5;
+const EXTENSION_PROTOCOL_BIT: u8 = 0x10;
+
+// Reserved bit advertising that we run a DHT node (BEP 5)
+const DHT_BYTE: usize = 7;
+const DHT_BIT: u8 = 0x01;
+
+// Reserved bit advertising support for the fast extension (BEP 6)
+const FAST_BYTE: usize = 7;
+const FAST_BIT: u8 = 0x04;
+
+/// The reserved bytes of a handshake, used to advertise support for protocol extensions
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Extensions([u8; EXTENSIONS_LEN]);
+
+impl Extensions {
+    pub fn supported() -> Self {
+        let mut extensions = Self::default();
+        extensions.0[EXTENSION_PROTOCOL_BYTE] |= EXTENSION_PROTOCOL_BIT;
+        extensions
+    }
+
+    pub fn with_dht(mut self) -> Self {
+        self.0[DHT_BYTE] |= DHT_BIT;
+        self
+    }
+
+    pub fn with_fast(mut self) -> Self {
+        self.0[FAST_BYTE] |= FAST_BIT;
+        self
+    }
+
+    pub fn extension_protocol(&self) -> bool {
+        self.0[EXTENSION_PROTOCOL_BYTE] & EXTENSION_PROTOCOL_BIT != 0
+    }
+
+    pub fn dht(&self) -> bool {
+        self.0[DHT_BYTE] & DHT_BIT != 0
+    }
+
+    pub fn fast(&self) -> bool {
+        self.0[FAST_BYTE] & FAST_BIT != 0
+    }
+}
+
 struct Handshake {
+    extensions: Extensions,
     info_hash: InfoHash,
     peer_id: PeerID,
 }
 
 impl Handshake {
-    fn new(info_hash: InfoHash, peer_id: PeerID) -> Self {
-        Self { info_hash, peer_id }
+    fn new(extensions: Extensions, info_hash: InfoHash, peer_id: PeerID) -> Self {
+        Self {
+            extensions,
+            info_hash,
+            peer_id,
+        }
     }
 
     async fn read<R: AsyncReadExt + Unpin>(reader: &mut R) -> anyhow::Result<Self> {
-        let mut h = Handshake::new([0u8; INFO_HASH_LEN], [0u8; PEER_ID_LEN]);
+        let mut h = Handshake::new(
+            Extensions::default(),
+            [0u8; INFO_HASH_LEN],
+            [0u8; PEER_ID_LEN],
+        );
 
-        let pstr_len: usize = reader.read_u8().await?.try_into()?;
+        let pstr_len: usize = reader.read_u8().await?.into();
 
         let mut buf = BytesMut::zeroed(pstr_len + EXTENSIONS_LEN + INFO_HASH_LEN + PEER_ID_LEN);
         reader.read_exact(&mut buf).await?;
@@ -37,9 +90,7 @@ impl Handshake {
         }
         buf.advance(pstr_len);
 
-        // Skip extensions, we don't support any of them
-        buf.advance(EXTENSIONS_LEN);
-
+        buf.copy_to_slice(&mut h.extensions.0);
         buf.copy_to_slice(&mut h.info_hash);
         buf.copy_to_slice(&mut h.peer_id);
 
@@ -54,8 +105,8 @@ impl Handshake {
         buf.put_u8(u8::try_from(PSTR.len())?);
         // Protocol identifier
         buf.put_slice(PSTR.as_bytes());
-        // Extension bytes, all 0 since we don't support any extensions
-        buf.put_bytes(0, EXTENSIONS_LEN);
+        // Extension bytes advertising the extensions we support
+        buf.put_slice(&self.extensions.0);
         // Info hash identifying the file we want
         buf.put_slice(&self.info_hash);
         // The peer ID of our client
@@ -67,18 +118,40 @@ impl Handshake {
     }
 }
 
+/// Performs the handshake in the initiator role, advertising `extensions` and returning the
+/// extensions and peer id the peer sent back
 pub async fn handshake(
-    stream: &mut TcpStream,
+    stream: &mut PeerStream,
+    extensions: Extensions,
     info_hash: &InfoHash,
     peer_id: &PeerID,
-) -> anyhow::Result<()> {
-    let send = Handshake::new(info_hash.clone(), peer_id.clone());
+) -> anyhow::Result<(Extensions, PeerID)> {
+    let send = Handshake::new(extensions, *info_hash, *peer_id);
     send.write(stream).await?;
     let recv = Handshake::read(stream).await?;
 
     if send.info_hash == recv.info_hash {
-        Result::Ok(())
+        Result::Ok((recv.extensions, recv.peer_id))
     } else {
         Result::Err(anyhow::anyhow!("Mismatched info hashes"))
     }
 }
+
+/// Performs the handshake in the responder role for a peer that connected to us, only replying if
+/// the peer asked for the torrent we are serving
+pub async fn accept_handshake(
+    stream: &mut PeerStream,
+    extensions: Extensions,
+    info_hash: &InfoHash,
+    peer_id: &PeerID,
+) -> anyhow::Result<(Extensions, PeerID)> {
+    let recv = Handshake::read(stream).await?;
+    if recv.info_hash != *info_hash {
+        return Result::Err(anyhow::anyhow!("Mismatched info hashes"));
+    }
+
+    let send = Handshake::new(extensions, *info_hash, *peer_id);
+    send.write(stream).await?;
+
+    Result::Ok((recv.extensions, recv.peer_id))
+}
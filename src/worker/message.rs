@@ -10,21 +10,76 @@ const MESSAGE_ID_BITFIELD: u8 = 5;
 const MESSAGE_ID_REQUEST: u8 = 6;
 const MESSAGE_ID_PIECE: u8 = 7;
 const MESSAGE_ID_CANCEL: u8 = 8;
+const MESSAGE_ID_PORT: u8 = 9;
+const MESSAGE_ID_SUGGEST_PIECE: u8 = 13;
+const MESSAGE_ID_HAVE_ALL: u8 = 14;
+const MESSAGE_ID_HAVE_NONE: u8 = 15;
+const MESSAGE_ID_REJECT_REQUEST: u8 = 16;
+const MESSAGE_ID_ALLOWED_FAST: u8 = 17;
+const MESSAGE_ID_EXTENDED: u8 = 20;
 
-#[derive(Debug)]
+// The longest message we accept from a peer, which is plenty for a block and its header, and for
+// the bitfield of a torrent with up to 8M pieces. Anything longer is a misbehaving peer that would
+// otherwise have us allocate up to 4 GiB.
+const MAX_MESSAGE_LEN: u32 = 1024 * 1024;
+
+#[derive(Debug, Clone)]
 pub struct Bitfield(Vec<u8>);
 
 impl Bitfield {
+    /// Creates an empty bitfield large enough to hold `num_pieces` pieces
+    pub fn new(num_pieces: usize) -> Self {
+        Self(vec![0u8; num_pieces.div_ceil(8)])
+    }
+
+    /// Creates a bitfield with each of the `num_pieces` pieces set
+    pub fn full(num_pieces: usize) -> Self {
+        let mut bitfield = Self::new(num_pieces);
+        for index in 0..num_pieces as u32 {
+            bitfield.set(index);
+        }
+        bitfield
+    }
+
+    pub fn from_bytes(bytes: Vec<u8>) -> Self {
+        Self(bytes)
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
     pub fn has(&self, index: u32) -> bool {
         let byte_index = index / 8;
         let bit_offset = index % 8;
-        self.0[byte_index as usize] >> (7 - bit_offset) & 1 != 0
+        self.0
+            .get(byte_index as usize)
+            .is_some_and(|byte| byte >> (7 - bit_offset) & 1 != 0)
     }
 
     pub fn set(&mut self, index: u32) {
         let byte_index = index / 8;
         let bit_offset = index % 8;
-        self.0[byte_index as usize] |= 1 << (7 - bit_offset);
+        if let Some(byte) = self.0.get_mut(byte_index as usize) {
+            *byte |= 1 << (7 - bit_offset);
+        }
+    }
+
+    pub fn clear(&mut self, index: u32) {
+        let byte_index = index / 8;
+        let bit_offset = index % 8;
+        if let Some(byte) = self.0.get_mut(byte_index as usize) {
+            *byte &= !(1 << (7 - bit_offset));
+        }
+    }
+
+    /// Returns true if every one of the first `num_pieces` pieces is set
+    pub fn is_complete(&self, num_pieces: usize) -> bool {
+        (0..num_pieces as u32).all(|index| self.has(index))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.iter().all(|byte| *byte == 0)
     }
 }
 
@@ -40,6 +95,17 @@ pub enum Message {
     Request(u32, u32, u32),
     Cancel(u32, u32, u32),
     Piece(u32, u32, Vec<u8>),
+    /// The UDP port of the peer's DHT node
+    Port(u16),
+    /// Messages added by the fast extension (BEP 6)
+    SuggestPiece(u32),
+    HaveAll,
+    HaveNone,
+    RejectRequest(u32, u32, u32),
+    AllowedFast(u32),
+    Extended(u8, Vec<u8>),
+    /// A message from an extension we don't support, which is ignored along with its payload
+    Unknown(u8),
 }
 
 impl Message {
@@ -49,11 +115,42 @@ impl Message {
             return Result::Ok(Self::KeepAlive);
         }
 
+        if len > MAX_MESSAGE_LEN {
+            return Result::Err(anyhow::anyhow!("Message of {} bytes is too long", len));
+        }
+
         let mut payload = BytesMut::zeroed(len as usize);
         reader.read_exact(&mut payload).await?;
 
         let id = payload.get_u8();
 
+        // Make sure the payload holds every field of the message before reading them
+        let valid = match id {
+            MESSAGE_ID_CHOKE
+            | MESSAGE_ID_UNCHOKE
+            | MESSAGE_ID_INTERESTED
+            | MESSAGE_ID_NOT_INTERESTED
+            | MESSAGE_ID_HAVE_ALL
+            | MESSAGE_ID_HAVE_NONE => payload.remaining() == 0,
+            MESSAGE_ID_HAVE | MESSAGE_ID_SUGGEST_PIECE | MESSAGE_ID_ALLOWED_FAST => {
+                payload.remaining() == 4
+            }
+            MESSAGE_ID_REQUEST | MESSAGE_ID_CANCEL | MESSAGE_ID_REJECT_REQUEST => {
+                payload.remaining() == 12
+            }
+            MESSAGE_ID_PORT => payload.remaining() == 2,
+            MESSAGE_ID_PIECE => payload.remaining() >= 8,
+            MESSAGE_ID_EXTENDED => payload.remaining() >= 1,
+            _ => true,
+        };
+        if !valid {
+            return Result::Err(anyhow::anyhow!(
+                "Invalid length {} for message ID {}",
+                len,
+                id
+            ));
+        }
+
         match id {
             MESSAGE_ID_CHOKE => Result::Ok(Self::Choke),
             MESSAGE_ID_UNCHOKE => Result::Ok(Self::Unchoke),
@@ -87,13 +184,39 @@ impl Message {
                 payload.copy_to_slice(&mut block);
                 Result::Ok(Self::Piece(index, begin, block))
             }
-            _ => Result::Err(anyhow::anyhow!("Unknown message ID: {}", id)),
+            MESSAGE_ID_PORT => {
+                let port = payload.get_u16();
+                Result::Ok(Self::Port(port))
+            }
+            MESSAGE_ID_SUGGEST_PIECE => {
+                let index = payload.get_u32();
+                Result::Ok(Self::SuggestPiece(index))
+            }
+            MESSAGE_ID_HAVE_ALL => Result::Ok(Self::HaveAll),
+            MESSAGE_ID_HAVE_NONE => Result::Ok(Self::HaveNone),
+            MESSAGE_ID_REJECT_REQUEST => {
+                let index = payload.get_u32();
+                let begin = payload.get_u32();
+                let length = payload.get_u32();
+                Result::Ok(Self::RejectRequest(index, begin, length))
+            }
+            MESSAGE_ID_ALLOWED_FAST => {
+                let index = payload.get_u32();
+                Result::Ok(Self::AllowedFast(index))
+            }
+            MESSAGE_ID_EXTENDED => {
+                let extended_id = payload.get_u8();
+                let mut extended_payload = vec![0u8; payload.remaining()];
+                payload.copy_to_slice(&mut extended_payload);
+                Result::Ok(Self::Extended(extended_id, extended_payload))
+            }
+            _ => Result::Ok(Self::Unknown(id)),
         }
     }
 
     pub async fn write<W: AsyncWriteExt + Unpin>(&self, writer: &mut W) -> anyhow::Result<()> {
         let buf: BytesMut = match self {
-            Self::KeepAlive => BytesMut::zeroed(1),
+            Self::KeepAlive => BytesMut::zeroed(4),
             Self::Choke => {
                 let mut buf = BytesMut::with_capacity(4 + 1);
                 buf.put_u32(1);
@@ -159,9 +282,158 @@ impl Message {
                 buf.put_slice(block);
                 buf
             }
+            Self::Port(port) => {
+                let mut buf = BytesMut::with_capacity(4 + 1 + 2);
+                buf.put_u32(1 + 2);
+                buf.put_u8(MESSAGE_ID_PORT);
+                buf.put_u16(*port);
+                buf
+            }
+            Self::SuggestPiece(index) => {
+                let mut buf = BytesMut::with_capacity(4 + 1 + 4);
+                buf.put_u32(1 + 4);
+                buf.put_u8(MESSAGE_ID_SUGGEST_PIECE);
+                buf.put_u32(*index);
+                buf
+            }
+            Self::HaveAll => {
+                let mut buf = BytesMut::with_capacity(4 + 1);
+                buf.put_u32(1);
+                buf.put_u8(MESSAGE_ID_HAVE_ALL);
+                buf
+            }
+            Self::HaveNone => {
+                let mut buf = BytesMut::with_capacity(4 + 1);
+                buf.put_u32(1);
+                buf.put_u8(MESSAGE_ID_HAVE_NONE);
+                buf
+            }
+            Self::RejectRequest(index, begin, length) => {
+                let mut buf = BytesMut::with_capacity(4 + 1 + 4 + 4 + 4);
+                buf.put_u32(1 + 4 + 4 + 4);
+                buf.put_u8(MESSAGE_ID_REJECT_REQUEST);
+                buf.put_u32(*index);
+                buf.put_u32(*begin);
+                buf.put_u32(*length);
+                buf
+            }
+            Self::AllowedFast(index) => {
+                let mut buf = BytesMut::with_capacity(4 + 1 + 4);
+                buf.put_u32(1 + 4);
+                buf.put_u8(MESSAGE_ID_ALLOWED_FAST);
+                buf.put_u32(*index);
+                buf
+            }
+            Self::Extended(extended_id, payload) => {
+                let mut buf = BytesMut::with_capacity(4 + 1 + 1 + payload.len());
+                buf.put_u32(1 + 1 + payload.len() as u32);
+                buf.put_u8(MESSAGE_ID_EXTENDED);
+                buf.put_u8(*extended_id);
+                buf.put_slice(payload);
+                buf
+            }
+            Self::Unknown(id) => {
+                return Result::Err(anyhow::anyhow!("Cannot send unknown message ID {}", id))
+            }
         };
 
         writer.write_all(&buf).await?;
         Result::Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn round_trip(msg: Message) -> Message {
+        let mut buf = Vec::new();
+        msg.write(&mut buf).await.unwrap();
+        Message::read(&mut buf.as_slice()).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn round_trips_messages() {
+        assert!(matches!(
+            round_trip(Message::KeepAlive).await,
+            Message::KeepAlive
+        ));
+        assert!(matches!(
+            round_trip(Message::Have(7)).await,
+            Message::Have(7)
+        ));
+        assert!(matches!(
+            round_trip(Message::Request(1, 16384, 16384)).await,
+            Message::Request(1, 16384, 16384)
+        ));
+        let Message::Piece(index, begin, block) =
+            round_trip(Message::Piece(2, 0, vec![1, 2, 3])).await
+        else {
+            panic!("expected a piece");
+        };
+        assert_eq!((index, begin, block), (2, 0, vec![1, 2, 3]));
+    }
+
+    #[tokio::test]
+    async fn rejects_truncated_messages() {
+        // A have with a single byte of piece index
+        let have: &[u8] = &[0, 0, 0, 2, MESSAGE_ID_HAVE, 1];
+        assert!(Message::read(&mut &*have).await.is_err());
+
+        let request: &[u8] = &[0, 0, 0, 5, MESSAGE_ID_REQUEST, 0, 0, 0, 1];
+        assert!(Message::read(&mut &*request).await.is_err());
+
+        let extended: &[u8] = &[0, 0, 0, 1, MESSAGE_ID_EXTENDED];
+        assert!(Message::read(&mut &*extended).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn rejects_oversized_messages() {
+        let huge: &[u8] = &[0xff, 0xff, 0xff, 0xff, MESSAGE_ID_PIECE];
+        assert!(Message::read(&mut &*huge).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn skips_unknown_messages() {
+        // An unknown message with a payload, followed by a have
+        let msgs: &[u8] = &[
+            0,
+            0,
+            0,
+            3,
+            42,
+            1,
+            2,
+            0,
+            0,
+            0,
+            5,
+            MESSAGE_ID_HAVE,
+            0,
+            0,
+            0,
+            7,
+        ];
+        let mut reader = msgs;
+        assert!(matches!(
+            Message::read(&mut reader).await.unwrap(),
+            Message::Unknown(42)
+        ));
+        assert!(matches!(
+            Message::read(&mut reader).await.unwrap(),
+            Message::Have(7)
+        ));
+    }
+
+    #[test]
+    fn bitfield_ignores_out_of_range_pieces() {
+        let mut bitfield = Bitfield::new(10);
+        bitfield.set(0);
+        bitfield.set(9);
+        bitfield.set(100);
+        assert!(bitfield.has(0) && bitfield.has(9));
+        assert!(!bitfield.has(100));
+        assert!(!bitfield.is_complete(10));
+        assert_eq!(bitfield.as_bytes(), &[0x80, 0x40]);
+    }
+}
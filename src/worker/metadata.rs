@@ -0,0 +1,178 @@
+use std::time::Duration;
+
+use anyhow::Context;
+use serde_derive::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+use tracing::info;
+
+use super::extension::{ExtendedMessage, ExtensionRegistry};
+use super::handshake::{handshake, Extensions};
+use super::message::{Bitfield, Message};
+use super::mse;
+use super::transport::TransportConfig;
+use crate::bencode;
+use crate::tracker::Peer;
+use crate::types::{InfoHash, PeerID};
+
+pub const UT_METADATA: &str = "ut_metadata";
+
+// Metadata is exchanged in pieces of 16 KiB
+const METADATA_PIECE_SIZE: usize = 16384;
+
+// The largest info dictionary we are willing to download from a peer
+const MAX_METADATA_SIZE: usize = 16 * 1024 * 1024;
+
+const METADATA_MSG_TYPE_REQUEST: u8 = 0;
+const METADATA_MSG_TYPE_DATA: u8 = 1;
+const METADATA_MSG_TYPE_REJECT: u8 = 2;
+
+#[derive(Debug, Deserialize, Serialize)]
+struct MetadataMessage {
+    msg_type: u8,
+    piece: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    total_size: Option<usize>,
+}
+
+/// Fetches the info dictionary of a torrent from a peer using the ut_metadata extension
+/// (BEP 9), verifying it against the info hash.
+pub async fn fetch_metadata(
+    info_hash: &InfoHash,
+    peer_id: &PeerID,
+    port: u16,
+    peer: &Peer,
+    transport: &TransportConfig,
+) -> anyhow::Result<Vec<u8>> {
+    let mut stream = mse::connect(peer.addr, info_hash, transport).await?;
+
+    let (extensions, _) =
+        handshake(&mut stream, Extensions::supported(), info_hash, peer_id).await?;
+    if !extensions.extension_protocol() {
+        return Result::Err(anyhow::anyhow!(
+            "Peer {} does not support the extension protocol",
+            peer
+        ));
+    }
+
+    let mut extensions = ExtensionRegistry::new(&[UT_METADATA]);
+    ExtensionRegistry::handshake_message(&extensions.handshake(peer.addr.ip(), port))?
+        .write(&mut stream)
+        .await?;
+
+    let mut metadata: Option<Vec<u8>> = None;
+    // The pieces received so far, since a peer may send the same one more than once
+    let mut received = Bitfield::new(0);
+
+    loop {
+        let msg = tokio::time::timeout(Duration::from_secs(30), Message::read(&mut stream))
+            .await
+            .context("Timed out waiting for metadata")??;
+
+        let Message::Extended(id, payload) = msg else {
+            continue;
+        };
+
+        match extensions.decode(id, payload)? {
+            Some(ExtendedMessage::Handshake) => {
+                if metadata.is_some() {
+                    continue;
+                }
+                if !extensions.supports(UT_METADATA) {
+                    return Result::Err(anyhow::anyhow!(
+                        "Peer {} does not support ut_metadata",
+                        peer
+                    ));
+                }
+                let size = extensions
+                    .peer_handshake()
+                    .and_then(|h| h.metadata_size)
+                    .ok_or_else(|| anyhow::anyhow!("Peer {} did not send a metadata size", peer))?;
+                if size == 0 || size > MAX_METADATA_SIZE {
+                    return Result::Err(anyhow::anyhow!("Invalid metadata size {}", size));
+                }
+
+                info!("Fetching {} bytes of metadata from {}", size, peer);
+
+                for piece in 0..size.div_ceil(METADATA_PIECE_SIZE) {
+                    let request = MetadataMessage {
+                        msg_type: METADATA_MSG_TYPE_REQUEST,
+                        piece,
+                        total_size: None,
+                    };
+                    if let Some(msg) =
+                        extensions.message(UT_METADATA, serde_bencode::to_bytes(&request)?)
+                    {
+                        msg.write(&mut stream).await?;
+                    }
+                }
+
+                metadata = Some(vec![0u8; size]);
+                received = Bitfield::new(size.div_ceil(METADATA_PIECE_SIZE));
+            }
+            Some(ExtendedMessage::Extension(UT_METADATA, payload)) => {
+                let buf = metadata.as_mut().ok_or_else(|| {
+                    anyhow::anyhow!("Received metadata before extension handshake")
+                })?;
+
+                // The piece data directly follows the bencoded dictionary
+                let header_end = bencode::value_end(&payload, 0)?;
+                let header = serde_bencode::from_bytes::<MetadataMessage>(&payload[..header_end])?;
+                let data = &payload[header_end..];
+
+                match header.msg_type {
+                    METADATA_MSG_TYPE_DATA => {
+                        // The piece number comes from the peer, so it may be far out of range
+                        let begin = header
+                            .piece
+                            .checked_mul(METADATA_PIECE_SIZE)
+                            .filter(|begin| *begin < buf.len());
+                        let end =
+                            begin.map(|begin| usize::min(begin + METADATA_PIECE_SIZE, buf.len()));
+                        let (Some(begin), Some(end)) = (begin, end) else {
+                            return Result::Err(anyhow::anyhow!(
+                                "Invalid metadata piece {}",
+                                header.piece
+                            ));
+                        };
+                        if data.len() != end - begin {
+                            return Result::Err(anyhow::anyhow!(
+                                "Invalid metadata piece {} of size {}",
+                                header.piece,
+                                data.len()
+                            ));
+                        }
+
+                        buf[begin..end].copy_from_slice(data);
+                        received.set(header.piece as u32);
+                    }
+                    METADATA_MSG_TYPE_REJECT => {
+                        return Result::Err(anyhow::anyhow!(
+                            "Peer {} rejected request for metadata piece {}",
+                            peer,
+                            header.piece
+                        ));
+                    }
+                    _ => {}
+                }
+
+                if received.is_complete(buf.len().div_ceil(METADATA_PIECE_SIZE)) {
+                    break;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let metadata = metadata.unwrap_or_default();
+    let mut sha1 = Sha1::new();
+    sha1.update(&metadata);
+    let hash: InfoHash = sha1.finalize().into();
+    if hash != *info_hash {
+        return Result::Err(anyhow::anyhow!(
+            "Metadata from {} failed integrity check",
+            peer
+        ));
+    }
+
+    Result::Ok(metadata)
+}
@@ -1,29 +1,55 @@
+mod extension;
+mod fast;
 mod handshake;
 mod message;
+mod metadata;
+mod mse;
+mod pex;
+mod piece;
+mod pipeline;
+mod transport;
 
+use std::collections::{HashSet, VecDeque};
+use std::net::SocketAddr;
+use std::sync::Arc;
 use std::time::Duration;
 
-use anyhow::Context;
-use async_channel::{Receiver, Sender};
-use sha1::{Digest, Sha1};
-use tokio::net::TcpStream;
+use tokio::io::{AsyncRead, AsyncWrite, WriteHalf};
+use tokio::sync::mpsc::error::TryRecvError;
 use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::{broadcast, mpsc};
+use tokio::task::JoinHandle;
 use tracing::{info, warn};
 
-use self::handshake::handshake;
-use self::message::{Bitfield, Message};
+use self::extension::{ExtendedMessage, ExtensionRegistry, LOCAL_REQQ};
+use self::fast::allowed_fast_set;
+use self::handshake::{accept_handshake, handshake, Extensions};
+pub use self::message::Bitfield;
+use self::message::Message;
+pub use self::metadata::fetch_metadata;
+pub use self::mse::EncryptionPolicy;
+use self::mse::PeerStream;
+pub use self::pex::PexPeer;
+use self::pex::{PeerExchange, PexMessage, PEX_FLAG_REACHABLE, PEX_FLAG_SEED, UT_PEX};
+use self::piece::PieceProgress;
+use self::pipeline::RequestPipeline;
+pub use self::transport::{Transport, TransportConfig};
+use crate::choker::ChokerHandle;
+use crate::state::TorrentState;
+use crate::tracker::Peer;
 use crate::types::PieceHash;
-use crate::{
-    tracker::Peer,
-    types::{InfoHash, PeerID},
-};
 
 // The largest number of bytes a request can ask for
-const MAX_BLOCK_SIZE: u32 = 16384;
+pub const MAX_BLOCK_SIZE: u32 = 16384;
 
-// The max number of unfulfilled requests a worker can have in flight at one time
-// TODO: Adaptive queueing of requests: https://luminarys.com/posts/writing-a-bittorrent-client.html
-const MAX_BACKLOG: u32 = 5;
+// The largest block we are willing to serve to a peer
+const MAX_REQUEST_SIZE: u32 = 131072;
+
+// How long to wait without hearing from a peer before sending them a keep alive
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(90);
+
+// How long to wait for a requested block before giving up on the peer
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
 
 #[derive(Debug)]
 pub struct PieceInfo {
@@ -42,186 +68,1066 @@ impl PieceInfo {
     }
 }
 
-pub type DownloadChannel = (Sender<PieceInfo>, Receiver<PieceInfo>);
-
+/// A piece that has been downloaded, verified and written to disk
 #[derive(Debug)]
 pub struct PieceResult {
     pub index: u32,
-    pub buf: Vec<u8>,
 }
 
 impl PieceResult {
-    pub fn new(index: u32, buf: Vec<u8>) -> Self {
-        Self { index, buf }
+    pub fn new(index: u32) -> Self {
+        Self { index }
     }
 }
 
-struct PieceProgress {
-    buf: Vec<u8>,
-    downloaded: u32,
-    requested: u32,
-    backlog: u32,
+enum WorkerEvent {
+    Message(Message),
+    Have(u32),
+    /// A block written by another worker downloading the same piece in endgame mode
+    Block(u32, u32),
+    /// Time to tell the peer about changes to the peers we are connected to
+    Pex,
+    /// The choker decided whether we should be choking the peer
+    Choke(bool),
+    /// Another worker gave up on a piece or endgame mode started, so there may be something new
+    /// to download
+    PiecesAvailable,
+    Timeout,
 }
 
-#[derive(Debug)]
-pub struct TorrentDownloadWorker {
-    stream: TcpStream,
+pub struct TorrentDownloadWorker<S = Transport> {
+    peer: Peer,
+    state: Arc<TorrentState>,
+    stream: WriteHalf<PeerStream<S>>,
+    messages: mpsc::Receiver<anyhow::Result<Message>>,
+    reader: JoinHandle<()>,
+    haves: broadcast::Receiver<u32>,
+    blocks: broadcast::Receiver<(u32, u32)>,
     choked: bool,
+    /// Whether we are choking the peer
+    choking: bool,
+    choker: ChokerHandle,
     bitfield: Bitfield,
+    /// Whether both sides support the fast extension
+    fast: bool,
+    /// The pieces the peer lets us request while it is choking us
+    allowed_fast: HashSet<u32>,
+    /// The pieces we let the peer request while we are choking it
+    offered_fast: HashSet<u32>,
+    extensions: ExtensionRegistry,
+    requests: VecDeque<(u32, u32, u32)>,
+    /// The pieces we are currently downloading from the peer
+    pieces: Vec<PieceProgress>,
+    pipeline: RequestPipeline,
+    /// Where to send peers we learn about from this peer
+    peer_sender: UnboundedSender<Vec<Peer>>,
+    pex: PeerExchange,
+    /// The address the peer accepts connections on, once we know it
+    listen_addr: Option<SocketAddr>,
 }
 
 impl TorrentDownloadWorker {
     pub async fn connect(
-        info_hash: &InfoHash,
-        peer_id: &PeerID,
+        state: Arc<TorrentState>,
         peer: &Peer,
+        peer_sender: UnboundedSender<Vec<Peer>>,
     ) -> anyhow::Result<Self> {
-        let mut stream = tokio::time::timeout(
-            Duration::from_secs(3),
-            TcpStream::connect((peer.ip, peer.port)),
+        let mut stream =
+            mse::connect(peer.addr, &state.torrent.info_hash, &state.transport).await?;
+
+        let (peer_extensions, peer_id) = handshake(
+            &mut stream,
+            local_extensions(&state),
+            &state.torrent.info_hash,
+            &state.peer_id,
         )
-        .await
-        .context("Connection timeout")??;
+        .await?;
+
+        if peer_id == state.peer_id {
+            return Result::Err(anyhow::anyhow!("Connected to ourselves"));
+        }
+        // Trackers using the original dictionary model tell us which peer to expect
+        if let Some(expected) = peer.peer_id {
+            if peer_id != expected {
+                return Result::Err(anyhow::anyhow!(
+                    "Peer id does not match the one the tracker gave us"
+                ));
+            }
+        }
 
-        handshake(&mut stream, info_hash, peer_id).await?;
+        info!("Connected to {} over {}", peer, stream.transport().name());
 
-        info!("Connected to {}", peer);
+        // Peers with nothing to offer may not send a bitfield at all, so we start from an empty
+        // one and pick up whatever the peer tells us about its pieces as it arrives
+        let mut worker = Self::new(stream, *peer, state, peer_extensions, peer_sender).await?;
 
-        let msg = tokio::time::timeout(Duration::from_secs(5), Message::read(&mut stream))
-            .await
-            .context("Timed out waiting for bitfield message")??;
+        // We reached the peer, so other peers can too
+        worker.set_listen_addr(peer.addr, PEX_FLAG_REACHABLE);
+
+        Result::Ok(worker)
+    }
+
+    /// Sets up a worker for a peer that connected to us
+    pub async fn accept(
+        state: Arc<TorrentState>,
+        stream: Transport,
+        peer_sender: UnboundedSender<Vec<Peer>>,
+    ) -> anyhow::Result<Self> {
+        let peer = Peer::new(stream.peer_addr()?);
+        let transport = stream.name();
+        let policy = state.transport.encryption;
+        let mut stream = mse::accept(stream, &state.torrent.info_hash, policy).await?;
+
+        let (peer_extensions, _) = accept_handshake(
+            &mut stream,
+            local_extensions(&state),
+            &state.torrent.info_hash,
+            &state.peer_id,
+        )
+        .await?;
+
+        info!("Accepted connection from {} over {}", peer, transport);
+
+        Self::new(stream, peer, state, peer_extensions, peer_sender).await
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send + 'static> TorrentDownloadWorker<S> {
+    async fn new(
+        stream: PeerStream<S>,
+        peer: Peer,
+        state: Arc<TorrentState>,
+        peer_extensions: Extensions,
+        peer_sender: UnboundedSender<Vec<Peer>>,
+    ) -> anyhow::Result<Self> {
+        let (mut reader, mut stream) = tokio::io::split(stream);
+
+        // Read messages on a separate task so that waiting for the next message can be cancelled
+        // safely while we wait on other events
+        let (message_sender, messages) = mpsc::channel(32);
+        let reader_state = state.clone();
+        let reader = tokio::spawn(async move {
+            loop {
+                let msg = Message::read(&mut reader).await;
+                // Not reading any further until the download limits allow the block also slows the
+                // peer down, once the socket's buffers fill up
+                if let Ok(Message::Piece(_, _, block)) = &msg {
+                    reader_state.throttle_download(block.len()).await;
+                }
+                let failed = msg.is_err();
+                if message_sender.send(msg).await.is_err() || failed {
+                    break;
+                }
+            }
+        });
+
+        // The bitfield is only allowed as the first message after the handshake
+        let fast = peer_extensions.fast();
+        let have = state.bitfield();
+        if fast && state.is_complete() {
+            Message::HaveAll.write(&mut stream).await?;
+        } else if fast && have.is_empty() {
+            Message::HaveNone.write(&mut stream).await?;
+        } else if !have.is_empty() {
+            Message::Bitfield(have).write(&mut stream).await?;
+        }
+
+        // Seeds let peers get started on a few pieces before they are unchoked
+        let mut offered_fast = HashSet::new();
+        if fast && state.is_complete() {
+            offered_fast =
+                allowed_fast_set(peer.addr.ip(), &state.torrent.info_hash, state.num_pieces());
+            for index in &offered_fast {
+                Message::AllowedFast(*index).write(&mut stream).await?;
+            }
+        }
+
+        let extensions = ExtensionRegistry::new(&[UT_PEX]);
+        if peer_extensions.extension_protocol() {
+            ExtensionRegistry::handshake_message(
+                &extensions.handshake(peer.addr.ip(), state.port),
+            )?
+            .write(&mut stream)
+            .await?;
+        }
 
-        match msg {
-            Message::Bitfield(bitfield) => Result::Ok(Self {
-                stream,
-                choked: true,
-                bitfield,
-            }),
-            msg => Result::Err(anyhow::anyhow!(
-                "Invalid message, expected bitfield, got {:?}",
-                msg
-            )),
+        // Tell peers running a DHT node where to find ours
+        if let Some(dht) = state.dht.as_ref().filter(|_| peer_extensions.dht()) {
+            Message::Port(dht.port()).write(&mut stream).await?;
         }
+
+        Result::Ok(Self {
+            peer,
+            haves: state.subscribe_haves(),
+            blocks: state.subscribe_blocks(),
+            bitfield: Bitfield::new(state.num_pieces()),
+            choker: state.choker.register(),
+            state,
+            stream,
+            messages,
+            reader,
+            choked: true,
+            choking: true,
+            fast,
+            allowed_fast: HashSet::new(),
+            offered_fast,
+            extensions,
+            requests: VecDeque::new(),
+            pieces: Vec::new(),
+            pipeline: RequestPipeline::new(),
+            peer_sender,
+            pex: PeerExchange::new(),
+            listen_addr: None,
+        })
     }
 
     pub async fn start(
         &mut self,
-        (download_sender, download_receiver): DownloadChannel,
         result_sender: UnboundedSender<PieceResult>,
     ) -> anyhow::Result<()> {
-        Message::Unchoke.write(&mut self.stream).await?;
-        Message::Interested.write(&mut self.stream).await?;
+        if !self.state.is_complete() {
+            Message::Interested.write(&mut self.stream).await?;
+        }
+
+        while !self.state.is_complete() {
+            if !self.choked || !self.allowed_fast.is_empty() {
+                self.fill_requests().await?;
+            }
 
-        while let Ok(piece_info) = download_receiver.recv().await {
-            if !self.bitfield.has(piece_info.index) {
-                // This peer doesn't have this piece, send it back for another worker to pick up
-                download_sender.send(piece_info).await?;
+            // New pieces may already be complete from blocks other workers wrote to disk, in which
+            // case there may be room to request more
+            let downloading = self.pieces.len();
+            for position in (0..downloading).rev() {
+                self.finish_piece(position, &result_sender)?;
+            }
+            if self.pieces.len() < downloading {
+                continue;
+            }
+
+            if self.pieces.is_empty() {
+                // Nothing to download from this peer right now, wait until they have something new
+                // or another worker gives up on a piece
+                match self.next_event(KEEP_ALIVE_INTERVAL, true).await? {
+                    WorkerEvent::PiecesAvailable => {}
+                    event => {
+                        self.handle_event(event).await?;
+                    }
+                }
                 continue;
             }
 
-            match self.download_piece(&piece_info).await.and_then(|piece| {
-                result_sender
-                    .send(PieceResult::new(piece_info.index, piece))
-                    .map_err(Into::into)
-            }) {
-                Ok(_) => {}
-                Err(error) => {
-                    // If we failed to download a piece, put the piece info back into the queue and
-                    // disconnect from this peer
-                    download_sender.send(piece_info).await?;
-                    return Result::Err(error);
+            let event = self.next_event(REQUEST_TIMEOUT, false).await?;
+            match event {
+                WorkerEvent::Timeout => {
+                    return Result::Err(anyhow::anyhow!("Timed out waiting for blocks"));
+                }
+                WorkerEvent::Block(index, begin) => {
+                    self.handle_shared_block(index, begin, &result_sender)
+                        .await?
+                }
+                event => {
+                    if let Some(Message::Piece(index, begin, block)) =
+                        self.handle_event(event).await?
+                    {
+                        self.handle_block(index, begin, block, &result_sender)
+                            .await?;
+                    }
+                }
+            }
+        }
+
+        self.seed().await
+    }
+
+    /// Keeps serving blocks to the peer once there is nothing left for us to download from them,
+    /// until either side disconnects
+    async fn seed(&mut self) -> anyhow::Result<()> {
+        loop {
+            if self.state.is_complete() && self.bitfield.is_complete(self.state.num_pieces()) {
+                info!(
+                    "Disconnecting from {}, both sides have the whole torrent",
+                    self.peer
+                );
+                return Result::Ok(());
+            }
+
+            let event = self.next_event(KEEP_ALIVE_INTERVAL, false).await?;
+            self.handle_event(event).await?;
+        }
+    }
+
+    /// Waits for the next event on this connection, serving any requests the peer has queued up in
+    /// the meantime. With `wait_for_pieces`, also returns once there may be new pieces to download.
+    async fn next_event(
+        &mut self,
+        timeout: Duration,
+        wait_for_pieces: bool,
+    ) -> anyhow::Result<WorkerEvent> {
+        // Register for new pieces before serving any requests, so that a notification sent while
+        // we are busy uploading isn't missed
+        let state = self.state.clone();
+        let pieces_available = state.pieces_available();
+        tokio::pin!(pieces_available);
+        pieces_available.as_mut().enable();
+
+        loop {
+            if !self.requests.is_empty() {
+                // Handle anything already received first, so that cancels are honoured before we
+                // serve the next request
+                match self.messages.try_recv() {
+                    Ok(msg) => return Result::Ok(WorkerEvent::Message(msg?)),
+                    Err(TryRecvError::Empty) => {
+                        // Don't let a long queue of requests hold up peer exchange
+                        if self.extensions.supports(UT_PEX)
+                            && tokio::time::Instant::now() >= self.pex.deadline()
+                        {
+                            return Result::Ok(WorkerEvent::Pex);
+                        }
+                        self.serve_request().await?;
+                        continue;
+                    }
+                    Err(TryRecvError::Disconnected) => {
+                        return Result::Err(anyhow::anyhow!("Peer disconnected"))
+                    }
+                }
+            }
+
+            let event = tokio::time::timeout(timeout, async {
+                tokio::select! {
+                    msg = self.messages.recv() => match msg {
+                        Some(msg) => msg.map(|msg| Some(WorkerEvent::Message(msg))),
+                        None => Result::Err(anyhow::anyhow!("Peer disconnected")),
+                    },
+                    index = self.haves.recv() => match index {
+                        Ok(index) => Result::Ok(Some(WorkerEvent::Have(index))),
+                        // Missing a have is harmless, the peer can still request the piece
+                        Err(_) => Result::Ok(None),
+                    },
+                    block = self.blocks.recv() => match block {
+                        Ok((index, begin)) => Result::Ok(Some(WorkerEvent::Block(index, begin))),
+                        // Missing a block only means we download it again ourselves
+                        Err(_) => Result::Ok(None),
+                    },
+                    _ = tokio::time::sleep_until(self.pex.deadline()),
+                        if self.extensions.supports(UT_PEX) =>
+                    {
+                        Result::Ok(Some(WorkerEvent::Pex))
+                    }
+                    Ok(()) = self.choker.unchoked.changed() => {
+                        let unchoked = *self.choker.unchoked.borrow();
+                        Result::Ok(Some(WorkerEvent::Choke(!unchoked)))
+                    }
+                    _ = &mut pieces_available, if wait_for_pieces => {
+                        Result::Ok(Some(WorkerEvent::PiecesAvailable))
+                    }
+                }
+            })
+            .await;
+
+            match event {
+                Err(_) => return Result::Ok(WorkerEvent::Timeout),
+                Ok(event) => {
+                    if let Some(event) = event? {
+                        return Result::Ok(event);
+                    }
                 }
             }
         }
+    }
+
+    /// Sends requests until as many are in flight as the peer can keep up with, starting on new
+    /// pieces once every block of the current ones has been requested so the queue never drains
+    /// between pieces
+    async fn fill_requests(&mut self) -> anyhow::Result<()> {
+        let max_depth = self
+            .extensions
+            .peer_handshake()
+            .and_then(|handshake| handshake.reqq);
+        let depth = self.pipeline.depth(max_depth) as usize;
+
+        while self.pipeline.len() < depth {
+            // Only allowed fast pieces can be requested while the peer is choking us
+            let request = self
+                .pieces
+                .iter_mut()
+                .filter(|piece| !self.choked || self.allowed_fast.contains(&piece.index()))
+                .find_map(|piece| {
+                    piece
+                        .next_request()
+                        .map(|(begin, length)| (piece.index(), begin, length))
+                });
+
+            let (index, begin, length) = match request {
+                Some(request) => request,
+                None => {
+                    if !self.start_piece().await? {
+                        break;
+                    }
+                    continue;
+                }
+            };
+
+            Message::Request(index, begin, length)
+                .write(&mut self.stream)
+                .await?;
+            self.pipeline.request_sent(index, begin);
+        }
+        self.choker.stats.set_waiting(self.pipeline.len() > 0);
 
         Result::Ok(())
     }
 
-    async fn download_piece(&mut self, piece_info: &PieceInfo) -> anyhow::Result<Vec<u8>> {
-        let mut progress = PieceProgress {
-            buf: vec![0u8; piece_info.length as usize],
-            downloaded: 0,
-            requested: 0,
-            backlog: 0,
+    /// Picks another piece to download from the peer, returning false if there is none
+    async fn start_piece(&mut self) -> anyhow::Result<bool> {
+        let downloading: Vec<u32> = self.pieces.iter().map(PieceProgress::index).collect();
+        let available = if self.choked {
+            let mut available = Bitfield::new(self.state.num_pieces());
+            for index in self.allowed_fast.iter().filter(|i| self.bitfield.has(**i)) {
+                available.set(*index);
+            }
+            available
+        } else {
+            self.bitfield.clone()
+        };
+        let Some(index) = self.state.pick_piece(&available, &downloading) else {
+            return Result::Ok(false);
         };
 
-        while progress.downloaded < piece_info.length {
-            if !self.choked {
-                while progress.backlog < MAX_BACKLOG && progress.requested < piece_info.length {
-                    let block_size =
-                        u32::min(MAX_BLOCK_SIZE, piece_info.length - progress.requested);
+        // Track the piece straight away so it is given back to the picker if anything fails
+        self.pieces
+            .push(PieceProgress::new(self.state.piece_info(index)));
+        let piece = self.pieces.last_mut().unwrap();
 
-                    Message::Request(piece_info.index, progress.requested, block_size)
-                        .write(&mut self.stream)
-                        .await?;
-                    progress.backlog += 1;
-                    progress.requested += block_size;
+        // Pick up any blocks of this piece that were written to disk before a restart, or by
+        // other workers downloading the same piece in endgame mode
+        if let Some(blocks) = self.state.partial_blocks(index) {
+            for block in 0..piece.num_blocks() {
+                if blocks.has(block) {
+                    read_block(&self.state, piece, block * MAX_BLOCK_SIZE).await?;
                 }
             }
+        }
+
+        Result::Ok(true)
+    }
+
+    /// Handles a block sent by the peer
+    async fn handle_block(
+        &mut self,
+        index: u32,
+        begin: u32,
+        block: Vec<u8>,
+        result_sender: &UnboundedSender<PieceResult>,
+    ) -> anyhow::Result<()> {
+        self.pipeline.block_received(index, begin, block.len());
+        self.choker.stats.add_downloaded(block.len() as u64);
+
+        let Some(position) = self.pieces.iter().position(|piece| piece.index() == index) else {
+            // Blocks we cancelled may still arrive for pieces we have since finished
+            if !self.state.has(index) {
+                warn!("Received block for piece {} we didn't request", index);
+            }
+            return Result::Ok(());
+        };
+
+        // A block we already cancelled may still arrive if the peer sent it before the cancel
+        let piece = &mut self.pieces[position];
+        if piece.has_block(begin) {
+            return Result::Ok(());
+        }
+
+        piece.add_block(begin, &block)?;
+        self.state.write_block(index, begin, &block).await?;
+
+        self.finish_piece(position, result_sender)
+    }
+
+    /// Handles a block written by another worker downloading the same piece in endgame mode,
+    /// cancelling our own request for it
+    async fn handle_shared_block(
+        &mut self,
+        index: u32,
+        begin: u32,
+        result_sender: &UnboundedSender<PieceResult>,
+    ) -> anyhow::Result<()> {
+        let Some(position) = self.pieces.iter().position(|piece| piece.index() == index) else {
+            return Result::Ok(());
+        };
 
-            let msg =
-                tokio::time::timeout(Duration::from_secs(30), Message::read(&mut self.stream))
-                    .await??;
+        let piece = &mut self.pieces[position];
+        if piece.has_block(begin) {
+            return Result::Ok(());
+        }
+
+        if piece.is_pending(begin) {
+            Message::Cancel(index, begin, piece.block_size(begin))
+                .write(&mut self.stream)
+                .await?;
+            self.pipeline.cancel(index, begin);
+        }
+        read_block(&self.state, piece, begin).await?;
+
+        self.finish_piece(position, result_sender)
+    }
 
-            match msg {
+    /// Verifies a piece once every block has arrived, and reports it to the client
+    fn finish_piece(
+        &mut self,
+        position: usize,
+        result_sender: &UnboundedSender<PieceResult>,
+    ) -> anyhow::Result<()> {
+        if !self.pieces[position].is_complete() {
+            return Result::Ok(());
+        }
+
+        let piece = self.pieces.swap_remove(position);
+        if !piece.verify() {
+            self.state.clear_partial(piece.index());
+            self.state.abort_piece(piece.index());
+            return Result::Err(anyhow::anyhow!(
+                "Failed integrity check for piece {}",
+                piece.index()
+            ));
+        }
+
+        result_sender.send(PieceResult::new(piece.index()))?;
+
+        Result::Ok(())
+    }
+
+    /// Handles an event on the connection, returning any piece message for the caller to process
+    async fn handle_event(&mut self, event: WorkerEvent) -> anyhow::Result<Option<Message>> {
+        match event {
+            WorkerEvent::Message(msg) => match msg {
                 Message::Choke => {
+                    // The peer drops every request it hasn't served yet when it chokes us, unless
+                    // it supports the fast extension, in which case it rejects each of them
                     self.choked = true;
+                    if !self.fast {
+                        self.pipeline.clear();
+                        for piece in &mut self.pieces {
+                            piece.reset_requests();
+                        }
+                    }
+                    // A peer that chokes us isn't snubbing us, it just isn't going to send more
+                    self.choker.stats.set_waiting(false);
                 }
                 Message::Unchoke => {
                     self.choked = false;
                 }
-                Message::Have(index) => {
+                Message::Interested => {
+                    self.choker.stats.set_interested(true);
+                    self.state.choker.peer_interested();
+                }
+                Message::NotInterested => self.choker.stats.set_interested(false),
+                Message::Have(index)
+                    if (index as usize) < self.state.num_pieces() && !self.bitfield.has(index) =>
+                {
                     self.bitfield.set(index);
+                    self.state.add_peer_have(index);
+                    self.update_seed_flag();
                 }
-                Message::Piece(index, begin, block) => {
-                    if index != piece_info.index {
-                        warn!(
-                            "Received incorrect piece index: expected {}, got {}",
-                            piece_info.index, index
-                        );
-                        continue;
-                    }
+                Message::Bitfield(bitfield) => self.replace_bitfield(bitfield),
+                Message::HaveAll if self.fast => {
+                    self.replace_bitfield(Bitfield::full(self.state.num_pieces()))
+                }
+                Message::HaveNone if self.fast => {
+                    self.replace_bitfield(Bitfield::new(self.state.num_pieces()))
+                }
+                Message::Request(index, begin, length) => {
+                    self.queue_request(index, begin, length).await?;
+                }
+                Message::Cancel(index, begin, length) => {
+                    let queued = self.requests.len();
+                    self.requests.retain(|r| *r != (index, begin, length));
 
-                    let b = begin as usize;
-                    if b >= progress.buf.len() {
-                        return Result::Err(anyhow::anyhow!(
-                            "Block begin offset {} too high for piece buffer size {}",
-                            begin,
-                            progress.buf.len()
-                        ));
+                    // The fast extension has every request answered, even cancelled ones
+                    if self.fast && self.requests.len() < queued {
+                        Message::RejectRequest(index, begin, length)
+                            .write(&mut self.stream)
+                            .await?;
                     }
-
-                    if b + block.len() > progress.buf.len() {
-                        return Result::Err(anyhow::anyhow!(
-                            "Block buffer of size {} at begin {} too large to put into piece buffer of size {}",
-                            block.len(),
-                            b,
-                            progress.buf.len()
-                        ));
+                }
+                Message::RejectRequest(index, begin, _) if self.fast => {
+                    self.handle_reject(index, begin);
+                }
+                Message::AllowedFast(index)
+                    if self.fast && (index as usize) < self.state.num_pieces() =>
+                {
+                    self.allowed_fast.insert(index);
+                }
+                Message::Extended(id, payload) => self.handle_extended(id, payload)?,
+                Message::Port(port) => {
+                    if let Some(dht) = &self.state.dht {
+                        dht.add_node(SocketAddr::new(self.peer.addr.ip(), port));
                     }
-
-                    progress.buf[b..b + block.len()].copy_from_slice(&block);
-                    progress.downloaded += block.len() as u32;
-                    progress.backlog -= 1;
                 }
+                Message::Piece(..) => return Result::Ok(Some(msg)),
                 _ => {}
+            },
+            WorkerEvent::Have(index) => {
+                Message::Have(index).write(&mut self.stream).await?;
+            }
+            // Only relevant while downloading, which is handled in start
+            WorkerEvent::Block(..) | WorkerEvent::PiecesAvailable => {}
+            WorkerEvent::Pex => self.send_pex().await?,
+            WorkerEvent::Choke(choke) => self.set_choking(choke).await?,
+            WorkerEvent::Timeout => {
+                Message::KeepAlive.write(&mut self.stream).await?;
             }
         }
 
-        let mut sha1 = Sha1::new();
-        sha1.update(&progress.buf);
-        let hash: PieceHash = sha1.finalize().try_into()?;
-        if hash != piece_info.hash {
-            return Result::Err(anyhow::anyhow!(
-                "Failed integrity check for piece {}",
-                piece_info.index
+        Result::Ok(None)
+    }
+
+    /// Chokes or unchokes the peer. Requests queued up while unchoked are dropped on choking,
+    /// except for allowed fast pieces, and rejected if the peer supports the fast extension.
+    async fn set_choking(&mut self, choke: bool) -> anyhow::Result<()> {
+        if choke == self.choking {
+            return Result::Ok(());
+        }
+        self.choking = choke;
+        if !choke {
+            return Message::Unchoke.write(&mut self.stream).await;
+        }
+
+        Message::Choke.write(&mut self.stream).await?;
+        let requests = std::mem::take(&mut self.requests);
+        for (index, begin, length) in requests {
+            if self.offered_fast.contains(&index) {
+                self.requests.push_back((index, begin, length));
+            } else if self.fast {
+                Message::RejectRequest(index, begin, length)
+                    .write(&mut self.stream)
+                    .await?;
+            }
+        }
+
+        Result::Ok(())
+    }
+
+    fn replace_bitfield(&mut self, bitfield: Bitfield) {
+        self.state.remove_peer_bitfield(&self.bitfield);
+        self.state.add_peer_bitfield(&bitfield);
+        self.bitfield = bitfield;
+        self.update_seed_flag();
+    }
+
+    /// Gives a rejected block back to be requested again, and the whole piece back to the picker
+    /// if we can't request it from the peer while it is choking us
+    fn handle_reject(&mut self, index: u32, begin: u32) {
+        self.pipeline.cancel(index, begin);
+        let Some(position) = self.pieces.iter().position(|piece| piece.index() == index) else {
+            return;
+        };
+
+        self.pieces[position].reset_request(begin);
+        if self.choked && !self.allowed_fast.contains(&index) {
+            let piece = self.pieces.swap_remove(position);
+            self.state.abort_piece(piece.index());
+        }
+    }
+
+    /// Queues up a request to be served, rejecting it if the peer supports the fast extension and
+    /// we can't serve it
+    async fn queue_request(&mut self, index: u32, begin: u32, length: u32) -> anyhow::Result<()> {
+        // The index has to be checked before looking up the piece's bounds
+        let valid = (index as usize) < self.state.num_pieces()
+            && self.state.has(index)
+            && length <= MAX_REQUEST_SIZE
+            && begin as u64 + length as u64 <= self.state.piece_bounds(index).1;
+
+        if !valid {
+            warn!(
+                "Ignoring invalid request from {} for piece {} at {} of length {}",
+                self.peer, index, begin, length
+            );
+        }
+
+        let allowed = !self.choking || self.offered_fast.contains(&index);
+        if valid && allowed && self.requests.len() < LOCAL_REQQ as usize {
+            self.requests.push_back((index, begin, length));
+        } else if self.fast {
+            Message::RejectRequest(index, begin, length)
+                .write(&mut self.stream)
+                .await?;
+        }
+
+        Result::Ok(())
+    }
+
+    async fn serve_request(&mut self) -> anyhow::Result<()> {
+        if let Some((index, begin, length)) = self.requests.pop_front() {
+            self.state.throttle_upload(length as usize).await;
+            let (offset, _) = self.state.piece_bounds(index);
+            let block = self
+                .state
+                .writer
+                .lock()
+                .await
+                .read(offset + begin as u64, length as usize)
+                .await?;
+            self.state.add_uploaded(block.len() as u64);
+            self.choker.stats.add_uploaded(block.len() as u64);
+            Message::Piece(index, begin, block)
+                .write(&mut self.stream)
+                .await?;
+        }
+
+        Result::Ok(())
+    }
+
+    fn handle_extended(&mut self, id: u8, payload: Vec<u8>) -> anyhow::Result<()> {
+        match self.extensions.decode(id, payload)? {
+            Some(ExtendedMessage::Handshake) => {
+                let Some(handshake) = self.extensions.peer_handshake() else {
+                    return Result::Ok(());
+                };
+                info!(
+                    "Peer client {}, listening on port {:?}, queue depth {:?}",
+                    handshake
+                        .v
+                        .as_ref()
+                        .map_or("unknown".into(), |v| String::from_utf8_lossy(v)),
+                    handshake.p,
+                    handshake.reqq
+                );
+
+                // Peers that connected to us can only be passed on if they tell us their port
+                if let (None, Some(port)) = (self.listen_addr, handshake.p) {
+                    let addr = SocketAddr::new(self.peer.addr.ip(), port);
+                    self.set_listen_addr(addr, 0);
+                }
+            }
+            Some(ExtendedMessage::Extension(UT_PEX, payload)) => self.handle_pex(&payload)?,
+            _ => {}
+        }
+
+        Result::Ok(())
+    }
+
+    /// Records where the peer accepts connections, so we can tell other peers about it
+    fn set_listen_addr(&mut self, addr: SocketAddr, mut flags: u8) {
+        if let Some(previous) = self.listen_addr.replace(addr) {
+            self.state.remove_connected(previous);
+        }
+        if self.bitfield.is_complete(self.state.num_pieces()) {
+            flags |= PEX_FLAG_SEED;
+        }
+        self.state.add_connected(addr, flags);
+    }
+
+    /// Tells other peers the peer is a seed once we learn it has every piece
+    fn update_seed_flag(&self) {
+        if let Some(addr) = self.listen_addr {
+            if self.bitfield.is_complete(self.state.num_pieces()) {
+                self.state.add_connected_flags(addr, PEX_FLAG_SEED);
+            }
+        }
+    }
+
+    /// Passes the peers the peer told us about on to the client to connect to
+    fn handle_pex(&mut self, payload: &[u8]) -> anyhow::Result<()> {
+        let msg = serde_bencode::from_bytes::<PexMessage>(payload)?;
+        let added = msg.added()?;
+        info!(
+            "Peer exchange from {}: {} added, {} dropped",
+            self.peer,
+            added.len(),
+            msg.num_dropped()
+        );
+
+        // Seeds have nothing to offer us once we are seeding too
+        let seeding = self.state.is_complete();
+        let peers = added
+            .into_iter()
+            .filter(|peer| !(seeding && peer.flags & PEX_FLAG_SEED != 0))
+            .map(|peer| Peer::new(peer.addr))
+            .collect::<Vec<_>>();
+        if !peers.is_empty() {
+            // The client is only gone when shutting down
+            let _ = self.peer_sender.send(peers);
+        }
+
+        Result::Ok(())
+    }
+
+    /// Tells the peer which peers we connected to or disconnected from since the last time
+    async fn send_pex(&mut self) -> anyhow::Result<()> {
+        let connected: Vec<PexPeer> = self
+            .state
+            .connected_peers()
+            .into_iter()
+            .filter(|peer| Some(peer.addr) != self.listen_addr)
+            .collect();
+
+        if let Some(msg) = self.pex.next_message(&connected) {
+            if let Some(msg) = self
+                .extensions
+                .message(UT_PEX, serde_bencode::to_bytes(&msg)?)
+            {
+                msg.write(&mut self.stream).await?;
+            }
+        }
+
+        Result::Ok(())
+    }
+}
+
+impl<S> Drop for TorrentDownloadWorker<S> {
+    fn drop(&mut self) {
+        // Give any pieces we didn't finish back to the picker so other peers can download them
+        for piece in &self.pieces {
+            self.state.abort_piece(piece.index());
+        }
+        self.state.remove_peer_bitfield(&self.bitfield);
+        self.state.choker.unregister(&self.choker);
+        if let Some(addr) = self.listen_addr {
+            self.state.remove_connected(addr);
+        }
+        self.reader.abort();
+    }
+}
+
+/// The extensions we advertise in our handshake
+fn local_extensions(state: &TorrentState) -> Extensions {
+    match state.dht {
+        Some(_) => Extensions::supported().with_fast().with_dht(),
+        None => Extensions::supported().with_fast(),
+    }
+}
+
+/// Copies a block of a piece that has already been written to disk into the piece buffer
+async fn read_block(
+    state: &TorrentState,
+    piece: &mut PieceProgress,
+    begin: u32,
+) -> anyhow::Result<()> {
+    let (offset, _) = state.piece_bounds(piece.index());
+    let block = state
+        .writer
+        .lock()
+        .await
+        .read(offset + begin as u64, piece.block_size(begin) as usize)
+        .await?;
+
+    piece.add_block(begin, &block)
+}
+
+#[cfg(test)]
+mod tests {
+    use sha1::{Digest, Sha1};
+    use tokio::io::DuplexStream;
+
+    use super::*;
+    use crate::ratelimit::RateLimits;
+    use crate::torrent::{Torrent, TorrentFile};
+    use crate::writer::TorrentWriter;
+
+    const PIECE_LENGTH: u64 = 2 * MAX_BLOCK_SIZE as u64;
+
+    /// Sets up a torrent of `data` backed by a file in the temp directory, which already holds the
+    /// whole torrent if `seeding`
+    async fn test_state(name: &str, data: &[u8], seeding: bool) -> Arc<TorrentState> {
+        let path = std::env::temp_dir().join(format!("rustor-worker-test-{}", name));
+        let _ = tokio::fs::remove_file(&path).await;
+        let torrent = Torrent {
+            name: name.to_owned(),
+            announce_list: Vec::new(),
+            length: data.len() as u64,
+            info_hash: [1; 20],
+            piece_length: PIECE_LENGTH,
+            piece_hashes: data
+                .chunks(PIECE_LENGTH as usize)
+                .map(|piece| Sha1::digest(piece).into())
+                .collect(),
+            files: vec![TorrentFile {
+                length: data.len() as u64,
+                path,
+            }],
+        };
+
+        let mut writer = TorrentWriter::from_torrent(&torrent).await.unwrap();
+        if seeding {
+            writer.write(0, data).await.unwrap();
+        }
+        let have = writer.verify(&torrent).await.unwrap();
+
+        let transport = TransportConfig {
+            encryption: EncryptionPolicy::Plaintext,
+            utp: None,
+            limits: Arc::new(RateLimits::default()),
+        };
+        Arc::new(TorrentState::new(
+            torrent, [2; 20], 6881, None, transport, writer, have,
+        ))
+    }
+
+    /// Sets up a worker talking to a peer over an in-memory stream, returning the peer's end
+    async fn test_worker(
+        state: Arc<TorrentState>,
+        extensions: Extensions,
+    ) -> (TorrentDownloadWorker<DuplexStream>, DuplexStream) {
+        let (local, remote) = tokio::io::duplex(1 << 20);
+        let (peer_sender, _) = mpsc::unbounded_channel();
+        let peer = Peer::new("127.0.0.1:6881".parse().unwrap());
+        let worker = TorrentDownloadWorker::new(
+            PeerStream::new(local, Vec::new()),
+            peer,
+            state,
+            extensions,
+            peer_sender,
+        )
+        .await
+        .unwrap();
+
+        (worker, remote)
+    }
+
+    /// Tells a worker the peer has every piece and unchokes it, then reads the requests it sends
+    async fn start_downloading(
+        worker: &mut TorrentDownloadWorker<DuplexStream>,
+        remote: &mut DuplexStream,
+    ) -> Vec<(u32, u32)> {
+        for msg in [Message::Have(0), Message::Unchoke] {
+            worker
+                .handle_event(WorkerEvent::Message(msg))
+                .await
+                .unwrap();
+        }
+        worker.fill_requests().await.unwrap();
+
+        let mut requests = Vec::new();
+        for _ in 0..worker.pipeline.len() {
+            let Message::Request(index, begin, _) = Message::read(remote).await.unwrap() else {
+                panic!("expected a request");
+            };
+            requests.push((index, begin));
+        }
+        requests
+    }
+
+    #[tokio::test]
+    async fn cancels_blocks_other_workers_received_in_endgame() {
+        let data: Vec<u8> = (0..PIECE_LENGTH).map(|i| i as u8).collect();
+        let state = test_state("endgame", &data, false).await;
+        let (mut a, mut remote_a) = test_worker(state.clone(), Extensions::default()).await;
+        let (mut b, mut remote_b) = test_worker(state.clone(), Extensions::default()).await;
+        let blocks = vec![(0, 0), (0, MAX_BLOCK_SIZE)];
+
+        // Once the first worker is downloading the only piece, endgame mode hands it out again
+        assert_eq!(start_downloading(&mut a, &mut remote_a).await, blocks);
+        assert_eq!(start_downloading(&mut b, &mut remote_b).await, blocks);
+
+        let (result_sender, mut results) = mpsc::unbounded_channel();
+        for (index, begin) in blocks {
+            let block = data[begin as usize..(begin + MAX_BLOCK_SIZE) as usize].to_vec();
+            a.handle_block(index, begin, block, &result_sender)
+                .await
+                .unwrap();
+
+            // The other worker hears about the block, cancels its own request and stops waiting
+            // for it
+            let event = b.next_event(REQUEST_TIMEOUT, false).await.unwrap();
+            assert!(matches!(event, WorkerEvent::Block(0, received) if received == begin));
+            b.handle_shared_block(index, begin, &result_sender)
+                .await
+                .unwrap();
+            assert!(matches!(
+                Message::read(&mut remote_b).await.unwrap(),
+                Message::Cancel(0, cancelled, MAX_BLOCK_SIZE) if cancelled == begin
             ));
         }
 
-        Message::Have(piece_info.index)
-            .write(&mut self.stream)
-            .await?;
+        assert_eq!(b.pipeline.len(), 0);
+        assert!(b.pieces.is_empty());
+        // Both workers end up with the whole piece, from their own peer or from disk
+        assert_eq!(results.recv().await.unwrap().index, 0);
+        assert_eq!(results.recv().await.unwrap().index, 0);
+    }
 
-        Result::Ok(progress.buf)
+    /// Handles the next message the peer sent
+    async fn receive(worker: &mut TorrentDownloadWorker<DuplexStream>) {
+        let event = worker.next_event(KEEP_ALIVE_INTERVAL, false).await.unwrap();
+        assert!(matches!(event, WorkerEvent::Message(_)));
+        worker.handle_event(event).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn accepts_peers_that_send_no_bitfield() {
+        let data = vec![7; 3 * PIECE_LENGTH as usize];
+        let state = test_state("no-bitfield", &data, false).await;
+        let (mut worker, mut remote) = test_worker(state.clone(), Extensions::default()).await;
+        let addr = worker.peer.addr;
+        worker.set_listen_addr(addr, PEX_FLAG_REACHABLE);
+        let flags = || state.connected_peers()[0].flags;
+
+        // The peer goes straight to haves and unchoking us instead of sending a bitfield
+        for msg in [Message::Have(2), Message::Unchoke, Message::Have(0)] {
+            msg.write(&mut remote).await.unwrap();
+            receive(&mut worker).await;
+        }
+        assert!(!worker.choked);
+        assert!(worker.bitfield.has(0) && !worker.bitfield.has(1) && worker.bitfield.has(2));
+        assert_eq!(flags(), PEX_FLAG_REACHABLE);
+
+        // Its haves are enough to find something to download
+        worker.fill_requests().await.unwrap();
+        assert!(matches!(
+            Message::read(&mut remote).await.unwrap(),
+            Message::Request(0 | 2, 0, MAX_BLOCK_SIZE)
+        ));
+
+        // Once the haves add up to every piece, other peers are told it is a seed
+        Message::Have(1).write(&mut remote).await.unwrap();
+        receive(&mut worker).await;
+        assert!(worker.bitfield.is_complete(3));
+        assert_eq!(flags(), PEX_FLAG_REACHABLE | PEX_FLAG_SEED);
+    }
+
+    #[tokio::test]
+    async fn ignores_requests_for_pieces_out_of_range() {
+        let data = vec![7; 3 * PIECE_LENGTH as usize];
+        let state = test_state("out-of-range", &data, true).await;
+        let (mut worker, _remote) = test_worker(state, Extensions::default()).await;
+        worker.choking = false;
+
+        for index in [3, 8, 1000, u32::MAX] {
+            let msg = Message::Request(index, 0, MAX_BLOCK_SIZE);
+            worker
+                .handle_event(WorkerEvent::Message(msg))
+                .await
+                .unwrap();
+        }
+        assert!(worker.requests.is_empty());
+
+        let msg = Message::Request(2, 0, MAX_BLOCK_SIZE);
+        worker
+            .handle_event(WorkerEvent::Message(msg))
+            .await
+            .unwrap();
+        assert_eq!(worker.requests, [(2, 0, MAX_BLOCK_SIZE)]);
+    }
+
+    #[tokio::test]
+    async fn rejects_requests_for_pieces_out_of_range() {
+        let data = vec![7; 3 * PIECE_LENGTH as usize];
+        let state = test_state("out-of-range-fast", &data, false).await;
+        let (mut worker, mut remote) = test_worker(state, Extensions::default().with_fast()).await;
+        assert!(matches!(
+            Message::read(&mut remote).await.unwrap(),
+            Message::HaveNone
+        ));
+
+        let msg = Message::Request(5, 0, MAX_BLOCK_SIZE);
+        worker
+            .handle_event(WorkerEvent::Message(msg))
+            .await
+            .unwrap();
+        assert!(worker.requests.is_empty());
+        assert!(matches!(
+            Message::read(&mut remote).await.unwrap(),
+            Message::RejectRequest(5, 0, MAX_BLOCK_SIZE)
+        ));
     }
 }
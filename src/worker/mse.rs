@@ -0,0 +1,602 @@
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{ready, Context as TaskContext, Poll};
+use std::time::Duration;
+
+use anyhow::Context;
+use num_bigint::BigUint;
+use rand::Rng;
+use sha1::{Digest, Sha1};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tracing::info;
+
+use super::transport::{self, Transport, TransportConfig};
+use crate::types::InfoHash;
+
+// The prime and generator of the Diffie-Hellman key exchange
+const DH_PRIME: &str = "FFFFFFFFFFFFFFFFC90FDAA22168C234C4C6628B80DC1CD129024E088A67CC74020BBEA63B139B225\
+                        14A08798E3404DDEF9519B3CD3A431B302B0A6DF25F14374FE1356D6D51C245E485B576625E7EC6\
+                        F44C42E9A63A36210000000000090563";
+const DH_GENERATOR: u32 = 2;
+
+// The length of a public key, and of the shared secret
+const KEY_LEN: usize = 96;
+
+// The length of a private key
+const PRIVATE_KEY_LEN: usize = 20;
+
+// The most random padding either side sends after its public key or in the handshake
+const MAX_PAD_LEN: usize = 512;
+
+// The verification constant, sent encrypted so each side can check the other derived the same keys
+const VC: [u8; 8] = [0u8; 8];
+
+// The bytes of the RC4 keystream thrown away, since the start of it leaks information about the key
+const RC4_DISCARD: usize = 1024;
+
+// The ways of protecting the connection after the handshake
+const CRYPTO_PLAINTEXT: u32 = 0x01;
+const CRYPTO_RC4: u32 = 0x02;
+
+// The start of a plaintext handshake, used to tell them apart from encrypted ones
+const PLAINTEXT_HEADER: &[u8] = b"\x13BitTorrent protocol";
+
+// How long the encryption handshake can take before we give up on it
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Whether connections to peers are encrypted with message stream encryption
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum EncryptionPolicy {
+    /// Only use plaintext connections
+    Plaintext,
+    /// Encrypt connections, falling back to plaintext for peers that don't support encryption
+    Prefer,
+    /// Only use encrypted connections
+    Require,
+}
+
+/// A connection to a peer, encrypted with RC4 if the peers agreed to it during the handshake
+pub struct PeerStream<S = Transport> {
+    stream: S,
+    /// Data the peer sent along with the encryption handshake, already decrypted
+    buffered: Vec<u8>,
+    read_cipher: Option<Rc4>,
+    write_cipher: Option<Rc4>,
+}
+
+impl<S> PeerStream<S> {
+    pub(super) fn new(stream: S, buffered: Vec<u8>) -> Self {
+        Self {
+            stream,
+            buffered,
+            read_cipher: None,
+            write_cipher: None,
+        }
+    }
+
+    fn encrypted(stream: S, buffered: Vec<u8>, read: Rc4, write: Rc4) -> Self {
+        Self {
+            stream,
+            buffered,
+            read_cipher: Some(read),
+            write_cipher: Some(write),
+        }
+    }
+}
+
+impl PeerStream {
+    /// The connection underneath the encryption
+    pub fn transport(&self) -> &Transport {
+        &self.stream
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for PeerStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if !this.buffered.is_empty() {
+            let len = usize::min(buf.remaining(), this.buffered.len());
+            buf.put_slice(&this.buffered[..len]);
+            this.buffered.drain(..len);
+            return Poll::Ready(Ok(()));
+        }
+
+        let filled = buf.filled().len();
+        ready!(Pin::new(&mut this.stream).poll_read(cx, buf))?;
+        if let Some(cipher) = &mut this.read_cipher {
+            cipher.apply(&mut buf.filled_mut()[filled..]);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for PeerStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let Some(cipher) = &mut this.write_cipher else {
+            return Pin::new(&mut this.stream).poll_write(cx, buf);
+        };
+
+        // Encrypt with a copy of the cipher, only moving the real one on by what was written so
+        // the rest can be encrypted again when the caller retries
+        let mut encrypted = buf.to_vec();
+        cipher.clone().apply(&mut encrypted);
+        let written = ready!(Pin::new(&mut this.stream).poll_write(cx, &encrypted))?;
+        cipher.skip(written);
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_shutdown(cx)
+    }
+}
+
+/// Connects to a peer, encrypting the connection as the policy asks. Peers that fail the
+/// encryption handshake are connected to again in plaintext if we only prefer encryption.
+pub async fn connect(
+    addr: SocketAddr,
+    info_hash: &InfoHash,
+    config: &TransportConfig,
+) -> anyhow::Result<PeerStream> {
+    let utp = config.utp.as_ref();
+    let policy = config.encryption;
+    let stream = transport::connect(addr, utp).await?;
+    let provide = match policy {
+        EncryptionPolicy::Plaintext => return Result::Ok(PeerStream::new(stream, Vec::new())),
+        EncryptionPolicy::Prefer => CRYPTO_RC4 | CRYPTO_PLAINTEXT,
+        EncryptionPolicy::Require => CRYPTO_RC4,
+    };
+
+    let result = tokio::time::timeout(HANDSHAKE_TIMEOUT, initiate(stream, info_hash, provide))
+        .await
+        .context("Encryption handshake timeout");
+    match result {
+        Ok(Ok(stream)) => Result::Ok(stream),
+        Ok(Err(error)) | Err(error) if policy == EncryptionPolicy::Prefer => {
+            info!(
+                "Encryption handshake with {} failed, retrying in plaintext: {}",
+                addr, error
+            );
+            Result::Ok(PeerStream::new(
+                transport::connect(addr, utp).await?,
+                Vec::new(),
+            ))
+        }
+        Ok(Err(error)) | Err(error) => Result::Err(error),
+    }
+}
+
+/// Sets up a connection a peer made to us, taking part in the encryption handshake if the peer
+/// started one and the policy allows it
+pub async fn accept(
+    mut stream: Transport,
+    info_hash: &InfoHash,
+    policy: EncryptionPolicy,
+) -> anyhow::Result<PeerStream> {
+    // An encrypted handshake starts with a public key, which is longer than the header of a
+    // plaintext one
+    let mut header = vec![0u8; PLAINTEXT_HEADER.len()];
+    stream.read_exact(&mut header).await?;
+
+    if header == PLAINTEXT_HEADER {
+        if policy == EncryptionPolicy::Require {
+            return Result::Err(anyhow::anyhow!("Peer did not encrypt the connection"));
+        }
+        return Result::Ok(PeerStream::new(stream, header));
+    }
+
+    if policy == EncryptionPolicy::Plaintext {
+        return Result::Err(anyhow::anyhow!("Peer tried to encrypt the connection"));
+    }
+    tokio::time::timeout(
+        HANDSHAKE_TIMEOUT,
+        respond(stream, &header, info_hash, policy),
+    )
+    .await
+    .context("Encryption handshake timeout")?
+}
+
+/// Performs the encryption handshake in the initiator role, offering the methods in `provide`
+async fn initiate<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
+    info_hash: &InfoHash,
+    provide: u32,
+) -> anyhow::Result<PeerStream<S>> {
+    let keys = KeyPair::generate();
+    let mut msg = keys.public.to_vec();
+    msg.extend(random_pad());
+    stream.write_all(&msg).await?;
+
+    let mut peer_public = [0u8; KEY_LEN];
+    stream.read_exact(&mut peer_public).await?;
+    let secret = keys.shared_secret(&peer_public)?;
+
+    let mut encrypt = cipher(b"keyA", &secret, info_hash);
+    let mut decrypt = cipher(b"keyB", &secret, info_hash);
+
+    // Prove we know the secret and say which torrent we want without revealing its info hash,
+    // then offer the ways to protect the rest of the connection
+    let mut msg = hash(&[b"req1", &secret]).to_vec();
+    msg.extend(xor(
+        &hash(&[b"req2", info_hash]),
+        &hash(&[b"req3", &secret]),
+    ));
+    let mut offer = VC.to_vec();
+    offer.extend(provide.to_be_bytes());
+    // No padding and no initial payload, the plaintext handshake follows once this is done
+    offer.extend(0u16.to_be_bytes());
+    offer.extend(0u16.to_be_bytes());
+    encrypt.apply(&mut offer);
+    msg.extend(offer);
+    stream.write_all(&msg).await?;
+
+    // The reply starts with the encrypted verification constant, after the peer's padding
+    let mut vc = VC;
+    decrypt.apply(&mut vc);
+    sync(&mut stream, &vc).await?;
+
+    let mut reply = [0u8; 4 + 2];
+    stream.read_exact(&mut reply).await?;
+    decrypt.apply(&mut reply);
+    let select = u32::from_be_bytes(reply[..4].try_into().unwrap());
+    let pad_len = u16::from_be_bytes(reply[4..].try_into().unwrap()) as usize;
+    read_pad(&mut stream, &mut decrypt, pad_len).await?;
+
+    match select & provide {
+        CRYPTO_RC4 => Result::Ok(PeerStream::encrypted(stream, Vec::new(), decrypt, encrypt)),
+        CRYPTO_PLAINTEXT => Result::Ok(PeerStream::new(stream, Vec::new())),
+        _ => Result::Err(anyhow::anyhow!(
+            "Peer selected an encryption method we didn't offer: {}",
+            select
+        )),
+    }
+}
+
+/// Performs the encryption handshake in the responder role, given the start of the peer's public
+/// key that was already read
+async fn respond<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
+    header: &[u8],
+    info_hash: &InfoHash,
+    policy: EncryptionPolicy,
+) -> anyhow::Result<PeerStream<S>> {
+    let mut peer_public = [0u8; KEY_LEN];
+    peer_public[..header.len()].copy_from_slice(header);
+    stream.read_exact(&mut peer_public[header.len()..]).await?;
+
+    let keys = KeyPair::generate();
+    let mut msg = keys.public.to_vec();
+    msg.extend(random_pad());
+    stream.write_all(&msg).await?;
+
+    let secret = keys.shared_secret(&peer_public)?;
+    sync(&mut stream, &hash(&[b"req1", &secret])).await?;
+
+    let mut torrent = [0u8; 20];
+    stream.read_exact(&mut torrent).await?;
+    let expected = xor(&hash(&[b"req2", info_hash]), &hash(&[b"req3", &secret]));
+    if torrent != expected {
+        return Result::Err(anyhow::anyhow!(
+            "Peer asked for a torrent we are not serving"
+        ));
+    }
+
+    let mut decrypt = cipher(b"keyA", &secret, info_hash);
+    let mut encrypt = cipher(b"keyB", &secret, info_hash);
+
+    let mut offer = [0u8; 8 + 4 + 2];
+    stream.read_exact(&mut offer).await?;
+    decrypt.apply(&mut offer);
+    if offer[..8] != VC {
+        return Result::Err(anyhow::anyhow!("Invalid verification constant"));
+    }
+    let provide = u32::from_be_bytes(offer[8..12].try_into().unwrap());
+    let pad_len = u16::from_be_bytes(offer[12..].try_into().unwrap()) as usize;
+    read_pad(&mut stream, &mut decrypt, pad_len).await?;
+
+    // The peer may send the start of the plaintext handshake along with the encryption handshake
+    let mut len = [0u8; 2];
+    stream.read_exact(&mut len).await?;
+    decrypt.apply(&mut len);
+    let mut initial = vec![0u8; u16::from_be_bytes(len) as usize];
+    stream.read_exact(&mut initial).await?;
+    decrypt.apply(&mut initial);
+
+    let select = if provide & CRYPTO_RC4 != 0 {
+        CRYPTO_RC4
+    } else if provide & CRYPTO_PLAINTEXT != 0 && policy != EncryptionPolicy::Require {
+        CRYPTO_PLAINTEXT
+    } else {
+        return Result::Err(anyhow::anyhow!(
+            "Peer offered no encryption method we accept: {}",
+            provide
+        ));
+    };
+
+    let mut reply = VC.to_vec();
+    reply.extend(select.to_be_bytes());
+    reply.extend(0u16.to_be_bytes());
+    encrypt.apply(&mut reply);
+    stream.write_all(&reply).await?;
+
+    match select {
+        CRYPTO_RC4 => Result::Ok(PeerStream::encrypted(stream, initial, decrypt, encrypt)),
+        _ => Result::Ok(PeerStream::new(stream, initial)),
+    }
+}
+
+/// Reads the peer's padding until `pattern` turns up, failing if it doesn't within the most
+/// padding the peer is allowed to send
+async fn sync<S: AsyncRead + Unpin>(stream: &mut S, pattern: &[u8]) -> anyhow::Result<()> {
+    let mut window = Vec::with_capacity(MAX_PAD_LEN + pattern.len());
+    while window.len() < MAX_PAD_LEN + pattern.len() {
+        window.push(stream.read_u8().await?);
+        if window.ends_with(pattern) {
+            return Result::Ok(());
+        }
+    }
+
+    Result::Err(anyhow::anyhow!(
+        "Failed to find the end of the peer's padding"
+    ))
+}
+
+/// Reads and decrypts padding, which has no meaning beyond making the handshake harder to spot
+async fn read_pad<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    decrypt: &mut Rc4,
+    len: usize,
+) -> anyhow::Result<()> {
+    if len > MAX_PAD_LEN {
+        return Result::Err(anyhow::anyhow!("Invalid padding length {}", len));
+    }
+
+    let mut pad = vec![0u8; len];
+    stream.read_exact(&mut pad).await?;
+    decrypt.apply(&mut pad);
+
+    Result::Ok(())
+}
+
+fn random_pad() -> Vec<u8> {
+    let mut rng = rand::thread_rng();
+    let mut pad = vec![0u8; rng.gen_range(0..=MAX_PAD_LEN)];
+    rng.fill(&mut pad[..]);
+    pad
+}
+
+/// A Diffie-Hellman key pair
+struct KeyPair {
+    private: BigUint,
+    public: [u8; KEY_LEN],
+}
+
+impl KeyPair {
+    fn generate() -> Self {
+        let mut private = [0u8; PRIVATE_KEY_LEN];
+        rand::thread_rng().fill(&mut private);
+        let private = BigUint::from_bytes_be(&private);
+        let public = BigUint::from(DH_GENERATOR).modpow(&private, &prime());
+
+        Self {
+            public: to_key(&public),
+            private,
+        }
+    }
+
+    fn shared_secret(&self, peer_public: &[u8; KEY_LEN]) -> anyhow::Result<[u8; KEY_LEN]> {
+        let prime = prime();
+        let peer_public = BigUint::from_bytes_be(peer_public);
+        if peer_public <= BigUint::from(1u32) || peer_public >= prime {
+            return Result::Err(anyhow::anyhow!("Invalid public key"));
+        }
+
+        Result::Ok(to_key(&peer_public.modpow(&self.private, &prime)))
+    }
+}
+
+fn prime() -> BigUint {
+    BigUint::parse_bytes(DH_PRIME.as_bytes(), 16).unwrap()
+}
+
+/// Encodes a number modulo the prime as a fixed length big endian key
+fn to_key(n: &BigUint) -> [u8; KEY_LEN] {
+    let bytes = n.to_bytes_be();
+    let mut key = [0u8; KEY_LEN];
+    key[KEY_LEN - bytes.len()..].copy_from_slice(&bytes);
+    key
+}
+
+fn hash(parts: &[&[u8]]) -> [u8; 20] {
+    let mut sha1 = Sha1::new();
+    for part in parts {
+        sha1.update(part);
+    }
+    sha1.finalize().into()
+}
+
+fn xor(a: &[u8; 20], b: &[u8; 20]) -> [u8; 20] {
+    let mut out = [0u8; 20];
+    for i in 0..out.len() {
+        out[i] = a[i] ^ b[i];
+    }
+    out
+}
+
+/// Creates the cipher for one direction of the connection, named by `key`
+fn cipher(key: &[u8], secret: &[u8; KEY_LEN], info_hash: &InfoHash) -> Rc4 {
+    let mut cipher = Rc4::new(&hash(&[key, secret, info_hash]));
+    cipher.skip(RC4_DISCARD);
+    cipher
+}
+
+/// The RC4 stream cipher
+#[derive(Clone)]
+struct Rc4 {
+    s: [u8; 256],
+    i: u8,
+    j: u8,
+}
+
+impl Rc4 {
+    fn new(key: &[u8]) -> Self {
+        let mut s = [0u8; 256];
+        for (i, byte) in s.iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+
+        let mut j = 0u8;
+        for i in 0..256 {
+            j = j.wrapping_add(s[i]).wrapping_add(key[i % key.len()]);
+            s.swap(i, j as usize);
+        }
+
+        Self { s, i: 0, j: 0 }
+    }
+
+    fn next(&mut self) -> u8 {
+        self.i = self.i.wrapping_add(1);
+        self.j = self.j.wrapping_add(self.s[self.i as usize]);
+        self.s.swap(self.i as usize, self.j as usize);
+        let k = self.s[self.i as usize].wrapping_add(self.s[self.j as usize]);
+        self.s[k as usize]
+    }
+
+    /// Encrypts or decrypts data in place
+    fn apply(&mut self, data: &mut [u8]) {
+        for byte in data {
+            *byte ^= self.next();
+        }
+    }
+
+    /// Moves the keystream on without using it
+    fn skip(&mut self, len: usize) {
+        for _ in 0..len {
+            self.next();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::DuplexStream;
+
+    use super::*;
+
+    const INFO_HASH: InfoHash = [0x42; 20];
+
+    fn rc4(key: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut data = data.to_vec();
+        Rc4::new(key).apply(&mut data);
+        data
+    }
+
+    #[test]
+    fn rc4_matches_known_answers() {
+        assert_eq!(
+            rc4(b"Key", b"Plaintext"),
+            [0xbb, 0xf3, 0x16, 0xe8, 0xd9, 0x40, 0xaf, 0x0a, 0xd3]
+        );
+        assert_eq!(rc4(b"Wiki", b"pedia"), [0x10, 0x21, 0xbf, 0x04, 0x20]);
+        assert_eq!(
+            rc4(b"Secret", b"Attack at dawn"),
+            [0x45, 0xa0, 0x1f, 0x64, 0x5f, 0xc3, 0x5b, 0x38, 0x35, 0x52, 0x54, 0x4b, 0x9b, 0xf5]
+        );
+    }
+
+    #[test]
+    fn rc4_skip_matches_apply() {
+        let mut skipped = Rc4::new(b"Key");
+        skipped.skip(RC4_DISCARD);
+        let mut applied = Rc4::new(b"Key");
+        applied.apply(&mut [0u8; RC4_DISCARD]);
+
+        let (mut a, mut b) = ([0u8; 16], [0u8; 16]);
+        skipped.apply(&mut a);
+        applied.apply(&mut b);
+        assert_eq!(a, b);
+    }
+
+    /// Takes the responder's side of a handshake, reading the header first like accept does
+    async fn accept_duplex(
+        mut stream: DuplexStream,
+        info_hash: &InfoHash,
+        policy: EncryptionPolicy,
+    ) -> anyhow::Result<PeerStream<DuplexStream>> {
+        let mut header = vec![0u8; PLAINTEXT_HEADER.len()];
+        stream.read_exact(&mut header).await?;
+        respond(stream, &header, info_hash, policy).await
+    }
+
+    async fn exchange(a: &mut PeerStream<DuplexStream>, b: &mut PeerStream<DuplexStream>) {
+        a.write_all(b"hello from the initiator").await.unwrap();
+        let mut buf = [0u8; 24];
+        b.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello from the initiator");
+
+        b.write_all(b"hello from the responder").await.unwrap();
+        a.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello from the responder");
+    }
+
+    #[tokio::test]
+    async fn negotiates_rc4() {
+        let (a, b) = tokio::io::duplex(64 * 1024);
+        let (initiator, responder) = tokio::join!(
+            initiate(a, &INFO_HASH, CRYPTO_RC4 | CRYPTO_PLAINTEXT),
+            accept_duplex(b, &INFO_HASH, EncryptionPolicy::Prefer)
+        );
+        let (mut initiator, mut responder) = (initiator.unwrap(), responder.unwrap());
+        assert!(initiator.write_cipher.is_some() && responder.read_cipher.is_some());
+
+        exchange(&mut initiator, &mut responder).await;
+    }
+
+    #[tokio::test]
+    async fn negotiates_plaintext() {
+        let (a, b) = tokio::io::duplex(64 * 1024);
+        let (initiator, responder) = tokio::join!(
+            initiate(a, &INFO_HASH, CRYPTO_PLAINTEXT),
+            accept_duplex(b, &INFO_HASH, EncryptionPolicy::Prefer)
+        );
+        let (mut initiator, mut responder) = (initiator.unwrap(), responder.unwrap());
+        assert!(initiator.write_cipher.is_none() && responder.read_cipher.is_none());
+
+        exchange(&mut initiator, &mut responder).await;
+    }
+
+    #[tokio::test]
+    async fn refuses_plaintext_when_required() {
+        let (a, b) = tokio::io::duplex(64 * 1024);
+        let (initiator, responder) = tokio::join!(
+            initiate(a, &INFO_HASH, CRYPTO_PLAINTEXT),
+            accept_duplex(b, &INFO_HASH, EncryptionPolicy::Require)
+        );
+        assert!(initiator.is_err());
+        assert!(responder.is_err());
+    }
+
+    #[tokio::test]
+    async fn rejects_unknown_torrents() {
+        let (a, b) = tokio::io::duplex(64 * 1024);
+        let (initiator, responder) = tokio::join!(
+            initiate(a, &[0x24; 20], CRYPTO_RC4),
+            accept_duplex(b, &INFO_HASH, EncryptionPolicy::Prefer)
+        );
+        assert!(initiator.is_err());
+        assert!(responder.is_err());
+    }
+}
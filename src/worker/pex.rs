@@ -0,0 +1,244 @@
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use serde_bytes::ByteBuf;
+use serde_derive::{Deserialize, Serialize};
+use tokio::time::Instant;
+
+use crate::tracker::{Peer, COMPACT_LEN_V4, COMPACT_LEN_V6};
+
+pub const UT_PEX: &str = "ut_pex";
+
+// How often we send a peer exchange message to each peer
+const PEX_INTERVAL: Duration = Duration::from_secs(60);
+
+// The most peers added or dropped in a single message
+const MAX_PEX_PEERS: usize = 50;
+
+// Flags describing an added peer
+pub const PEX_FLAG_SEED: u8 = 0x02;
+pub const PEX_FLAG_REACHABLE: u8 = 0x10;
+
+/// A peer we are connected to, along with the flags describing it to other peers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PexPeer {
+    pub addr: SocketAddr,
+    pub flags: u8,
+}
+
+/// A peer exchange message, listing the peers the sender connected to and disconnected from
+/// since its last message (BEP 11)
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct PexMessage {
+    #[serde(default)]
+    added: ByteBuf,
+    #[serde(default, rename = "added.f")]
+    added_flags: ByteBuf,
+    #[serde(default)]
+    added6: ByteBuf,
+    #[serde(default, rename = "added6.f")]
+    added6_flags: ByteBuf,
+    #[serde(default)]
+    dropped: ByteBuf,
+    #[serde(default)]
+    dropped6: ByteBuf,
+}
+
+impl PexMessage {
+    fn new(added: &[PexPeer], dropped: &[SocketAddr]) -> Self {
+        let mut msg = Self::default();
+        for peer in added {
+            let (peers, flags) = if peer.addr.is_ipv4() {
+                (&mut msg.added, &mut msg.added_flags)
+            } else {
+                (&mut msg.added6, &mut msg.added6_flags)
+            };
+            peers.extend_from_slice(&Peer::new(peer.addr).to_compact());
+            flags.push(peer.flags);
+        }
+        for addr in dropped {
+            let peers = if addr.is_ipv4() {
+                &mut msg.dropped
+            } else {
+                &mut msg.dropped6
+            };
+            peers.extend_from_slice(&Peer::new(*addr).to_compact());
+        }
+        msg
+    }
+
+    /// Returns the peers the sender connected to, with their flags
+    pub fn added(&self) -> anyhow::Result<Vec<PexPeer>> {
+        let mut added = parse_peers(&self.added, &self.added_flags, COMPACT_LEN_V4)?;
+        added.extend(parse_peers(
+            &self.added6,
+            &self.added6_flags,
+            COMPACT_LEN_V6,
+        )?);
+        Result::Ok(added)
+    }
+
+    /// Returns the number of peers the sender disconnected from
+    pub fn num_dropped(&self) -> usize {
+        self.dropped.len() / COMPACT_LEN_V4 + self.dropped6.len() / COMPACT_LEN_V6
+    }
+}
+
+/// Parses compact peers and their flags, which are left at 0 if the sender didn't include them
+fn parse_peers(peers: &[u8], flags: &[u8], peer_len: usize) -> anyhow::Result<Vec<PexPeer>> {
+    if !peers.len().is_multiple_of(peer_len) {
+        return Result::Err(anyhow::anyhow!(
+            "Invalid PEX peers, length {} is not a multiple of {}",
+            peers.len(),
+            peer_len
+        ));
+    }
+
+    Result::Ok(
+        peers
+            .chunks_exact(peer_len)
+            .enumerate()
+            .map(|(i, peer)| PexPeer {
+                addr: Peer::from_compact(peer).addr,
+                flags: flags.get(i).copied().unwrap_or(0),
+            })
+            .collect(),
+    )
+}
+
+/// Tracks what we have told a peer about the swarm, so each message only lists what changed since
+/// the last one
+#[derive(Debug)]
+pub struct PeerExchange {
+    sent: HashSet<SocketAddr>,
+    next: Instant,
+}
+
+impl PeerExchange {
+    pub fn new() -> Self {
+        Self {
+            sent: HashSet::new(),
+            next: Instant::now(),
+        }
+    }
+
+    /// When the next message is due
+    pub fn deadline(&self) -> Instant {
+        self.next
+    }
+
+    /// Builds the next message from the peers we are connected to now, or returns None if nothing
+    /// changed. Peers that don't fit in this message are sent in the next one.
+    pub fn next_message(&mut self, connected: &[PexPeer]) -> Option<PexMessage> {
+        self.next = Instant::now() + PEX_INTERVAL;
+
+        let added: Vec<PexPeer> = connected
+            .iter()
+            .filter(|peer| !self.sent.contains(&peer.addr))
+            .take(MAX_PEX_PEERS)
+            .copied()
+            .collect();
+        let dropped: Vec<SocketAddr> = self
+            .sent
+            .iter()
+            .filter(|addr| !connected.iter().any(|peer| peer.addr == **addr))
+            .take(MAX_PEX_PEERS)
+            .copied()
+            .collect();
+        if added.is_empty() && dropped.is_empty() {
+            return None;
+        }
+
+        for peer in &added {
+            self.sent.insert(peer.addr);
+        }
+        for addr in &dropped {
+            self.sent.remove(addr);
+        }
+        Some(PexMessage::new(&added, &dropped))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(addr: &str) -> PexPeer {
+        PexPeer {
+            addr: addr.parse().unwrap(),
+            flags: PEX_FLAG_REACHABLE,
+        }
+    }
+
+    fn dropped(msg: &PexMessage) -> Vec<SocketAddr> {
+        let mut dropped = parse_peers(&msg.dropped, &[], COMPACT_LEN_V4).unwrap();
+        dropped.extend(parse_peers(&msg.dropped6, &[], COMPACT_LEN_V6).unwrap());
+        dropped.into_iter().map(|peer| peer.addr).collect()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn sends_only_changes() {
+        let mut pex = PeerExchange::new();
+        let a = peer("10.0.0.1:6881");
+        let b = peer("[2001:db8::1]:6881");
+        let c = peer("10.0.0.3:6881");
+
+        let msg = pex.next_message(&[a, b]).unwrap();
+        assert_eq!(msg.added().unwrap(), vec![a, b]);
+        assert_eq!(msg.num_dropped(), 0);
+        assert_eq!(pex.deadline(), Instant::now() + PEX_INTERVAL);
+
+        assert!(pex.next_message(&[b, a]).is_none());
+
+        let msg = pex.next_message(&[a, c]).unwrap();
+        assert_eq!(msg.added().unwrap(), vec![c]);
+        assert_eq!(dropped(&msg), vec![b.addr]);
+
+        let msg = pex.next_message(&[]).unwrap();
+        assert!(msg.added().unwrap().is_empty());
+        assert_eq!(msg.num_dropped(), 2);
+    }
+
+    #[test]
+    fn spreads_large_changes_over_messages() {
+        let mut pex = PeerExchange::new();
+        let connected: Vec<PexPeer> = (0..MAX_PEX_PEERS + 10)
+            .map(|i| peer(&format!("10.0.{}.{}:6881", i / 256, i % 256)))
+            .collect();
+
+        let msg = pex.next_message(&connected).unwrap();
+        assert_eq!(msg.added().unwrap().len(), MAX_PEX_PEERS);
+        let msg = pex.next_message(&connected).unwrap();
+        assert_eq!(msg.added().unwrap().len(), 10);
+        assert!(pex.next_message(&connected).is_none());
+    }
+
+    #[test]
+    fn round_trips_messages() {
+        let a = peer("10.0.0.1:6881");
+        let b = PexPeer {
+            addr: "[2001:db8::1]:6881".parse().unwrap(),
+            flags: PEX_FLAG_SEED,
+        };
+        let dropped_addrs = ["10.0.0.2:6881".parse().unwrap()];
+        let bytes = serde_bencode::to_bytes(&PexMessage::new(&[a, b], &dropped_addrs)).unwrap();
+
+        let msg = serde_bencode::from_bytes::<PexMessage>(&bytes).unwrap();
+        assert_eq!(msg.added().unwrap(), vec![a, b]);
+        assert_eq!(dropped(&msg), dropped_addrs);
+    }
+
+    #[test]
+    fn defaults_missing_flags() {
+        let msg = serde_bencode::from_bytes::<PexMessage>(b"d5:added6:\x0a\x00\x00\x01\x1a\xe1e")
+            .unwrap();
+        let added = msg.added().unwrap();
+        assert_eq!(added.len(), 1);
+        assert_eq!(added[0].flags, 0);
+
+        let msg =
+            serde_bencode::from_bytes::<PexMessage>(b"d5:added5:\x0a\x00\x00\x01\x1ae").unwrap();
+        assert!(msg.added().is_err());
+    }
+}
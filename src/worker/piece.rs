@@ -0,0 +1,120 @@
+use sha1::{Digest, Sha1};
+
+use super::message::Bitfield;
+use super::{PieceInfo, MAX_BLOCK_SIZE};
+use crate::types::PieceHash;
+
+/// A piece being downloaded from a peer, tracking which of its blocks have been requested and
+/// received
+pub struct PieceProgress {
+    pub info: PieceInfo,
+    buf: Vec<u8>,
+    requested: Bitfield,
+    received: Bitfield,
+    downloaded: u32,
+}
+
+impl PieceProgress {
+    pub fn new(info: PieceInfo) -> Self {
+        let num_blocks = info.length.div_ceil(MAX_BLOCK_SIZE) as usize;
+        Self {
+            buf: vec![0u8; info.length as usize],
+            requested: Bitfield::new(num_blocks),
+            received: Bitfield::new(num_blocks),
+            downloaded: 0,
+            info,
+        }
+    }
+
+    pub fn index(&self) -> u32 {
+        self.info.index
+    }
+
+    pub fn num_blocks(&self) -> u32 {
+        self.info.length.div_ceil(MAX_BLOCK_SIZE)
+    }
+
+    /// Returns the length of the block starting at `begin`
+    pub fn block_size(&self, begin: u32) -> u32 {
+        u32::min(MAX_BLOCK_SIZE, self.info.length - begin)
+    }
+
+    /// Returns the offset and length of the next block that hasn't been requested or received yet,
+    /// marking it as requested
+    pub fn next_request(&mut self) -> Option<(u32, u32)> {
+        let block = (0..self.num_blocks())
+            .find(|block| !self.requested.has(*block) && !self.received.has(*block))?;
+        self.requested.set(block);
+
+        let begin = block * MAX_BLOCK_SIZE;
+        Some((begin, self.block_size(begin)))
+    }
+
+    /// Forgets every request that hasn't been fulfilled, e.g. after the peer choked us
+    pub fn reset_requests(&mut self) {
+        self.requested = self.received.clone();
+    }
+
+    /// Forgets the request for the block starting at `begin`, e.g. after the peer rejected it
+    pub fn reset_request(&mut self, begin: u32) {
+        let block = begin / MAX_BLOCK_SIZE;
+        if !self.received.has(block) {
+            self.requested.clear(block);
+        }
+    }
+
+    /// Returns true if the block starting at `begin` was requested and hasn't arrived yet
+    pub fn is_pending(&self, begin: u32) -> bool {
+        let block = begin / MAX_BLOCK_SIZE;
+        self.requested.has(block) && !self.received.has(block)
+    }
+
+    pub fn has_block(&self, begin: u32) -> bool {
+        self.received.has(begin / MAX_BLOCK_SIZE)
+    }
+
+    /// Copies a block into the piece buffer, checking it lines up with a block of the piece
+    pub fn add_block(&mut self, begin: u32, block: &[u8]) -> anyhow::Result<()> {
+        if !begin.is_multiple_of(MAX_BLOCK_SIZE) || begin >= self.info.length {
+            return Result::Err(anyhow::anyhow!(
+                "Invalid block begin offset {} for piece {} of size {}",
+                begin,
+                self.info.index,
+                self.info.length
+            ));
+        }
+
+        if block.len() as u32 != self.block_size(begin) {
+            return Result::Err(anyhow::anyhow!(
+                "Block of size {} at begin {} does not match piece {} of size {}",
+                block.len(),
+                begin,
+                self.info.index,
+                self.info.length
+            ));
+        }
+
+        if self.has_block(begin) {
+            return Result::Ok(());
+        }
+
+        let b = begin as usize;
+        self.buf[b..b + block.len()].copy_from_slice(block);
+        self.received.set(begin / MAX_BLOCK_SIZE);
+        self.downloaded += block.len() as u32;
+
+        Result::Ok(())
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.downloaded == self.info.length
+    }
+
+    /// Checks the downloaded piece against its expected hash
+    pub fn verify(&self) -> bool {
+        let mut sha1 = Sha1::new();
+        sha1.update(&self.buf);
+        let hash: PieceHash = sha1.finalize().into();
+        hash == self.info.hash
+    }
+}
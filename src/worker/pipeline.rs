@@ -0,0 +1,177 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use super::MAX_BLOCK_SIZE;
+
+// The number of requests to keep in flight before we know anything about the peer
+const INITIAL_QUEUE_DEPTH: u32 = 5;
+
+// The fewest requests we keep in flight, so there is always a block on its way to us
+const MIN_QUEUE_DEPTH: u32 = 2;
+
+// The most requests we send a peer that didn't advertise its own limit
+const DEFAULT_MAX_QUEUE_DEPTH: u32 = 250;
+
+// How much data to keep queued up beyond the round trip, to absorb jitter in the peer's upload rate
+const QUEUE_TIME: Duration = Duration::from_secs(1);
+
+// How often the download rate estimate is updated
+const RATE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Tracks the requests in flight to a peer and sizes the queue of outstanding requests to the
+/// peer's measured download rate and round trip time, so a fast peer on a slow link is kept busy
+/// without flooding a slow one
+pub struct RequestPipeline {
+    /// When each outstanding request was sent, by piece index and offset
+    outstanding: HashMap<(u32, u32), Instant>,
+    /// Moving average of the download rate in bytes per second
+    rate: Option<f64>,
+    /// The shortest time seen between sending a request and receiving its block, which excludes
+    /// time spent waiting behind our other requests
+    min_rtt: Option<Duration>,
+    interval_start: Instant,
+    interval_bytes: u64,
+}
+
+impl RequestPipeline {
+    pub fn new() -> Self {
+        Self {
+            outstanding: HashMap::new(),
+            rate: None,
+            min_rtt: None,
+            interval_start: Instant::now(),
+            interval_bytes: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.outstanding.len()
+    }
+
+    /// Returns how many requests should be in flight, never more than `max_depth` if the peer
+    /// told us how many it will queue
+    pub fn depth(&self, max_depth: Option<u32>) -> u32 {
+        let max_depth = max_depth.unwrap_or(DEFAULT_MAX_QUEUE_DEPTH).max(1);
+        let depth = match (self.rate, self.min_rtt) {
+            (Some(rate), Some(min_rtt)) => {
+                // Keep enough requests in flight to cover the bandwidth delay product
+                let queue_time = (min_rtt + QUEUE_TIME).as_secs_f64();
+                let depth = (rate * queue_time / MAX_BLOCK_SIZE as f64).ceil() as u32;
+                depth.max(MIN_QUEUE_DEPTH)
+            }
+            _ => INITIAL_QUEUE_DEPTH,
+        };
+
+        depth.min(max_depth)
+    }
+
+    pub fn request_sent(&mut self, index: u32, begin: u32) {
+        if self.outstanding.is_empty() {
+            // Don't count time spent with nothing requested against the peer's rate
+            self.interval_start = Instant::now();
+            self.interval_bytes = 0;
+        }
+        self.outstanding.insert((index, begin), Instant::now());
+    }
+
+    /// Records a block arriving, ignoring any we weren't waiting for
+    pub fn block_received(&mut self, index: u32, begin: u32, length: usize) {
+        let Some(sent) = self.outstanding.remove(&(index, begin)) else {
+            return;
+        };
+
+        let rtt = sent.elapsed();
+        self.min_rtt = Some(self.min_rtt.map_or(rtt, |min_rtt| min_rtt.min(rtt)));
+
+        self.interval_bytes += length as u64;
+        let elapsed = self.interval_start.elapsed();
+        if elapsed >= RATE_INTERVAL {
+            let rate = self.interval_bytes as f64 / elapsed.as_secs_f64();
+            self.rate = Some(self.rate.map_or(rate, |average| (average + rate) / 2.0));
+            self.interval_start = Instant::now();
+            self.interval_bytes = 0;
+        }
+    }
+
+    pub fn cancel(&mut self, index: u32, begin: u32) {
+        self.outstanding.remove(&(index, begin));
+    }
+
+    /// Forgets every outstanding request, e.g. after the peer choked us and dropped them
+    pub fn clear(&mut self) {
+        self.outstanding.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Measures a single round trip of `rtt` that brings in `blocks` blocks
+    async fn sampled_pipeline(rtt: Duration, blocks: u32) -> RequestPipeline {
+        let mut pipeline = RequestPipeline::new();
+        pipeline.request_sent(0, 0);
+        tokio::time::advance(rtt).await;
+        pipeline.block_received(0, 0, (blocks * MAX_BLOCK_SIZE) as usize);
+        pipeline
+    }
+
+    #[test]
+    fn starts_with_the_initial_depth() {
+        let pipeline = RequestPipeline::new();
+        assert_eq!(pipeline.depth(None), INITIAL_QUEUE_DEPTH);
+        assert_eq!(pipeline.depth(Some(3)), 3);
+        // A peer advertising no queue at all still gets one request at a time
+        assert_eq!(pipeline.depth(Some(0)), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn depth_grows_with_rate_and_round_trip_time() {
+        let second = Duration::from_secs(1);
+
+        // Ten blocks a second, over a second of round trip plus a second of queue time
+        let pipeline = sampled_pipeline(second, 10).await;
+        assert_eq!(pipeline.depth(None), 20);
+
+        let faster = sampled_pipeline(second, 20).await;
+        assert_eq!(faster.depth(None), 40);
+
+        let further = sampled_pipeline(3 * second, 30).await;
+        assert_eq!(further.depth(None), 40);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn depth_is_clamped() {
+        let pipeline = sampled_pipeline(Duration::from_secs(1), 10).await;
+        assert_eq!(pipeline.depth(Some(8)), 8);
+
+        let fast = sampled_pipeline(Duration::from_secs(1), 1000).await;
+        assert_eq!(fast.depth(None), DEFAULT_MAX_QUEUE_DEPTH);
+
+        // Slow peers still have a block on its way at all times
+        let mut slow = RequestPipeline::new();
+        slow.request_sent(0, 0);
+        tokio::time::advance(Duration::from_secs(2)).await;
+        slow.block_received(0, 0, 1);
+        assert_eq!(slow.depth(None), MIN_QUEUE_DEPTH);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn ignores_blocks_it_did_not_request() {
+        let mut pipeline = RequestPipeline::new();
+        pipeline.request_sent(0, 0);
+        pipeline.request_sent(0, MAX_BLOCK_SIZE);
+        pipeline.cancel(0, 0);
+        assert_eq!(pipeline.len(), 1);
+
+        tokio::time::advance(Duration::from_secs(1)).await;
+        pipeline.block_received(0, 0, (100 * MAX_BLOCK_SIZE) as usize);
+        assert_eq!(pipeline.len(), 1);
+        assert_eq!(pipeline.depth(None), INITIAL_QUEUE_DEPTH);
+
+        pipeline.clear();
+        assert_eq!(pipeline.len(), 0);
+    }
+}
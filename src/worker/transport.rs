@@ -0,0 +1,110 @@
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context as TaskContext, Poll};
+use std::time::Duration;
+
+use anyhow::Context;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use tracing::info;
+
+use super::mse::EncryptionPolicy;
+use crate::ratelimit::RateLimits;
+use crate::utp::{UtpSocket, UtpStream};
+
+// How long to wait for a TCP connection to a peer to be established
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// How connections to peers are made
+#[derive(Clone)]
+pub struct TransportConfig {
+    pub encryption: EncryptionPolicy,
+    /// The socket to make uTP connections on, if uTP is enabled
+    pub utp: Option<Arc<UtpSocket>>,
+    /// Limits on the blocks exchanged with peers, shared by every torrent
+    pub limits: Arc<RateLimits>,
+}
+
+/// A connection to a peer over TCP or uTP
+pub enum Transport {
+    Tcp(TcpStream),
+    Utp(UtpStream),
+}
+
+impl Transport {
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            Self::Tcp(stream) => stream.peer_addr(),
+            Self::Utp(stream) => Ok(stream.peer_addr()),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Tcp(_) => "TCP",
+            Self::Utp(_) => "uTP",
+        }
+    }
+}
+
+impl AsyncRead for Transport {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            Self::Utp(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Transport {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            Self::Utp(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            Self::Utp(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            Self::Utp(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+/// Connects to a peer, over uTP if it is enabled so we yield to other traffic on the link, and
+/// over TCP for peers that don't speak uTP
+pub async fn connect(addr: SocketAddr, utp: Option<&Arc<UtpSocket>>) -> anyhow::Result<Transport> {
+    // The uTP socket only listens on IPv4
+    if let Some(utp) = utp.filter(|_| addr.is_ipv4()) {
+        match utp.connect(addr).await {
+            Ok(stream) => return Result::Ok(Transport::Utp(stream)),
+            Err(error) => info!(
+                "uTP connection to {} failed, falling back to TCP: {}",
+                addr, error
+            ),
+        }
+    }
+
+    let stream = tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect(addr))
+        .await
+        .context("Connection timeout")??;
+    Result::Ok(Transport::Tcp(stream))
+}
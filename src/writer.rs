@@ -1,11 +1,16 @@
 use std::io::SeekFrom;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
 
 use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
-use tokio::fs::File;
-use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+use sha1::{Digest, Sha1};
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
 
+use crate::resume::ResumeFile;
 use crate::torrent::{Torrent, TorrentFile};
+use crate::types::PieceHash;
+use crate::worker::Bitfield;
 
 pub struct TorrentWriter {
     files: Vec<TorrentWriterFileHandle>,
@@ -18,23 +23,31 @@ struct TorrentWriterFileHandle {
     progress: ProgressBar,
 }
 
+/// The part of a read or write that falls within a single file
+struct FileSpan {
+    handle: usize,
+    file_offset: u64,
+    buf_offset: usize,
+    length: usize,
+}
+
 impl TorrentWriter {
     pub async fn from_torrent(torrent: &Torrent) -> anyhow::Result<Self> {
         let total_progress = MultiProgress::new();
         let files: Vec<TorrentWriterFileHandle> = if torrent.files.len() == 1 {
-            // For single files, we can just create the single file to write to
+            // For single files, we can just open the single file to write to
             let f = &torrent.files[0];
             let progress = total_progress.add(Self::progress_bar(f)?);
             vec![TorrentWriterFileHandle {
-                file: File::create(&f.path).await?,
+                file: Self::open(&f.path, f.length).await?,
                 length: f.length,
                 written: 0,
                 progress,
             }]
         } else {
             // For multiple files, we need to set up the directory structure for the files
-            let base_dir_path = PathBuf::try_from(&torrent.name)?;
-            tokio::fs::create_dir(&base_dir_path).await?;
+            let base_dir_path = PathBuf::from(&torrent.name);
+            tokio::fs::create_dir_all(&base_dir_path).await?;
 
             let mut result: Vec<TorrentWriterFileHandle> = Vec::with_capacity(torrent.files.len());
             for f in torrent.files.iter() {
@@ -43,7 +56,7 @@ impl TorrentWriter {
                     tokio::fs::create_dir_all(base_dir_path.join(parent)).await?;
                 }
 
-                let file = File::create(base_dir_path.join(&f.path)).await?;
+                let file = Self::open(&base_dir_path.join(&f.path), f.length).await?;
                 let progress = total_progress.add(Self::progress_bar(f)?);
                 result.push(TorrentWriterFileHandle {
                     file,
@@ -60,37 +73,139 @@ impl TorrentWriter {
     }
 
     pub async fn write(&mut self, offset: u64, buf: &[u8]) -> anyhow::Result<()> {
-        let mut buf_offset: usize = 0;
-        let buf_length = buf.len();
-        let mut current_handle_offset: u64 = 0;
+        for span in self.spans(offset, buf.len())? {
+            let h = &mut self.files[span.handle];
+            h.file.seek(SeekFrom::Start(span.file_offset)).await?;
+            h.file
+                .write_all(&buf[span.buf_offset..span.buf_offset + span.length])
+                .await?;
+        }
+
+        Result::Ok(())
+    }
+
+    /// Reads `length` bytes starting at `offset` in the torrent back from the files on disk
+    pub async fn read(&mut self, offset: u64, length: usize) -> anyhow::Result<Vec<u8>> {
+        let mut buf = vec![0u8; length];
+        for span in self.spans(offset, length)? {
+            let h = &mut self.files[span.handle];
+            h.file.seek(SeekFrom::Start(span.file_offset)).await?;
+            h.file
+                .read_exact(&mut buf[span.buf_offset..span.buf_offset + span.length])
+                .await?;
+        }
+
+        Result::Ok(buf)
+    }
+
+    /// Hashes every piece already on disk against the torrent's piece hashes, returning a
+    /// bitfield of the pieces that are complete
+    pub async fn verify(&mut self, torrent: &Torrent) -> anyhow::Result<Bitfield> {
+        let mut have = Bitfield::new(torrent.piece_hashes.len());
+        for (index, expected) in torrent.piece_hashes.iter().enumerate() {
+            let offset = index as u64 * torrent.piece_length;
+            let length = u64::min(torrent.piece_length, torrent.length - offset) as usize;
+
+            let piece = self.read(offset, length).await?;
+            let mut sha1 = Sha1::new();
+            sha1.update(&piece);
+            let hash: PieceHash = sha1.finalize().into();
 
+            if hash == *expected {
+                have.set(index as u32);
+                self.mark_complete(offset, length)?;
+            }
+        }
+
+        Result::Ok(have)
+    }
+
+    /// Returns the size and modification time of every file, flushing any pending writes first
+    pub async fn file_stats(&mut self) -> anyhow::Result<Vec<ResumeFile>> {
+        let mut stats = Vec::with_capacity(self.files.len());
         for h in &mut self.files {
-            if offset < current_handle_offset + h.length {
-                let file_offset = offset - current_handle_offset;
-                let write_length =
-                    usize::min((h.length - file_offset) as usize, buf_length - buf_offset);
-                h.file.seek(SeekFrom::Start(file_offset)).await?;
-                h.file
-                    .write_all(&buf[buf_offset..buf_offset + write_length])
-                    .await?;
-                buf_offset += write_length;
-
-                h.progress.inc(write_length as u64);
-                h.written += write_length as u64;
-
-                if h.written == h.length {
-                    h.progress.finish();
-                }
+            h.file.flush().await?;
+            let metadata = h.file.metadata().await?;
+            let mtime = metadata.modified()?.duration_since(UNIX_EPOCH)?.as_nanos() as u64;
+            stats.push(ResumeFile {
+                length: metadata.len(),
+                mtime,
+            });
+        }
+
+        Result::Ok(stats)
+    }
+
+    /// Records a verified range of the torrent as complete in the progress bars
+    pub fn mark_complete(&mut self, offset: u64, length: usize) -> anyhow::Result<()> {
+        for span in self.spans(offset, length)? {
+            let h = &mut self.files[span.handle];
+            h.progress.inc(span.length as u64);
+            h.written += span.length as u64;
+
+            if h.written == h.length {
+                h.progress.finish();
             }
+        }
 
-            current_handle_offset += h.length;
+        Result::Ok(())
+    }
+
+    /// Splits a range of the torrent into the spans of each file it covers
+    fn spans(&self, offset: u64, length: usize) -> anyhow::Result<Vec<FileSpan>> {
+        let mut spans = Vec::new();
+        let mut buf_offset: usize = 0;
+        let mut current_handle_offset: u64 = 0;
 
-            if buf_offset == buf_length {
+        for (handle, h) in self.files.iter().enumerate() {
+            if buf_offset == length {
                 break;
             }
+
+            let current_offset = offset + buf_offset as u64;
+            if current_offset < current_handle_offset + h.length {
+                let file_offset = current_offset - current_handle_offset;
+                let span_length =
+                    usize::min((h.length - file_offset) as usize, length - buf_offset);
+                spans.push(FileSpan {
+                    handle,
+                    file_offset,
+                    buf_offset,
+                    length: span_length,
+                });
+                buf_offset += span_length;
+            }
+
+            current_handle_offset += h.length;
         }
 
-        Result::Ok(())
+        if buf_offset != length {
+            return Result::Err(anyhow::anyhow!(
+                "Range of {} bytes at offset {} is out of bounds",
+                length,
+                offset
+            ));
+        }
+
+        Result::Ok(spans)
+    }
+
+    /// Opens a file without truncating it so existing data can be resumed, sizing it to the
+    /// length of the file in the torrent
+    async fn open(path: &Path, length: u64) -> anyhow::Result<File> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .await?;
+
+        if file.metadata().await?.len() != length {
+            file.set_len(length).await?;
+        }
+
+        Result::Ok(file)
     }
 
     fn progress_bar(file: &TorrentFile) -> anyhow::Result<ProgressBar> {
@@ -109,3 +224,59 @@ impl TorrentWriter {
         Result::Ok(progress_bar)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PIECE_LENGTH: u64 = 16;
+
+    #[tokio::test]
+    async fn verify_marks_only_matching_pieces() {
+        // Three full pieces and a short last one
+        let data: Vec<u8> = (0..3 * PIECE_LENGTH as u8 + 5).collect();
+        let path = std::env::temp_dir().join("rustor-writer-test-verify");
+        let _ = tokio::fs::remove_file(&path).await;
+        let torrent = Torrent {
+            name: "verify".to_owned(),
+            announce_list: Vec::new(),
+            length: data.len() as u64,
+            info_hash: [0; 20],
+            piece_length: PIECE_LENGTH,
+            piece_hashes: data
+                .chunks(PIECE_LENGTH as usize)
+                .map(|piece| Sha1::digest(piece).into())
+                .collect(),
+            files: vec![TorrentFile {
+                length: data.len() as u64,
+                path: path.clone(),
+            }],
+        };
+
+        // The second piece is corrupt and the third was never written
+        let mut writer = TorrentWriter::from_torrent(&torrent).await.unwrap();
+        let mut on_disk = data.clone();
+        on_disk[PIECE_LENGTH as usize + 3] ^= 0xff;
+        on_disk[2 * PIECE_LENGTH as usize..3 * PIECE_LENGTH as usize].fill(0);
+        writer.write(0, &on_disk).await.unwrap();
+
+        let have = writer.verify(&torrent).await.unwrap();
+        assert_eq!(
+            (0..4).map(|index| have.has(index)).collect::<Vec<_>>(),
+            vec![true, false, false, true]
+        );
+
+        // Fixing the corrupt piece on disk makes it pass too
+        writer
+            .write(
+                PIECE_LENGTH,
+                &data[PIECE_LENGTH as usize..2 * PIECE_LENGTH as usize],
+            )
+            .await
+            .unwrap();
+        let have = writer.verify(&torrent).await.unwrap();
+        assert!(have.has(1) && !have.has(2));
+
+        tokio::fs::remove_file(&path).await.unwrap();
+    }
+}